@@ -1,13 +1,24 @@
 use anyhow::Result;
 use clap::Parser;
+use std::sync::Arc;
 use tokio::net::TcpListener;
 use tokio::signal;
+use tor_provider::body_limit::BodyLimits;
+use tor_provider::channel_registry::{ChannelRegistry, HostChannel};
+use tor_provider::claim_scheduler::ClaimScheduler;
 use tor_provider::config::HostConfig;
-use tor_provider::hidden_service::{HiddenServiceConfig, HiddenServiceManager};
-use tor_provider::hpc_service::HpcClient;
+use tor_provider::free_tier::FreeTierPolicy;
+use tor_provider::funds_monitor::FundsMonitor;
+use tor_provider::hidden_service::{HiddenServiceConfig, HiddenServiceManager, stored_identity};
+use tor_provider::lottery::LotteryPolicy;
 use tor_provider::nimbus::{NimbusConfig, NimbusManager};
+use tor_provider::payment_backend::create_payment_backend;
+use tor_provider::payment_receipt::ReceiptSigner;
+use tor_provider::postpaid::PostpaidPolicy;
+use tor_provider::pricing::PricingTable;
 use tor_provider::proxy_local_client::ProxyLocalClient;
 use tor_provider::server_host::{AppState, create_router};
+use tor_provider::ticket_ledger::TicketLedger;
 use tor_provider::tor::bootstrap_tor_client;
 use tracing::info;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
@@ -42,9 +53,94 @@ async fn main() -> Result<()> {
         .await?;
     info!("Nimbus RPC ready!");
 
-    // initialise HiddenPaymentChannels client
-    let hpc_client = HpcClient::new(config.hpc.hpc_service_url.clone());
-    info!("HiddenPaymentChannels client initialized");
+    // initialise payment backend
+    let payment_backend = create_payment_backend(&config.hpc)?;
+    info!("payment backend initialized");
+
+    // open the persistent ticket ledger
+    let ledger = TicketLedger::open(&config.ledger_path())?;
+    info!("ticket ledger ready");
+
+    // load per-method pricing
+    let pricing = match &config.pricing_file {
+        Some(path) => PricingTable::load(path, config.hpc.ticket_price_wei)?,
+        None => PricingTable::flat(config.hpc.ticket_price_wei),
+    };
+
+    // the configured channel, tickets for unregistered contracts are routed to it
+    let default_channel = HostChannel {
+        contract_address: config.hpc.contract_address(),
+        railgun_address: config.hpc.railgun_address(),
+        funds_monitor: FundsMonitor::new(
+            payment_backend.clone(),
+            config.funds.funds_warn_thresholds_wei.clone(),
+        ),
+        payment_backend,
+        pricing: Arc::new(pricing),
+    };
+    let channels = match &config.channels_file {
+        Some(path) => ChannelRegistry::load(
+            path,
+            default_channel,
+            &config.hpc,
+            &config.funds.funds_warn_thresholds_wei,
+        )?,
+        None => ChannelRegistry::single(default_channel),
+    };
+
+    // accept lottery tickets if configured
+    let lottery = config
+        .lottery_win_probability_ppm
+        .map(|ppm| LotteryPolicy::load_or_generate(ppm, &config.lottery_secret_file()))
+        .transpose()?;
+
+    // serve bootstrap requests without a ticket if configured
+    let free_tier = FreeTierPolicy::new(
+        &config.free_tier.free_methods,
+        &config.free_tier.free_contracts,
+        config.free_tier.free_calls_per_minute,
+    );
+
+    // bill responses by size if configured
+    let postpaid = config.postpaid.postpaid_wei_per_kib.map(|wei_per_kib| {
+        PostpaidPolicy::new(
+            wei_per_kib,
+            config.postpaid.postpaid_credit_limit_wei,
+            &config.postpaid.postpaid_methods,
+        )
+    });
+
+    // claim tickets in the background
+    let claim_task = if config.validate_tickets && config.claim.claim_tickets {
+        Some(ClaimScheduler::new(channels.clone(), ledger.clone(), config.claim.clone()).spawn())
+    } else {
+        info!("automatic ticket claiming disabled");
+        None
+    };
+
+    // watch the channels' funds, tickets above them could never be claimed
+    let funds_tasks = match config.funds.check_interval() {
+        Some(interval) if config.validate_tickets => channels.spawn_funds_monitors(interval),
+        _ => Vec::new(),
+    };
+
+    // sign payment receipts with the onion identity, a host started before keeps the identity in
+    // the Tor keystore and with it its onion address
+    let (receipt_signer, onion_identity) = match stored_identity(&tor_manager)? {
+        Some(identity) => {
+            info!("signing payment receipts with the hidden service's identity key");
+            (ReceiptSigner::from_onion_identity(identity), None)
+        }
+        None => {
+            let receipt_signer = ReceiptSigner::load_or_generate(&config.receipt_key_file())?;
+            let onion_identity = receipt_signer.onion_identity();
+            (receipt_signer, Some(onion_identity))
+        }
+    };
+    info!(
+        "payment receipts signed with {}",
+        receipt_signer.receipt_key().public_key
+    );
 
     // create local HTTP client for forwarding to Nimbus
     let local_client = ProxyLocalClient::new(config.tor.request_timeout())?;
@@ -52,10 +148,18 @@ async fn main() -> Result<()> {
 
     // create application state
     let app_state = AppState {
-        local_client: local_client,
+        local_client,
         validate_tickets: config.validate_tickets,
         nimbus_rpc_url: config.nimbus_rpc_url.clone(),
-        hpc_client: hpc_client,
+        nimbus_ws_url: config.nimbus_ws_url(),
+        channels,
+        ledger,
+        lottery,
+        free_tier,
+        postpaid,
+        network: config.hpc.network.clone(),
+        receipt_signer,
+        body_limits: BodyLimits::from_config(&config.body_limits),
         ready_rx: tor_manager.ready_receiver(),
     };
 
@@ -89,6 +193,7 @@ async fn main() -> Result<()> {
             tor_manager,
             config.listen_addr.port(),
             config.hidden_service_port,
+            onion_identity,
         )
         .await?;
 
@@ -123,6 +228,12 @@ async fn main() -> Result<()> {
 
     // Cleanup
     info!("shutting down...");
+    if let Some(claim_task) = claim_task {
+        claim_task.abort();
+    }
+    for funds_task in funds_tasks {
+        funds_task.abort();
+    }
     hidden_service.stop().await?;
 
     info!("shutdown complete");
@@ -0,0 +1,80 @@
+use anyhow::Result;
+use clap::Parser;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::signal;
+use tor_provider::config::MockHpcConfig;
+use tor_provider::mock_hpc::{create_router, log_identity};
+use tor_provider::payment_backend::MemoryBackend;
+use tracing::info;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // initialize tracing/logging
+    init_tracing();
+
+    // parse configuration from environment and CLI
+    let config = MockHpcConfig::parse();
+    info!("loaded configuration: {:?}", config);
+    info!("starting mock HiddenPaymentChannels service");
+
+    // simulated contract and deterministic ticket signer
+    let backend = Arc::new(MemoryBackend::new(
+        config.funds_wei,
+        config.ticket_price_wei,
+    ));
+    log_identity(&backend);
+
+    // bind to the listen address
+    let listener = TcpListener::bind(&config.listen_addr).await?;
+    info!("mock service listening on {}", config.listen_addr);
+
+    axum::serve(listener, create_router(backend))
+        .with_graceful_shutdown(shutdown_signal())
+        .await?;
+
+    Ok(())
+}
+
+/// initialize tracing/logging with environment filter
+fn init_tracing() {
+    // default log level
+    let default_log = "info,hyper=warn";
+
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| default_log.into()),
+        )
+        .with(tracing_subscriber::fmt::layer().with_target(true))
+        .init();
+}
+
+/// wait for shutdown signal (SIGINT/SIGTERM)
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install signal handler")
+            .recv()
+            .await;
+    };
+
+    tokio::select! {
+        _ = ctrl_c => {
+            info!("Received SIGINT (Ctrl+C), initiating graceful shutdown...");
+        },
+        _ = terminate => {
+            info!("Received SIGTERM, initiating graceful shutdown...");
+        },
+    }
+
+    info!("Shutdown signal received, draining in-flight requests...");
+}
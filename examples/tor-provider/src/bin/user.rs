@@ -1,13 +1,21 @@
 use anyhow::Result;
 use clap::Parser;
+use std::hash::RandomState;
+use std::net::SocketAddr;
 use tokio::net::TcpListener;
 use tokio::signal;
+use tor_provider::body_limit::BodyLimits;
 use tor_provider::config::UserConfig;
-use tor_provider::hpc_service::HpcClient;
+use tor_provider::connection_pool::ConnectionPool;
+use tor_provider::payment_receipt::ReceiptBook;
+use tor_provider::payment_requirements::RequirementsCache;
+use tor_provider::postpaid::DebtBook;
+use tor_provider::provider_registry::{ProviderRegistry, UserChannel};
 use tor_provider::proxy_tor_client::ProxyTorClient;
 use tor_provider::server_user::AppState;
 use tor_provider::server_user::create_router;
-use tor_provider::tor::bootstrap_tor_client;
+use tor_provider::spend_budget::SpendBudget;
+use tor_provider::tor::{CircuitIsolation, bootstrap_tor_client};
 use tracing::info;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -26,19 +34,66 @@ async fn main() -> Result<()> {
     let tor_manager = bootstrap_tor_client(config.tor.tor_data_dir.clone()).await?;
     info!("TOR client ready!");
 
-    // create HTTP client that routes through Tor
-    let tor_http_client = ProxyTorClient::new(tor_manager.clone(), config.tor.request_timeout())?;
+    // create HTTP client that routes through Tor, reusing keep-alive and HTTP/2 connections to
+    // providers
+    let pool = ConnectionPool::new(
+        config.pool.pool_max_idle_connections,
+        config.pool.idle_timeout(),
+    );
+    let pool_task = pool.spawn_reaper();
+    let tor_http_client = ProxyTorClient::new(tor_manager.clone(), config.tor.request_timeout())?
+        .with_pool(pool)
+        .with_http2(config.pool.http2)
+        .with_isolation(CircuitIsolation::from_config(&config));
     info!("created TOR HTTP client (provider URL must be specified via query parameter)");
 
-    // create HPC client
-    let hpc_client = HpcClient::new(config.hpc.hpc_service_url.clone());
-    info!("HiddenPaymentChannels client initialized");
+    // the configured channel pays providers that are not in the providers file
+    let default_channel = UserChannel::from_config(
+        &config.hpc,
+        config.postpaid,
+        &config.funds.funds_warn_thresholds_wei,
+    )?;
+    info!("payment backend initialized");
+    let (providers, provider_limits) = match &config.providers_file {
+        Some(path) => ProviderRegistry::load(
+            path,
+            default_channel,
+            &config.hpc,
+            config.postpaid,
+            &config.funds.funds_warn_thresholds_wei,
+        )?,
+        None => (
+            ProviderRegistry::single(default_channel),
+            Default::default(),
+        ),
+    };
+
+    // open the persistent spend database
+    let spend_budget = SpendBudget::open(&config.budget.spend_db_path(), config.budget.limits())?
+        .with_provider_limits(provider_limits);
+    info!("spending limits: {:?}", spend_budget.limits());
+
+    // watch the channels' funds against the tickets already signed and continue their nonces
+    providers.restore_latest_tickets(&spend_budget)?;
+    let funds_tasks = match config.funds.check_interval() {
+        Some(interval) => providers.spawn_funds_monitors(interval),
+        None => Vec::new(),
+    };
 
     // create application state
     let app_state = AppState {
         client: tor_http_client,
         issue_payment_tickets: config.issue_payment_tickets,
-        hpc_client: hpc_client,
+        providers,
+        payment_requirements: RequirementsCache::new(),
+        payment_flow: config.payment_flow,
+        network: config.hpc.network.clone(),
+        spend_budget,
+        max_payment_retries: config.max_payment_retries,
+        receipts: ReceiptBook::new(),
+        body_limits: BodyLimits::from_config(&config.body_limits),
+        debts: DebtBook::new(config.postpaid_allowance_wei),
+        local_client_keys: RandomState::new(),
         ready_rx: tor_manager.ready_receiver(),
     };
 
@@ -54,9 +109,18 @@ async fn main() -> Result<()> {
     );
 
     // start the server with graceful shutdown
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await?;
+    // the source address tells local clients apart for --tor-isolation per-local-client
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await?;
+
+    for funds_task in funds_tasks {
+        funds_task.abort();
+    }
+    pool_task.abort();
 
     info!("server shut down gracefully");
     Ok(())
@@ -0,0 +1,130 @@
+use crate::config::BodyLimitConfig;
+use crate::rpc_utils::JsonRpcErrorResponse;
+use axum::{
+    BoxError,
+    body::{Body, HttpBody},
+    extract::State,
+    http::{Response, StatusCode, header},
+};
+use bytes::Bytes;
+use http_body_util::{LengthLimitError, Limited};
+use tower_http::limit::RequestBodyLimitLayer;
+use tracing::warn;
+
+/// size caps on the bodies a proxy passes through
+///
+/// requests are small and read whole (they are priced and may be resent), responses are
+/// streamed to the client and only cut off once they grow over the cap, so a large `eth_getLogs`
+/// response never sits in memory as a whole
+#[derive(Clone, Copy, Debug)]
+pub struct BodyLimits {
+    // largest request body (bytes)
+    pub request: usize,
+    // largest response body (bytes)
+    pub response: usize,
+}
+
+impl BodyLimits {
+    /// limits from the command line configuration
+    pub fn from_config(config: &BodyLimitConfig) -> Self {
+        Self {
+            request: config.max_request_body_bytes,
+            response: config.max_response_body_bytes,
+        }
+    }
+
+    /// layer refusing requests whose body is over the request cap, see `json_rpc_too_large`
+    pub fn request_layer(&self) -> RequestBodyLimitLayer {
+        RequestBodyLimitLayer::new(self.request)
+    }
+
+    /// stream an upstream response body, refused upfront when its declared length is over the
+    /// response cap and cut off once it grows over it
+    pub fn stream_response(
+        &self,
+        headers: &hyper::HeaderMap,
+        body: hyper::Body,
+    ) -> Result<Body, JsonRpcErrorResponse> {
+        let declared_length = headers
+            .get(hyper::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok());
+        if declared_length.is_some_and(|length| length > self.response) {
+            warn!(
+                "upstream response of {:?} bytes is over the {} byte limit",
+                declared_length, self.response
+            );
+            return Err(JsonRpcErrorResponse::response_too_large(self.response));
+        }
+
+        Ok(Body::new(Limited::new(
+            Body::from_stream(body),
+            self.response,
+        )))
+    }
+
+    /// read a whole upstream response body up to the response cap
+    pub async fn read_response(&self, body: hyper::Body) -> Result<Bytes, JsonRpcErrorResponse> {
+        self.read(Body::from_stream(body)).await
+    }
+
+    /// read a whole response body that is already streamed up to the response cap
+    pub async fn read(&self, body: Body) -> Result<Bytes, JsonRpcErrorResponse> {
+        axum::body::to_bytes(body, self.response)
+            .await
+            .map_err(|e| {
+                if is_too_large(&e) {
+                    warn!("response body is over the {} byte limit", self.response);
+                    JsonRpcErrorResponse::response_too_large(self.response)
+                } else {
+                    warn!("failed to read response body: {}", e);
+                    JsonRpcErrorResponse::connection_error(e.to_string())
+                }
+            })
+    }
+}
+
+/// whether a body error comes from a body growing over its size limit
+pub fn is_too_large(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut error = Some(error);
+    while let Some(e) = error {
+        if e.is::<LengthLimitError>() {
+            return true;
+        }
+        error = e.source();
+    }
+    false
+}
+
+/// turn the plain text 413 of the request limit layer into a JSON-RPC `body_too_large` error
+pub async fn json_rpc_too_large<B>(
+    State(limit): State<usize>,
+    response: Response<B>,
+) -> Response<Body>
+where
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("application/json"));
+    if response.status() != StatusCode::PAYLOAD_TOO_LARGE || is_json {
+        return response.map(Body::new);
+    }
+
+    warn!("request body is over the {} byte limit", limit);
+    body_too_large_response(limit)
+}
+
+/// 413 response with a JSON-RPC `body_too_large` error
+pub fn body_too_large_response(limit: usize) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::PAYLOAD_TOO_LARGE)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            JsonRpcErrorResponse::body_too_large(limit).to_json_bytes_for(None),
+        ))
+        .unwrap()
+}
@@ -0,0 +1,189 @@
+use crate::config::HpcConfig;
+use crate::funds_monitor::FundsMonitor;
+use crate::hpc_service::HpcClient;
+use crate::payment_backend::{ContractReader, NativeBackend, PaymentBackend};
+use crate::pricing::PricingTable;
+use crate::ticket_ledger::channel_key;
+use crate::ticket_verifier::TicketVerifier;
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::info;
+
+/// a paying user's channel as the host sees it
+#[derive(Clone)]
+pub struct HostChannel {
+    // HiddenPaymentChannels contract, `None` for the default channel when no contract is
+    // configured
+    pub contract_address: Option<String>,
+    // railgun address tickets on this channel must pay to
+    pub railgun_address: Option<String>,
+    // validates this channel's tickets and reads its funds
+    pub payment_backend: Arc<dyn PaymentBackend>,
+    pub pricing: Arc<PricingTable>,
+    pub funds_monitor: FundsMonitor,
+}
+
+/// channel registry file as written by the operator, keyed by contract address:
+///
+/// ```toml
+/// [channels."0x5FbDB2315678afecb367f032d93F642f64180aa3"]
+/// ticket_signer_address = "0xf39Fd6e51aad88F6F4ce6aB8827279cffFb92266"
+/// to_railgun_address = "0zk1..."
+/// pricing_file = "alice-pricing.toml"
+/// ```
+///
+/// `to_railgun_address` defaults to `--railgun-address`, prices default to `ticket_price_wei`
+/// or else `--ticket-price-wei`, and `pricing_file` is relative to the registry file
+#[derive(Deserialize)]
+struct RegistryFile {
+    #[serde(default)]
+    channels: BTreeMap<String, ChannelEntry>,
+}
+
+#[derive(Deserialize)]
+struct ChannelEntry {
+    ticket_signer_address: String,
+    to_railgun_address: Option<String>,
+    #[serde(default, with = "crate::pricing::wei_option")]
+    ticket_price_wei: Option<u128>,
+    pricing_file: Option<PathBuf>,
+}
+
+/// the channels a host serves, tickets are routed to their contract's channel, the default
+/// channel serves its configured contract or, without one, every unregistered contract
+#[derive(Clone)]
+pub struct ChannelRegistry {
+    default: HostChannel,
+    channels: Arc<HashMap<String, HostChannel>>,
+}
+
+impl ChannelRegistry {
+    /// a registry serving only the default channel
+    pub fn single(default: HostChannel) -> Self {
+        Self {
+            default,
+            channels: Arc::new(HashMap::new()),
+        }
+    }
+
+    /// load the channels of a registry file next to the default channel
+    ///
+    /// registered tickets are verified natively against the channel's signer, funds are read
+    /// from the contract when `--eth-rpc-url` is set and claims go through the service
+    pub fn load(
+        path: &Path,
+        default: HostChannel,
+        config: &HpcConfig,
+        warn_thresholds: &[u128],
+    ) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read channel registry {:?}", path))?;
+        let file: RegistryFile = toml::from_str(&contents)
+            .with_context(|| format!("failed to parse channel registry {:?}", path))?;
+        let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+
+        let mut channels = HashMap::new();
+        for (contract_address, entry) in file.channels {
+            let railgun_address = entry
+                .to_railgun_address
+                .or_else(|| config.railgun_address.clone());
+            let default_price = entry.ticket_price_wei.unwrap_or(config.ticket_price_wei);
+            let pricing = match &entry.pricing_file {
+                Some(pricing_file) => {
+                    PricingTable::load(&base_dir.join(pricing_file), default_price)?
+                }
+                None => PricingTable::flat(default_price),
+            };
+
+            let mut verifier = TicketVerifier::new(&entry.ticket_signer_address)
+                .with_context(|| format!("invalid ticket signer for channel {}", contract_address))?
+                .with_expected_contract_address(&contract_address)
+                .with_context(|| {
+                    format!("invalid channel contract address {}", contract_address)
+                })?;
+            if let Some(railgun_address) = &railgun_address {
+                verifier = verifier.with_expected_to_railgun_address(railgun_address.clone());
+            }
+            let contract = config
+                .eth_rpc_url
+                .as_ref()
+                .map(|rpc_url| ContractReader::new(rpc_url.clone(), contract_address.clone()));
+            let payment_backend: Arc<dyn PaymentBackend> = Arc::new(NativeBackend::new(
+                None,
+                Some(verifier),
+                contract,
+                HpcClient::new(config.hpc_service_url.clone()),
+            ));
+
+            info!(
+                "registered channel {} (signer: {}, default price: {} wei)",
+                contract_address, entry.ticket_signer_address, pricing.default
+            );
+            channels.insert(
+                channel_key(&contract_address),
+                HostChannel {
+                    contract_address: Some(contract_address),
+                    railgun_address,
+                    funds_monitor: FundsMonitor::new(
+                        payment_backend.clone(),
+                        warn_thresholds.to_vec(),
+                    ),
+                    payment_backend,
+                    pricing: Arc::new(pricing),
+                },
+            );
+        }
+
+        info!("loaded {} channels from {:?}", channels.len(), path);
+        Ok(Self {
+            default,
+            channels: Arc::new(channels),
+        })
+    }
+
+    /// the channel serving a contract, `None` if the host does not serve it
+    ///
+    /// every channel tracks its own funds, so tickets for a contract the default channel is not
+    /// configured for are refused rather than mixed into its funds
+    pub fn channel(&self, contract_address: &str) -> Option<&HostChannel> {
+        let key = channel_key(contract_address);
+        if let Some(channel) = self.channels.get(&key) {
+            return Some(channel);
+        }
+        match &self.default.contract_address {
+            Some(default) if channel_key(default) != key => None,
+            _ => Some(&self.default),
+        }
+    }
+
+    /// the configured channel
+    pub fn default_channel(&self) -> &HostChannel {
+        &self.default
+    }
+
+    /// railgun address every channel pays to, `None` if the channels pay to different ones
+    pub fn shared_railgun_address(&self) -> Option<&str> {
+        let mut channels = self.channels();
+        let railgun_address = channels.next()?.railgun_address.as_deref()?;
+        channels
+            .all(|channel| channel.railgun_address.as_deref() == Some(railgun_address))
+            .then_some(railgun_address)
+    }
+
+    /// every channel, the default one first
+    pub fn channels(&self) -> impl Iterator<Item = &HostChannel> {
+        std::iter::once(&self.default).chain(self.channels.values())
+    }
+
+    /// poll the funds of every channel in the background
+    pub fn spawn_funds_monitors(&self, interval: Duration) -> Vec<JoinHandle<()>> {
+        self.channels()
+            .map(|channel| channel.funds_monitor.spawn(interval))
+            .collect()
+    }
+}
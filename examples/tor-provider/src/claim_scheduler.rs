@@ -0,0 +1,198 @@
+use crate::channel_registry::ChannelRegistry;
+use crate::config::ClaimConfig;
+use crate::ticket_ledger::{LedgerTicket, TicketLedger, channel_key};
+use anyhow::Result;
+use rand::Rng;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, sleep};
+use tracing::{debug, error, info, warn};
+
+/// per-channel claim progress
+#[derive(Debug, Default)]
+struct ChannelClaimState {
+    // when the pending claim should be sent, set once a ticket becomes eligible
+    claim_at: Option<Instant>,
+    // consecutive failed claims
+    failures: u32,
+}
+
+/// background task that claims the best outstanding ticket of every channel
+///
+/// claiming immediately would link onchain activity to usage, so a claim is only made once
+/// the ticket is worth `claim_min_amount_wei` or has waited `claim_max_age_secs`, and is then
+/// delayed by a random jitter
+pub struct ClaimScheduler {
+    // every channel claims through its own payment backend
+    registry: ChannelRegistry,
+    ledger: TicketLedger,
+    config: ClaimConfig,
+    channels: HashMap<String, ChannelClaimState>,
+}
+
+impl ClaimScheduler {
+    /// create a new claim scheduler
+    pub fn new(registry: ChannelRegistry, ledger: TicketLedger, config: ClaimConfig) -> Self {
+        Self {
+            registry,
+            ledger,
+            config,
+            channels: HashMap::new(),
+        }
+    }
+
+    /// run the scheduler in the background
+    pub fn spawn(self) -> JoinHandle<()> {
+        info!(
+            "starting claim scheduler (min amount: {} wei, max age: {}s, jitter: {}s)",
+            self.config.claim_min_amount_wei,
+            self.config.claim_max_age_secs,
+            self.config.claim_jitter_secs
+        );
+        tokio::spawn(self.run())
+    }
+
+    async fn run(mut self) {
+        let interval = Duration::from_secs(self.config.claim_check_interval_secs.max(1));
+
+        loop {
+            if let Err(e) = self.tick().await {
+                error!("claim scheduler failed to read the ledger: {}", e);
+            }
+            sleep(interval).await;
+        }
+    }
+
+    /// check every channel once
+    async fn tick(&mut self) -> Result<()> {
+        for contract_address in self.ledger.contract_addresses()? {
+            if let Err(e) = self.process_channel(&contract_address).await {
+                error!("failed to process claims for {}: {}", contract_address, e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn process_channel(&mut self, contract_address: &str) -> Result<()> {
+        let channel = channel_key(contract_address);
+
+        let Some(best) = self.ledger.best_unclaimed_ticket(contract_address)? else {
+            self.channels.remove(&channel);
+            return Ok(());
+        };
+
+        let now = Instant::now();
+        let eligible = self.is_eligible(contract_address, &best)?;
+        let state = self.channels.entry(channel).or_default();
+
+        let claim_at = match state.claim_at {
+            Some(claim_at) => claim_at,
+            None if eligible => {
+                let jitter = random_duration(self.config.claim_jitter_secs);
+                info!(
+                    "ticket with nonce {} for {} is eligible, claiming in {:?}",
+                    best.ticket.nonce, contract_address, jitter
+                );
+                *state.claim_at.insert(now + jitter)
+            }
+            None => return Ok(()),
+        };
+
+        if claim_at > now {
+            debug!(
+                "claim for {} scheduled in {:?}",
+                contract_address,
+                claim_at - now
+            );
+            return Ok(());
+        }
+
+        // claim the best ticket at the time of claiming, it may have grown since scheduling
+        let Some(channel) = self.registry.channel(contract_address) else {
+            anyhow::bail!("contract {} is no longer served", contract_address);
+        };
+        let payment_backend = &channel.payment_backend;
+        let outcome = match payment_backend.claim_ticket(&best.ticket).await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err("service did not claim the ticket".to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+
+        self.ledger.record_claim_attempt(
+            &best.ticket,
+            outcome.as_ref().map(|_| ()).map_err(|e| e.as_str()),
+        )?;
+
+        match outcome {
+            Ok(()) => {
+                self.ledger.mark_claimed(&best.ticket)?;
+                info!(
+                    "claimed ticket with nonce {} for {} ({} wei)",
+                    best.ticket.nonce, contract_address, best.ticket.amount
+                );
+                if best.is_lottery() {
+                    log_lottery_summary(&self.ledger, contract_address);
+                }
+                *state = ChannelClaimState::default();
+            }
+            Err(e) => {
+                state.failures += 1;
+                let backoff = retry_backoff(&self.config, state.failures);
+                warn!(
+                    "failed to claim ticket with nonce {} for {} (attempt {}), retrying in {:?}: {}",
+                    best.ticket.nonce, contract_address, state.failures, backoff, e
+                );
+                state.claim_at = Some(now + backoff);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// whether the best ticket should be claimed according to the configured rules
+    fn is_eligible(&self, contract_address: &str, best: &LedgerTicket) -> Result<bool> {
+        if best.ticket.amount_value()? >= self.config.claim_min_amount_wei {
+            return Ok(true);
+        }
+
+        let max_age = chrono::Duration::seconds(self.config.claim_max_age_secs as i64);
+        let oldest = self.ledger.unclaimed_since(contract_address)?;
+        Ok(oldest.is_some_and(|oldest| chrono::Utc::now() - oldest >= max_age))
+    }
+}
+
+/// compare what the lottery tickets of a channel won with what they were expected to win
+fn log_lottery_summary(ledger: &TicketLedger, contract_address: &str) {
+    match ledger.lottery_summary(contract_address) {
+        Ok(summary) => info!(
+            "lottery tickets for {}: {} received, {} won, {} wei won against {} wei expected",
+            contract_address,
+            summary.tickets,
+            summary.winners,
+            summary.won_value,
+            summary.expected_value
+        ),
+        Err(e) => warn!(
+            "failed to read the lottery tickets of {}: {}",
+            contract_address, e
+        ),
+    }
+}
+
+/// exponential backoff with jitter, capped at `claim_retry_max_secs`
+fn retry_backoff(config: &ClaimConfig, failures: u32) -> Duration {
+    let base = config.claim_retry_base_secs.max(1);
+    let secs = base
+        .saturating_mul(1u64 << failures.saturating_sub(1).min(32))
+        .min(config.claim_retry_max_secs.max(base));
+    Duration::from_secs(secs) + random_duration(base)
+}
+
+/// a random duration between zero and `max_secs`
+fn random_duration(max_secs: u64) -> Duration {
+    if max_secs == 0 {
+        return Duration::ZERO;
+    }
+    Duration::from_millis(rand::thread_rng().gen_range(0..=max_secs.saturating_mul(1000)))
+}
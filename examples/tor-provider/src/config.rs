@@ -1,3 +1,5 @@
+use crate::payment_backend::{MEMORY_RAILGUN_ADDRESS, PaymentBackendKind, memory_contract_address};
+use crate::spend_budget::{BudgetLimits, SpendLimits};
 use clap::Parser;
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
@@ -22,6 +24,22 @@ impl TorConfig {
     }
 }
 
+/// which requests may share Tor circuits, requests that may not never share a circuit or a
+/// connection to a provider
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IsolationPolicy {
+    /// requests to the same provider
+    PerProvider,
+    /// requests from the same local client, told apart by the Authorization header it sends
+    /// (credentials in its RPC URL) or else by its source port
+    PerLocalClient,
+    /// none, every request gets its own circuit
+    PerRequest,
+    /// requests made in the same time window
+    PerTimeWindow,
+}
+
 // HiddenPaymentChannels config
 #[derive(Parser, Debug, Clone, Serialize, Deserialize)]
 pub struct HpcConfig {
@@ -32,6 +50,324 @@ pub struct HpcConfig {
         default_value = "http://127.0.0.1:8080"
     )]
     pub hpc_service_url: String,
+
+    // payment backend: http (HiddenPaymentChannels service), native (tickets signed and verified
+    // in process) or memory (deterministic keys, simulated contract), defaults to native when
+    // --ticket-signer-address is set and http otherwise
+    #[arg(long, env = "PAYMENT_BACKEND", value_enum)]
+    pub payment_backend: Option<PaymentBackendKind>,
+
+    // file with the hex secp256k1 private key tickets are signed with (native backend, user)
+    #[arg(long, env = "TICKET_SIGNING_KEY_FILE")]
+    pub ticket_signing_key_file: Option<PathBuf>,
+
+    // address that signs the user's tickets, when set tickets are verified natively
+    // instead of calling the HiddenPaymentChannels service
+    #[arg(long, env = "TICKET_SIGNER_ADDRESS")]
+    pub ticket_signer_address: Option<String>,
+
+    // HiddenPaymentChannels contract address tickets are issued for
+    #[arg(long, env = "HPC_CONTRACT_ADDRESS")]
+    pub hpc_contract_address: Option<String>,
+
+    // host railgun address tickets pay to
+    #[arg(long, env = "RAILGUN_ADDRESS")]
+    pub railgun_address: Option<String>,
+
+    // price of a single request in wei, each ticket must add at least this much
+    // (the default price when a pricing file is used)
+    #[arg(long, env = "TICKET_PRICE_WEI", default_value = "300000000")]
+    pub ticket_price_wei: u128,
+
+    // EVM network tickets are paid on, advertised in x402 payment requirements
+    #[arg(long, env = "NETWORK", default_value = "sepolia")]
+    pub network: String,
+
+    // Ethereum RPC URL the native backend reads the HiddenPaymentChannels contract from
+    #[arg(long, env = "ETH_RPC_URL")]
+    pub eth_rpc_url: Option<String>,
+
+    // simulated contract balance of the memory backend (wei)
+    #[arg(
+        long,
+        env = "MEMORY_BACKEND_FUNDS_WEI",
+        default_value = "1000000000000000000"
+    )]
+    pub memory_backend_funds_wei: u128,
+}
+
+impl HpcConfig {
+    /// the HiddenPaymentChannels contract tickets are issued for, the memory backend always
+    /// uses its own
+    pub fn contract_address(&self) -> Option<String> {
+        match self.payment_backend() {
+            PaymentBackendKind::Memory => Some(memory_contract_address()),
+            _ => self.hpc_contract_address.clone(),
+        }
+    }
+
+    /// the railgun address tickets pay to, the memory backend always uses its own
+    pub fn railgun_address(&self) -> Option<String> {
+        match self.payment_backend() {
+            PaymentBackendKind::Memory => Some(MEMORY_RAILGUN_ADDRESS.to_string()),
+            _ => self.railgun_address.clone(),
+        }
+    }
+
+    /// the backend to use, see `payment_backend`
+    pub fn payment_backend(&self) -> PaymentBackendKind {
+        self.payment_backend
+            .unwrap_or(if self.ticket_signer_address.is_some() {
+                PaymentBackendKind::Native
+            } else {
+                PaymentBackendKind::Http
+            })
+    }
+}
+
+impl Default for HpcConfig {
+    fn default() -> Self {
+        Self {
+            hpc_service_url: "http://127.0.0.1:3000".to_string(),
+            payment_backend: None,
+            ticket_signing_key_file: None,
+            ticket_signer_address: None,
+            hpc_contract_address: None,
+            railgun_address: None,
+            ticket_price_wei: 300_000_000,
+            network: "sepolia".to_string(),
+            eth_rpc_url: None,
+            memory_backend_funds_wei: 1_000_000_000_000_000_000,
+        }
+    }
+}
+
+// automatic ticket claiming config (host)
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct ClaimConfig {
+    // claim tickets automatically in the background
+    #[arg(long, env = "CLAIM_TICKETS", default_value = "true")]
+    pub claim_tickets: bool,
+
+    // claim once the best unclaimed ticket is worth at least this much (wei)
+    #[arg(long, env = "CLAIM_MIN_AMOUNT_WEI", default_value = "3000000000")]
+    pub claim_min_amount_wei: u128,
+
+    // claim regardless of amount once the oldest unclaimed ticket is this old
+    #[arg(long, env = "CLAIM_MAX_AGE_SECS", default_value = "86400")]
+    pub claim_max_age_secs: u64,
+
+    // how often the ledger is checked for claimable tickets
+    #[arg(long, env = "CLAIM_CHECK_INTERVAL_SECS", default_value = "300")]
+    pub claim_check_interval_secs: u64,
+
+    // claims are delayed by a random amount up to this, so onchain activity does not line up
+    // with usage
+    #[arg(long, env = "CLAIM_JITTER_SECS", default_value = "3600")]
+    pub claim_jitter_secs: u64,
+
+    // initial delay before retrying a failed claim, doubled on every failure
+    #[arg(long, env = "CLAIM_RETRY_BASE_SECS", default_value = "60")]
+    pub claim_retry_base_secs: u64,
+
+    // maximum delay between claim retries
+    #[arg(long, env = "CLAIM_RETRY_MAX_SECS", default_value = "3600")]
+    pub claim_retry_max_secs: u64,
+}
+
+impl Default for ClaimConfig {
+    fn default() -> Self {
+        Self {
+            claim_tickets: true,
+            claim_min_amount_wei: 3_000_000_000,
+            claim_max_age_secs: 86400,
+            claim_check_interval_secs: 300,
+            claim_jitter_secs: 3600,
+            claim_retry_base_secs: 60,
+            claim_retry_max_secs: 3600,
+        }
+    }
+}
+
+// channel funds monitoring config
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct FundsConfig {
+    // how often the channel's available funds are read, 0 disables monitoring
+    #[arg(long, env = "FUNDS_CHECK_INTERVAL_SECS", default_value = "60")]
+    pub funds_check_interval_secs: u64,
+
+    // warn when the funds not yet promised by a ticket drop below any of these (wei, comma
+    // separated)
+    #[arg(long, env = "FUNDS_WARN_THRESHOLDS_WEI", value_delimiter = ',')]
+    pub funds_warn_thresholds_wei: Vec<u128>,
+}
+
+impl FundsConfig {
+    /// the polling interval, `None` when monitoring is disabled
+    pub fn check_interval(&self) -> Option<Duration> {
+        (self.funds_check_interval_secs > 0)
+            .then(|| Duration::from_secs(self.funds_check_interval_secs))
+    }
+}
+
+impl Default for FundsConfig {
+    fn default() -> Self {
+        Self {
+            funds_check_interval_secs: 60,
+            funds_warn_thresholds_wei: Vec::new(),
+        }
+    }
+}
+
+// free tier config (host), requests served without a ticket to bootstrap new users
+#[derive(Parser, Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FreeTierConfig {
+    // JSON-RPC methods served without a ticket (comma separated), empty disables the free tier
+    #[arg(long, env = "FREE_METHODS", value_delimiter = ',')]
+    pub free_methods: Vec<String>,
+
+    // only serve free calls targeting these contracts (comma separated), e.g. the Railgun
+    // contract, empty allows any target
+    #[arg(long, env = "FREE_CONTRACTS", value_delimiter = ',')]
+    pub free_contracts: Vec<String>,
+
+    // free calls served per minute across all users, unlimited when not set
+    #[arg(long, env = "FREE_CALLS_PER_MINUTE")]
+    pub free_calls_per_minute: Option<u32>,
+}
+
+// postpaid metering config (host), responses billed by size on top of their price
+#[derive(Parser, Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PostpaidConfig {
+    // price of every started KiB of a metered response (wei), not set disables metering
+    #[arg(long, env = "POSTPAID_WEI_PER_KIB")]
+    pub postpaid_wei_per_kib: Option<u128>,
+
+    // methods billed by response size (comma separated), empty meters every paid request
+    #[arg(long, env = "POSTPAID_METHODS", value_delimiter = ',')]
+    pub postpaid_methods: Vec<String>,
+
+    // debt (wei) a ticket may leave unpaid, requests are refused until the debt above it is paid
+    #[arg(long, env = "POSTPAID_CREDIT_LIMIT_WEI", default_value = "0")]
+    pub postpaid_credit_limit_wei: u128,
+}
+
+// body size limits (user and host), larger requests are refused and larger responses cut off
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct BodyLimitConfig {
+    // largest request body (or WebSocket message) accepted from the wallet or the user (bytes)
+    #[arg(long, env = "MAX_REQUEST_BODY_BYTES", default_value = "2097152")]
+    pub max_request_body_bytes: usize,
+
+    // largest response body passed on from the provider or Nimbus (bytes)
+    #[arg(long, env = "MAX_RESPONSE_BODY_BYTES", default_value = "33554432")]
+    pub max_response_body_bytes: usize,
+}
+
+impl Default for BodyLimitConfig {
+    fn default() -> Self {
+        Self {
+            max_request_body_bytes: 2 * 1024 * 1024,
+            max_response_body_bytes: 32 * 1024 * 1024,
+        }
+    }
+}
+
+// connection pool config (user), keep-alive and HTTP/2 connections to providers over Tor
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionPoolConfig {
+    // idle connections kept across all providers, 0 opens a new Tor stream for every request
+    #[arg(long, env = "POOL_MAX_IDLE_CONNECTIONS", default_value = "16")]
+    pub pool_max_idle_connections: usize,
+
+    // close connections idle for longer than this
+    #[arg(long, env = "POOL_IDLE_TIMEOUT_SECS", default_value = "90")]
+    pub pool_idle_timeout_secs: u64,
+
+    // speak HTTP/2 with providers that support it, concurrent requests then share one Tor stream
+    #[arg(long, env = "HTTP2", default_value = "true")]
+    pub http2: bool,
+}
+
+impl ConnectionPoolConfig {
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.pool_idle_timeout_secs)
+    }
+}
+
+impl Default for ConnectionPoolConfig {
+    fn default() -> Self {
+        Self {
+            pool_max_idle_connections: 16,
+            pool_idle_timeout_secs: 90,
+            http2: true,
+        }
+    }
+}
+
+// spending limits config (user), limits that are not set are unlimited
+#[derive(Parser, Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BudgetConfig {
+    // largest amount a single request may spend (wei)
+    #[arg(long, env = "MAX_REQUEST_SPEND_WEI")]
+    pub max_request_spend_wei: Option<u128>,
+
+    // spend on a single provider over the last hour (wei)
+    #[arg(long, env = "PROVIDER_HOURLY_BUDGET_WEI")]
+    pub provider_hourly_budget_wei: Option<u128>,
+
+    // spend on a single provider over the last day (wei)
+    #[arg(long, env = "PROVIDER_DAILY_BUDGET_WEI")]
+    pub provider_daily_budget_wei: Option<u128>,
+
+    // spend on a single provider ever (wei)
+    #[arg(long, env = "PROVIDER_LIFETIME_BUDGET_WEI")]
+    pub provider_lifetime_budget_wei: Option<u128>,
+
+    // spend on all providers over the last hour (wei)
+    #[arg(long, env = "HOURLY_BUDGET_WEI")]
+    pub hourly_budget_wei: Option<u128>,
+
+    // spend on all providers over the last day (wei)
+    #[arg(long, env = "DAILY_BUDGET_WEI")]
+    pub daily_budget_wei: Option<u128>,
+
+    // spend on all providers ever (wei)
+    #[arg(long, env = "LIFETIME_BUDGET_WEI")]
+    pub lifetime_budget_wei: Option<u128>,
+
+    // SQLite spend database path (defaults to <data dir>/tor-provider/user-spend.sqlite)
+    #[arg(long, env = "SPEND_DB_PATH")]
+    pub spend_db_path: Option<PathBuf>,
+}
+
+impl BudgetConfig {
+    /// the limits to enforce
+    pub fn limits(&self) -> BudgetLimits {
+        BudgetLimits {
+            per_request: self.max_request_spend_wei,
+            provider: SpendLimits {
+                hourly: self.provider_hourly_budget_wei,
+                daily: self.provider_daily_budget_wei,
+                lifetime: self.provider_lifetime_budget_wei,
+            },
+            total: SpendLimits {
+                hourly: self.hourly_budget_wei,
+                daily: self.daily_budget_wei,
+                lifetime: self.lifetime_budget_wei,
+            },
+        }
+    }
+
+    /// resolve the spend database path
+    pub fn spend_db_path(&self) -> PathBuf {
+        self.spend_db_path.clone().unwrap_or_else(|| {
+            dirs::data_dir()
+                .unwrap_or_else(|| PathBuf::from("."))
+                .join("tor-provider")
+                .join("user-spend.sqlite")
+        })
+    }
 }
 
 // tor-provider-user config
@@ -46,6 +382,22 @@ pub struct UserConfig {
     #[command(flatten)]
     pub hpc: HpcConfig,
 
+    // spending limits config
+    #[command(flatten)]
+    pub budget: BudgetConfig,
+
+    // channel funds monitoring config
+    #[command(flatten)]
+    pub funds: FundsConfig,
+
+    // body size limits config
+    #[command(flatten)]
+    pub body_limits: BodyLimitConfig,
+
+    // connection pool config
+    #[command(flatten)]
+    pub pool: ConnectionPoolConfig,
+
     // local server listen address
     #[arg(long, env = "LISTEN_ADDR", default_value = "127.0.0.1:8545")]
     pub listen_addr: SocketAddr,
@@ -53,6 +405,62 @@ pub struct UserConfig {
     // disable payments
     #[arg(long, env = "ISSUE_PAYMENT_TICKETS", default_value = "true")]
     pub issue_payment_tickets: bool,
+
+    // prepay: attach a ticket to every request, x402: send requests unpaid and pay when the
+    // provider answers with an x402 payment challenge
+    #[arg(long, env = "PAYMENT_FLOW", value_enum, default_value = "prepay")]
+    pub payment_flow: PaymentFlow,
+
+    // pay the postpaid debt providers bill for metered responses and subscription
+    // notifications with the next ticket, needs a backend that can add debt to tickets (native
+    // or memory), without it reported debt is left unpaid
+    #[arg(long, env = "POSTPAID", default_value = "false")]
+    pub postpaid: bool,
+
+    // what a provider may bill for a postpaid response on top of the price of its request
+    // (wei), a reported debt growing by more is not paid
+    #[arg(long, env = "POSTPAID_ALLOWANCE_WEI", default_value = "300000000")]
+    pub postpaid_allowance_wei: u128,
+
+    // how often a ticket the provider rejected with a 402 is replaced by a new one before the
+    // 402 is passed on to the wallet
+    #[arg(long, env = "MAX_PAYMENT_RETRIES", default_value = "2")]
+    pub max_payment_retries: u32,
+
+    // TOML file of the providers to pay, keyed by name, each with its onion URL, channel,
+    // railgun address and budget, other providers are paid through the channel configured above
+    #[arg(long, env = "PROVIDERS_FILE")]
+    pub providers_file: Option<PathBuf>,
+
+    // which requests may share Tor circuits
+    #[arg(
+        long,
+        env = "TOR_ISOLATION",
+        value_enum,
+        default_value = "per-provider"
+    )]
+    pub tor_isolation: IsolationPolicy,
+
+    // length of a time window in seconds for --tor-isolation per-time-window
+    #[arg(long, env = "TOR_ISOLATION_WINDOW_SECS", default_value = "600")]
+    pub tor_isolation_window_secs: u64,
+}
+
+impl UserConfig {
+    /// length of a Tor isolation time window
+    pub fn isolation_window(&self) -> Duration {
+        Duration::from_secs(self.tor_isolation_window_secs)
+    }
+}
+
+/// when the user proxy attaches payment tickets
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PaymentFlow {
+    /// attach a ticket to every request
+    Prepay,
+    /// pay on the provider's x402 challenge, then upfront once its requirements are known
+    X402,
 }
 
 impl Default for UserConfig {
@@ -62,11 +470,20 @@ impl Default for UserConfig {
                 request_timeout_secs: 20,
                 tor_data_dir: None,
             },
-            hpc: HpcConfig {
-                hpc_service_url: "http://127.0.0.1:3000".to_string(),
-            },
+            hpc: HpcConfig::default(),
+            budget: BudgetConfig::default(),
+            funds: FundsConfig::default(),
+            body_limits: BodyLimitConfig::default(),
+            pool: ConnectionPoolConfig::default(),
             listen_addr: "127.0.0.1:8545".parse().unwrap(),
             issue_payment_tickets: true,
+            payment_flow: PaymentFlow::Prepay,
+            postpaid: false,
+            postpaid_allowance_wei: 300_000_000,
+            max_payment_retries: 2,
+            providers_file: None,
+            tor_isolation: IsolationPolicy::PerProvider,
+            tor_isolation_window_secs: 600,
         }
     }
 }
@@ -83,6 +500,26 @@ pub struct HostConfig {
     #[command(flatten)]
     pub hpc: HpcConfig,
 
+    // automatic ticket claiming config
+    #[command(flatten)]
+    pub claim: ClaimConfig,
+
+    // channel funds monitoring config
+    #[command(flatten)]
+    pub funds: FundsConfig,
+
+    // free tier config
+    #[command(flatten)]
+    pub free_tier: FreeTierConfig,
+
+    // postpaid metering config
+    #[command(flatten)]
+    pub postpaid: PostpaidConfig,
+
+    // body size limits config
+    #[command(flatten)]
+    pub body_limits: BodyLimitConfig,
+
     // local server listen address
     #[arg(long, env = "LISTEN_ADDR", default_value = "127.0.0.1:9545")]
     pub listen_addr: SocketAddr,
@@ -92,6 +529,10 @@ pub struct HostConfig {
     #[arg(long, env = "NIMBUS_RPC_URL", default_value = "http://127.0.0.1:8546")]
     pub nimbus_rpc_url: String,
 
+    // Nimbus WebSocket URL subscriptions are proxied to (defaults to the RPC URL with ws://)
+    #[arg(long, env = "NIMBUS_WS_URL")]
+    pub nimbus_ws_url: Option<String>,
+
     // port to expose on the .onion hidden service
     #[arg(long, env = "HIDDEN_SERVICE_PORT", default_value = "80")]
     pub hidden_service_port: u16,
@@ -99,6 +540,74 @@ pub struct HostConfig {
     // validate tickets
     #[arg(long, env = "VALIDATE_TICKETS", default_value = "true")]
     pub validate_tickets: bool,
+
+    // TOML file with per JSON-RPC method prices
+    #[arg(long, env = "PRICING_FILE")]
+    pub pricing_file: Option<PathBuf>,
+
+    // TOML registry of the paying users' channels, keyed by contract address, each with its
+    // ticket signer, railgun address and pricing
+    #[arg(long, env = "CHANNELS_FILE")]
+    pub channels_file: Option<PathBuf>,
+
+    // accept probabilistic (lottery) tickets that win with this chance in parts per million,
+    // a ticket pays for a request when face value * probability covers the price
+    #[arg(long, env = "LOTTERY_WIN_PROBABILITY_PPM")]
+    pub lottery_win_probability_ppm: Option<u32>,
+
+    // secret lottery tickets are drawn with, generated if missing
+    // (defaults to <data dir>/tor-provider/lottery-secret)
+    #[arg(long, env = "LOTTERY_SECRET_FILE")]
+    pub lottery_secret_file: Option<PathBuf>,
+
+    // SQLite ticket ledger path (defaults to <data dir>/tor-provider/host-ledger.sqlite)
+    #[arg(long, env = "LEDGER_PATH")]
+    pub ledger_path: Option<PathBuf>,
+
+    // ed25519 key payment receipts are signed with and identity key of the onion service,
+    // generated if missing, unused when the Tor keystore already holds the service's identity
+    // (defaults to <data dir>/tor-provider/receipt-key)
+    #[arg(long, env = "RECEIPT_KEY_FILE")]
+    pub receipt_key_file: Option<PathBuf>,
+}
+
+impl HostConfig {
+    /// resolve the Nimbus WebSocket URL
+    pub fn nimbus_ws_url(&self) -> String {
+        self.nimbus_ws_url
+            .clone()
+            .unwrap_or_else(|| crate::websocket::websocket_url(&self.nimbus_rpc_url))
+    }
+
+    /// resolve the ticket ledger path
+    pub fn ledger_path(&self) -> PathBuf {
+        self.ledger_path.clone().unwrap_or_else(|| {
+            dirs::data_dir()
+                .unwrap_or_else(|| PathBuf::from("."))
+                .join("tor-provider")
+                .join("host-ledger.sqlite")
+        })
+    }
+
+    /// resolve the lottery secret path
+    pub fn lottery_secret_file(&self) -> PathBuf {
+        self.lottery_secret_file.clone().unwrap_or_else(|| {
+            dirs::data_dir()
+                .unwrap_or_else(|| PathBuf::from("."))
+                .join("tor-provider")
+                .join("lottery-secret")
+        })
+    }
+
+    /// resolve the receipt key path
+    pub fn receipt_key_file(&self) -> PathBuf {
+        self.receipt_key_file.clone().unwrap_or_else(|| {
+            dirs::data_dir()
+                .unwrap_or_else(|| PathBuf::from("."))
+                .join("tor-provider")
+                .join("receipt-key")
+        })
+    }
 }
 
 impl Default for HostConfig {
@@ -108,13 +617,50 @@ impl Default for HostConfig {
                 request_timeout_secs: 20,
                 tor_data_dir: None,
             },
-            hpc: HpcConfig {
-                hpc_service_url: "http://127.0.0.1:3000".to_string(),
-            },
+            hpc: HpcConfig::default(),
+            claim: ClaimConfig::default(),
+            funds: FundsConfig::default(),
+            free_tier: FreeTierConfig::default(),
+            postpaid: PostpaidConfig::default(),
+            body_limits: BodyLimitConfig::default(),
             listen_addr: "127.0.0.1:9545".parse().unwrap(),
             nimbus_rpc_url: "http://127.0.0.1:8546".to_string(),
+            nimbus_ws_url: None,
             hidden_service_port: 80,
             validate_tickets: true,
+            pricing_file: None,
+            channels_file: None,
+            lottery_win_probability_ppm: None,
+            lottery_secret_file: None,
+            ledger_path: None,
+            receipt_key_file: None,
+        }
+    }
+}
+
+// tor-provider-mock-hpc config
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+#[command(author, version, about, long_about = None)]
+pub struct MockHpcConfig {
+    // listen address, matches the default HiddenPaymentChannels service URL
+    #[arg(long, env = "LISTEN_ADDR", default_value = "127.0.0.1:8080")]
+    pub listen_addr: SocketAddr,
+
+    // simulated contract balance (wei)
+    #[arg(long, env = "MOCK_FUNDS_WEI", default_value = "1000000000000000000")]
+    pub funds_wei: u128,
+
+    // price of a single request in wei, generated tickets add this much each
+    #[arg(long, env = "TICKET_PRICE_WEI", default_value = "300000000")]
+    pub ticket_price_wei: u128,
+}
+
+impl Default for MockHpcConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: "127.0.0.1:8080".parse().unwrap(),
+            funds_wei: 1_000_000_000_000_000_000,
+            ticket_price_wei: 300_000_000,
         }
     }
 }
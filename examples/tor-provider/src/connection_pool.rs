@@ -0,0 +1,324 @@
+use bytes::Bytes;
+use futures_util::future::Either;
+use futures_util::{FutureExt, Stream};
+use hyper::client::conn::{http1, http2};
+use hyper::{Body, Request, Response};
+use parking_lot::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+/// how long a connection handed back at the end of a response may take to accept the next
+/// request before it is given up
+const READY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// keep-alive HTTP connections to providers over Tor, keyed by provider
+///
+/// opening a Tor stream to an onion service and handshaking takes seconds, so an HTTP/1
+/// connection whose response was read to the end is kept for the next request to the same
+/// provider, and an HTTP/2 connection is shared by all concurrent requests to it. Connections
+/// are dropped once the provider closes them, after `idle_timeout` unused and when `max_idle`
+/// connections are already kept
+#[derive(Clone)]
+pub struct ConnectionPool {
+    idle: Arc<Mutex<HashMap<String, Vec<IdleConnection>>>>,
+    shared: Arc<Mutex<HashMap<String, IdleConnection<http2::SendRequest<Body>>>>>,
+    // providers that did not speak HTTP/2 with prior knowledge
+    http1_only: Arc<Mutex<HashSet<String>>>,
+    max_idle: usize,
+    idle_timeout: Duration,
+}
+
+struct IdleConnection<S = http1::SendRequest<Body>> {
+    sender: S,
+    since: Instant,
+}
+
+/// the sending half of an HTTP/1 or HTTP/2 connection to a provider
+pub enum Sender {
+    Http1(http1::SendRequest<Body>),
+    Http2(http2::SendRequest<Body>),
+}
+
+impl Sender {
+    /// send a request on the connection, it is handed to the connection right away so
+    /// requests on one connection go out in the order they were sent, the future resolves to
+    /// the response
+    pub fn send_request(
+        &mut self,
+        request: Request<Body>,
+    ) -> impl Future<Output = hyper::Result<Response<Body>>> + use<> {
+        match self {
+            Sender::Http1(sender) => Either::Left(sender.send_request(request)),
+            Sender::Http2(sender) => Either::Right(sender.send_request(request)),
+        }
+    }
+
+    /// whether the connection speaks HTTP/2
+    pub fn is_http2(&self) -> bool {
+        matches!(self, Sender::Http2(_))
+    }
+}
+
+impl ConnectionPool {
+    /// create a pool keeping up to `max_idle` connections for `idle_timeout` each, a pool of
+    /// size 0 keeps none
+    pub fn new(max_idle: usize, idle_timeout: Duration) -> Self {
+        Self {
+            idle: Arc::new(Mutex::new(HashMap::new())),
+            shared: Arc::new(Mutex::new(HashMap::new())),
+            http1_only: Arc::new(Mutex::new(HashSet::new())),
+            max_idle,
+            idle_timeout,
+        }
+    }
+
+    /// a connection to a provider that accepts a request, the shared HTTP/2 connection if there
+    /// is one, else an idle HTTP/1 connection
+    pub async fn checkout(&self, key: &str) -> Option<Sender> {
+        if let Some(sender) = self.checkout_shared(key) {
+            return Some(Sender::Http2(sender));
+        }
+
+        loop {
+            let mut connection = {
+                let mut idle = self.idle.lock();
+                self.expire(&mut idle);
+                idle.get_mut(key)?.pop()?
+            };
+
+            match tokio::time::timeout(READY_TIMEOUT, connection.sender.ready()).await {
+                Ok(Ok(())) => {
+                    debug!(
+                        "reusing connection to {} idle for {:?}",
+                        key,
+                        connection.since.elapsed()
+                    );
+                    return Some(Sender::Http1(connection.sender));
+                }
+                Ok(Err(e)) => debug!("dropping closed connection to {}: {}", key, e),
+                Err(_) => debug!("dropping connection to {} that is not ready", key),
+            }
+        }
+    }
+
+    fn checkout_shared(&self, key: &str) -> Option<http2::SendRequest<Body>> {
+        let mut shared = self.shared.lock();
+        self.expire_shared(&mut shared);
+        let connection = shared.get_mut(key)?;
+        connection.since = Instant::now();
+        debug!("sharing HTTP/2 connection to {}", key);
+        Some(connection.sender.clone())
+    }
+
+    /// share a new HTTP/2 connection with the following requests to the provider, a connection
+    /// already shared is kept
+    pub fn share(&self, key: &str, sender: http2::SendRequest<Body>) {
+        // locked in the same order as in `checkin`
+        let idle = self.idle.lock();
+        let mut shared = self.shared.lock();
+        self.expire_shared(&mut shared);
+        if shared.contains_key(key) || self.kept(&idle, &shared) >= self.max_idle {
+            return;
+        }
+        shared.insert(
+            key.to_string(),
+            IdleConnection {
+                sender,
+                since: Instant::now(),
+            },
+        );
+    }
+
+    /// keep a connection whose response was read to the end for the next request
+    pub fn checkin(&self, key: &str, mut sender: http1::SendRequest<Body>) {
+        if is_closed(&mut sender) {
+            return;
+        }
+
+        let mut idle = self.idle.lock();
+        self.expire(&mut idle);
+        if self.kept(&idle, &self.shared.lock()) >= self.max_idle {
+            debug!("connection pool is full, closing connection to {}", key);
+            return;
+        }
+        idle.entry(key.to_string())
+            .or_default()
+            .push(IdleConnection {
+                sender,
+                since: Instant::now(),
+            });
+    }
+
+    /// hand a response body out, an HTTP/1 connection returns to the pool once the body was
+    /// read to the end and is closed if it is dropped early
+    pub fn return_on_end(&self, key: String, sender: Sender, body: Body) -> Body {
+        let Sender::Http1(sender) = sender else {
+            return body;
+        };
+        if self.max_idle == 0 {
+            return body;
+        }
+        Body::wrap_stream(ReturnOnEnd {
+            body,
+            pool: self.clone(),
+            key,
+            sender: Some(sender),
+        })
+    }
+
+    /// drop expired and closed connections in the background, so they do not hold Tor streams
+    /// open until the next request
+    pub fn spawn_reaper(&self) -> JoinHandle<()> {
+        let pool = self.clone();
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(pool.idle_timeout.max(Duration::from_secs(2)) / 2);
+            loop {
+                interval.tick().await;
+                pool.expire(&mut pool.idle.lock());
+                pool.expire_shared(&mut pool.shared.lock());
+            }
+        })
+    }
+
+    /// remember that a provider does not speak HTTP/2 with prior knowledge
+    pub fn mark_http1_only(&self, key: &str) {
+        self.http1_only.lock().insert(key.to_string());
+    }
+
+    /// whether a provider did not speak HTTP/2 with prior knowledge
+    pub fn is_http1_only(&self, key: &str) -> bool {
+        self.http1_only.lock().contains(key)
+    }
+
+    fn kept(
+        &self,
+        idle: &HashMap<String, Vec<IdleConnection>>,
+        shared: &HashMap<String, IdleConnection<http2::SendRequest<Body>>>,
+    ) -> usize {
+        idle.values().map(Vec::len).sum::<usize>() + shared.len()
+    }
+
+    fn expire(&self, idle: &mut HashMap<String, Vec<IdleConnection>>) {
+        idle.retain(|key, connections| {
+            connections.retain_mut(|connection| {
+                let keep = connection.since.elapsed() < self.idle_timeout
+                    && !is_closed(&mut connection.sender);
+                if !keep {
+                    debug!("closing idle connection to {}", key);
+                }
+                keep
+            });
+            !connections.is_empty()
+        });
+    }
+
+    fn expire_shared(
+        &self,
+        shared: &mut HashMap<String, IdleConnection<http2::SendRequest<Body>>>,
+    ) {
+        shared.retain(|key, connection| {
+            let keep = connection.since.elapsed() < self.idle_timeout
+                && !matches!(connection.sender.ready().now_or_never(), Some(Err(_)));
+            if !keep {
+                debug!("closing shared HTTP/2 connection to {}", key);
+            }
+            keep
+        });
+    }
+}
+
+/// whether the provider closed the connection
+fn is_closed(sender: &mut http1::SendRequest<Body>) -> bool {
+    matches!(sender.ready().now_or_never(), Some(Err(_)))
+}
+
+/// response body that hands its connection back to the pool at the end
+struct ReturnOnEnd {
+    body: Body,
+    pool: ConnectionPool,
+    key: String,
+    sender: Option<http1::SendRequest<Body>>,
+}
+
+impl Stream for ReturnOnEnd {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let item = ready!(Pin::new(&mut self.body).poll_next(cx));
+        if item.is_none()
+            && let Some(sender) = self.sender.take()
+        {
+            self.pool.checkin(&self.key, sender);
+        }
+        Poll::Ready(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::DuplexStream;
+
+    /// an HTTP/1 connection and the provider's end of it
+    async fn connection() -> (http1::SendRequest<Body>, DuplexStream) {
+        let (client, provider) = tokio::io::duplex(1024);
+        let (sender, connection) = http1::handshake(client).await.unwrap();
+        tokio::spawn(connection);
+        (sender, provider)
+    }
+
+    #[tokio::test]
+    async fn reuses_idle_connections_per_provider() {
+        let pool = ConnectionPool::new(4, Duration::from_secs(60));
+        let (sender, _provider) = connection().await;
+
+        pool.checkin("a", sender);
+        assert!(pool.checkout("b").await.is_none());
+        let Some(Sender::Http1(sender)) = pool.checkout("a").await else {
+            panic!("idle connection was not reused");
+        };
+        assert!(pool.checkout("a").await.is_none());
+
+        pool.checkin("a", sender);
+        assert!(pool.checkout("a").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn keeps_at_most_max_idle_connections() {
+        let pool = ConnectionPool::new(1, Duration::from_secs(60));
+        let (first, _first_provider) = connection().await;
+        let (second, _second_provider) = connection().await;
+        pool.checkin("a", first);
+        pool.checkin("b", second);
+        assert!(pool.checkout("a").await.is_some());
+        assert!(pool.checkout("b").await.is_none());
+
+        let pool = ConnectionPool::new(0, Duration::from_secs(60));
+        let (sender, _provider) = connection().await;
+        pool.checkin("a", sender);
+        assert!(pool.checkout("a").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn drops_expired_and_closed_connections() {
+        let pool = ConnectionPool::new(4, Duration::from_millis(50));
+        let (sender, _provider) = connection().await;
+        pool.checkin("a", sender);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(pool.checkout("a").await.is_none());
+
+        let pool = ConnectionPool::new(4, Duration::from_secs(60));
+        let (sender, provider) = connection().await;
+        pool.checkin("a", sender);
+        drop(provider);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(pool.checkout("a").await.is_none());
+    }
+}
@@ -0,0 +1,225 @@
+use crate::rpc_utils::{RpcCall, RpcRequest};
+use crate::ticket_ledger::channel_key;
+use parking_lot::Mutex;
+use std::collections::{HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// window the free tier rate limit is measured over
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// host-side rules for requests served without a ticket
+///
+/// a new user cannot pay before their wallet has synced the Railgun contract, so configured
+/// methods (e.g. `eth_chainId`, `eth_getLogs`, `eth_call`) are let through unpaid, optionally
+/// only when they target configured contracts and up to a number of calls per minute. Requests
+/// reach the host through its onion service, so the rate limit is shared by all users
+#[derive(Clone)]
+pub struct FreeTierPolicy {
+    methods: Arc<HashSet<String>>,
+    // lowercase contract addresses free calls must target, empty allows any target
+    contracts: Arc<HashSet<String>>,
+    // free calls allowed per minute, `None` is unlimited
+    calls_per_minute: Option<u32>,
+    // when the free calls of the last minute were served
+    served: Arc<Mutex<VecDeque<Instant>>>,
+}
+
+impl FreeTierPolicy {
+    /// create a policy, `None` when no method is free
+    pub fn new(
+        methods: &[String],
+        contracts: &[String],
+        calls_per_minute: Option<u32>,
+    ) -> Option<Self> {
+        if methods.is_empty() {
+            return None;
+        }
+
+        info!(
+            "free tier enabled for {:?} (contracts: {:?}, calls per minute: {:?})",
+            methods, contracts, calls_per_minute
+        );
+        Some(Self {
+            methods: Arc::new(methods.iter().cloned().collect()),
+            contracts: Arc::new(contracts.iter().map(|c| channel_key(c)).collect()),
+            calls_per_minute,
+            served: Arc::new(Mutex::new(VecDeque::new())),
+        })
+    }
+
+    /// whether every call of a request is free
+    pub fn covers(&self, request: &RpcRequest) -> bool {
+        let calls = request.calls();
+        !calls.is_empty() && calls.iter().all(|call| self.covers_call(call))
+    }
+
+    /// take the request's calls from the rate limit, false if there is not enough room left
+    pub fn try_acquire(&self, request: &RpcRequest) -> bool {
+        let Some(limit) = self.calls_per_minute else {
+            return true;
+        };
+
+        let now = Instant::now();
+        let mut served = self.served.lock();
+        while served
+            .front()
+            .is_some_and(|at| now.duration_since(*at) >= RATE_WINDOW)
+        {
+            served.pop_front();
+        }
+
+        let calls = request.calls().len();
+        if served.len() + calls > limit as usize {
+            return false;
+        }
+        served.extend(std::iter::repeat_n(now, calls));
+        true
+    }
+
+    fn covers_call(&self, call: &RpcCall) -> bool {
+        let Some(method) = &call.method else {
+            return false;
+        };
+        if !self.methods.contains(method) {
+            return false;
+        }
+        if self.contracts.is_empty() {
+            return true;
+        }
+
+        match call_targets(call) {
+            Some(targets) => {
+                !targets.is_empty()
+                    && targets
+                        .iter()
+                        .all(|target| self.contracts.contains(&channel_key(target)))
+            }
+            // calls without a target (eth_chainId, eth_blockNumber, ...) are not restricted
+            None => true,
+        }
+    }
+}
+
+/// contract addresses a call targets, `None` if the call does not target any
+///
+/// transaction-like objects target `to`, log filters their `address` (one or many), and calls
+/// like `eth_getCode` take the address as first parameter. A filter without address targets
+/// nothing in particular and returns an empty list
+fn call_targets(call: &RpcCall) -> Option<Vec<&str>> {
+    let first = call.params.as_ref()?.as_array()?.first()?;
+    match first {
+        serde_json::Value::Object(object) => {
+            let target = object.get("to").or_else(|| object.get("address"));
+            Some(match target {
+                Some(serde_json::Value::String(address)) => vec![address.as_str()],
+                Some(serde_json::Value::Array(addresses)) => {
+                    addresses.iter().filter_map(|a| a.as_str()).collect()
+                }
+                _ => Vec::new(),
+            })
+        }
+        serde_json::Value::String(value) if is_address(value) => Some(vec![value.as_str()]),
+        _ => None,
+    }
+}
+
+fn is_address(value: &str) -> bool {
+    value.len() == 42
+        && value.starts_with("0x")
+        && value[2..].bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc_utils::parse_request;
+
+    const CONTRACT: &str = "0x00000000000000000000000000000000000000aa";
+
+    fn request(body: &str) -> RpcRequest {
+        parse_request(body.as_bytes()).unwrap()
+    }
+
+    fn free_tier(contracts: &[&str], calls_per_minute: Option<u32>) -> FreeTierPolicy {
+        let methods = ["eth_chainId", "eth_call", "eth_getLogs", "eth_getCode"].map(String::from);
+        let contracts: Vec<String> = contracts.iter().map(|c| c.to_string()).collect();
+        FreeTierPolicy::new(&methods, &contracts, calls_per_minute).unwrap()
+    }
+
+    #[test]
+    fn no_free_methods_disables_the_free_tier() {
+        assert!(FreeTierPolicy::new(&[], &[CONTRACT.to_string()], Some(10)).is_none());
+    }
+
+    #[test]
+    fn covers_free_methods_on_configured_contracts() {
+        let policy = free_tier(&[CONTRACT], None);
+
+        assert!(policy.covers(&request(
+            r#"{"jsonrpc":"2.0","id":1,"method":"eth_chainId","params":[]}"#
+        )));
+        // addresses match case-insensitively
+        assert!(policy.covers(&request(
+            r#"{"jsonrpc":"2.0","id":1,"method":"eth_call","params":[{"to":"0x00000000000000000000000000000000000000AA"},"latest"]}"#
+        )));
+        assert!(policy.covers(&request(&format!(
+            r#"{{"jsonrpc":"2.0","id":1,"method":"eth_getLogs","params":[{{"address":["{}"]}}]}}"#,
+            CONTRACT
+        ))));
+        assert!(policy.covers(&request(&format!(
+            r#"{{"jsonrpc":"2.0","id":1,"method":"eth_getCode","params":["{}","latest"]}}"#,
+            CONTRACT
+        ))));
+
+        // other contracts, filters over every contract and paid methods
+        assert!(!policy.covers(&request(
+            r#"{"jsonrpc":"2.0","id":1,"method":"eth_call","params":[{"to":"0x00000000000000000000000000000000000000bb"}]}"#
+        )));
+        assert!(!policy.covers(&request(
+            r#"{"jsonrpc":"2.0","id":1,"method":"eth_getLogs","params":[{"fromBlock":"0x1"}]}"#
+        )));
+        assert!(!policy.covers(&request(
+            r#"{"jsonrpc":"2.0","id":1,"method":"eth_sendRawTransaction","params":["0x00"]}"#
+        )));
+
+        // a batch is free only if every call is
+        assert!(policy.covers(&request(
+            r#"[{"jsonrpc":"2.0","id":1,"method":"eth_chainId"},{"jsonrpc":"2.0","id":2,"method":"eth_chainId"}]"#
+        )));
+        assert!(!policy.covers(&request(
+            r#"[{"jsonrpc":"2.0","id":1,"method":"eth_chainId"},{"jsonrpc":"2.0","id":2,"method":"eth_blockNumber"}]"#
+        )));
+        assert!(!policy.covers(&request("[]")));
+
+        // without configured contracts any target is free
+        assert!(free_tier(&[], None).covers(&request(
+            r#"{"jsonrpc":"2.0","id":1,"method":"eth_getLogs","params":[{"fromBlock":"0x1"}]}"#
+        )));
+    }
+
+    #[test]
+    fn rate_limit_counts_calls() {
+        let policy = free_tier(&[], Some(3));
+        let single = request(r#"{"jsonrpc":"2.0","id":1,"method":"eth_chainId"}"#);
+        let batch = request(
+            r#"[{"jsonrpc":"2.0","id":1,"method":"eth_chainId"},{"jsonrpc":"2.0","id":2,"method":"eth_chainId"}]"#,
+        );
+
+        assert!(policy.try_acquire(&batch));
+        // a batch that does not fit takes nothing
+        assert!(!policy.try_acquire(&batch));
+        assert!(policy.try_acquire(&single));
+        assert!(!policy.try_acquire(&single));
+
+        // calls leave the window after a minute
+        let earlier = Instant::now() - RATE_WINDOW;
+        policy.served.lock().iter_mut().for_each(|at| *at = earlier);
+        assert!(policy.try_acquire(&batch));
+
+        // the limit is shared by clones
+        assert!(!policy.clone().try_acquire(&batch));
+        assert!(free_tier(&[], None).try_acquire(&batch));
+    }
+}
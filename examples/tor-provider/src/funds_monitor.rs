@@ -0,0 +1,152 @@
+use crate::payment_backend::PaymentBackend;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tracing::{debug, error, info, warn};
+
+/// a ticket the channel cannot cover, it could never be claimed
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("ticket amount {amount} wei exceeds the {available} wei available in the channel")]
+pub struct InsufficientFunds {
+    pub amount: u128,
+    pub available: u128,
+}
+
+#[derive(Debug, Default)]
+struct FundsState {
+    // available funds of the channel at the last successful poll
+    available: Option<u128>,
+    checked_at: Option<DateTime<Utc>>,
+    // cumulative amount of the latest ticket, not yet withdrawn
+    outstanding: u128,
+    // how many warning thresholds the remaining funds are below
+    warned: usize,
+}
+
+/// polls the channel's available funds and tracks them against the cumulative amount of the
+/// latest ticket
+///
+/// until the first poll succeeds nothing is refused, the backend is the final judge anyway
+#[derive(Clone)]
+pub struct FundsMonitor {
+    payment_backend: Arc<dyn PaymentBackend>,
+    // remaining funds (wei) to warn at, highest first
+    warn_thresholds: Arc<Vec<u128>>,
+    state: Arc<Mutex<FundsState>>,
+}
+
+impl FundsMonitor {
+    /// create a monitor warning when the remaining funds drop below any of `warn_thresholds`
+    pub fn new(payment_backend: Arc<dyn PaymentBackend>, mut warn_thresholds: Vec<u128>) -> Self {
+        warn_thresholds.sort_unstable_by(|a, b| b.cmp(a));
+        warn_thresholds.dedup();
+        Self {
+            payment_backend,
+            warn_thresholds: Arc::new(warn_thresholds),
+            state: Arc::new(Mutex::new(FundsState::default())),
+        }
+    }
+
+    /// poll the available funds in the background every `interval`
+    pub fn spawn(&self, interval: Duration) -> JoinHandle<()> {
+        info!(
+            "starting funds monitor (interval: {:?}, warn thresholds: {:?} wei)",
+            interval, self.warn_thresholds
+        );
+        let monitor = self.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = monitor.refresh().await {
+                    error!("failed to read available funds: {}", e);
+                }
+                sleep(interval).await;
+            }
+        })
+    }
+
+    /// read the available funds from the payment backend
+    pub async fn refresh(&self) -> Result<u128> {
+        let funds = self.payment_backend.get_available_funds().await?;
+        let available: u128 = funds
+            .available_funds
+            .parse()
+            .with_context(|| format!("invalid available funds '{}'", funds.available_funds))?;
+        debug!(
+            "channel funds: funded={} withdrawn={} available={}",
+            funds.total_funded, funds.total_withdrawn, available
+        );
+
+        let mut state = self.state.lock();
+        state.available = Some(available);
+        state.checked_at = Some(Utc::now());
+        self.warn_if_low(&mut state);
+        Ok(available)
+    }
+
+    /// available funds at the last poll, `None` before the first successful poll
+    pub fn available(&self) -> Option<u128> {
+        self.state.lock().available
+    }
+
+    /// when the funds were last read
+    pub fn checked_at(&self) -> Option<DateTime<Utc>> {
+        self.state.lock().checked_at
+    }
+
+    /// available funds not yet promised by the latest ticket
+    pub fn remaining(&self) -> Option<u128> {
+        let state = self.state.lock();
+        state
+            .available
+            .map(|available| available.saturating_sub(state.outstanding))
+    }
+
+    /// check that the channel covers a ticket with this cumulative amount
+    pub fn check_amount(&self, amount: u128) -> Result<(), InsufficientFunds> {
+        match self.available() {
+            Some(available) if amount > available => Err(InsufficientFunds { amount, available }),
+            _ => Ok(()),
+        }
+    }
+
+    /// check that the channel covers the next ticket when it adds `spend` to the latest one
+    pub fn check_spend(&self, spend: u128) -> Result<(), InsufficientFunds> {
+        let outstanding = self.state.lock().outstanding;
+        self.check_amount(outstanding.saturating_add(spend))
+    }
+
+    /// a ticket with this cumulative amount was signed (user) or accepted (host)
+    pub fn record_ticket_amount(&self, amount: u128) {
+        let mut state = self.state.lock();
+        state.outstanding = amount;
+        self.warn_if_low(&mut state);
+    }
+
+    fn warn_if_low(&self, state: &mut FundsState) {
+        let Some(available) = state.available else {
+            return;
+        };
+        let remaining = available.saturating_sub(state.outstanding);
+        let crossed = self
+            .warn_thresholds
+            .iter()
+            .take_while(|threshold| remaining < **threshold)
+            .count();
+
+        // warn once per threshold, again only after a top up
+        if crossed > state.warned {
+            warn!(
+                "channel funds are running low: {} wei remaining ({} available, {} outstanding), below the {} wei threshold",
+                remaining,
+                available,
+                state.outstanding,
+                self.warn_thresholds[crossed - 1]
+            );
+        }
+        state.warned = crossed;
+    }
+}
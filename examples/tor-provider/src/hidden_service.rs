@@ -1,14 +1,17 @@
 use crate::tor::TorClientManager;
 use anyhow::{Context, Result};
+use futures_util::future::Either;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
+use tor_hscrypto::pk::{HsIdKey, HsIdKeypair};
 use tor_hsrproxy::OnionServiceReverseProxy;
 use tor_hsrproxy::config::{
     Encapsulation, ProxyAction, ProxyConfigBuilder, ProxyPattern, ProxyRule, TargetAddr,
 };
 use tor_hsservice::config::OnionServiceConfigBuilder;
-use tor_hsservice::{HsNickname, RunningOnionService};
+use tor_hsservice::{HsIdKeypairSpecifier, HsNickname, RunningOnionService};
+use tor_keymgr::{ArtiNativeKeystore, KeyMgrBuilder};
 use tracing::{error, info};
 
 /// manages an Arti-based hidden service
@@ -40,19 +43,20 @@ impl HiddenServiceManager {
     }
 
     /// start the hidden service using Arti
+    ///
+    /// `identity` is stored in Arti's keystore and becomes the service's identity key, it must
+    /// only be given while the keystore holds none yet (see `stored_identity`). Without it the
+    /// keystore's identity is used, or a new one is generated
     pub async fn start(
         &mut self,
         tor_manager: Arc<TorClientManager>,
         local_port: u16,
         onion_port: u16,
+        identity: Option<HsIdKeypair>,
     ) -> Result<()> {
         info!("starting Arti-based hidden service...");
 
-        // create a unique nickname for this hidden service
-        let nickname: HsNickname = "tor_provider_hs"
-            .to_string()
-            .try_into()
-            .context("Invalid hidden service nickname")?;
+        let nickname = nickname()?;
 
         // configure the hidden service
         let hs_config = OnionServiceConfigBuilder::default()
@@ -61,15 +65,39 @@ impl HiddenServiceManager {
             .context("Failed to build hidden service config")?;
 
         // launch the onion service (this is NOT async, returns immediately)
-        let (onion_service, rend_requests) = tor_manager
-            .client()
-            .launch_onion_service(hs_config)
-            .context("Failed to launch onion service")?;
+        let hs_id = identity
+            .as_ref()
+            .map(|identity| HsIdKey::from(identity).id());
+        let (onion_service, rend_requests) = match identity {
+            Some(identity) => {
+                let (onion_service, rend_requests) = tor_manager
+                    .client()
+                    .launch_onion_service_with_hsid(hs_config, identity)
+                    .context("Failed to launch onion service")?;
+                (onion_service, Either::Left(rend_requests))
+            }
+            None => {
+                let (onion_service, rend_requests) = tor_manager
+                    .client()
+                    .launch_onion_service(hs_config)
+                    .context("Failed to launch onion service")?;
+                (onion_service, Either::Right(rend_requests))
+            }
+        };
 
         // get the onion address
         let onion_name = onion_service
             .onion_name()
             .context("Failed to get onion name")?;
+        if let Some(hs_id) = hs_id
+            && onion_name != hs_id
+        {
+            anyhow::bail!(
+                "the hidden service runs as {} instead of the given identity {}",
+                onion_name,
+                hs_id
+            );
+        }
         let onion_address = format!("{}:{}", onion_name, onion_port);
 
         info!("hidden service established at: {}", onion_address);
@@ -156,6 +184,30 @@ impl HiddenServiceManager {
     }
 }
 
+/// the identity key Arti's keystore holds for the hidden service, if it was started before
+pub fn stored_identity(tor_manager: &TorClientManager) -> Result<Option<HsIdKeypair>> {
+    let keystore_dir = tor_manager.state_dir().join("keystore");
+    let keystore = ArtiNativeKeystore::from_path_and_mistrust(
+        &keystore_dir,
+        tor_manager.config().fs_mistrust(),
+    )
+    .with_context(|| format!("failed to open the Tor keystore {:?}", keystore_dir))?;
+    let keymgr = KeyMgrBuilder::default()
+        .primary_store(Box::new(keystore))
+        .build()
+        .context("failed to open the Tor keystore")?;
+    keymgr
+        .get::<HsIdKeypair>(&HsIdKeypairSpecifier::new(nickname()?))
+        .context("failed to read the hidden service identity from the Tor keystore")
+}
+
+fn nickname() -> Result<HsNickname> {
+    "tor_provider_hs"
+        .to_string()
+        .try_into()
+        .context("Invalid hidden service nickname")
+}
+
 impl Drop for HiddenServiceManager {
     fn drop(&mut self) {
         if let Some(handle) = self.proxy_handle.take() {
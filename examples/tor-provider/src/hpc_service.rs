@@ -14,6 +14,51 @@ pub struct PaymentTicket {
     pub signature: String,
 }
 
+impl PaymentTicket {
+    /// parse the ticket amount (wei, decimal string)
+    pub fn amount_value(&self) -> Result<u128> {
+        self.amount
+            .parse()
+            .map_err(|e| anyhow!("invalid ticket amount '{}': {}", self.amount, e))
+    }
+
+    /// parse the ticket nonce (decimal string)
+    pub fn nonce_value(&self) -> Result<u128> {
+        self.nonce
+            .parse()
+            .map_err(|e| anyhow!("invalid ticket nonce '{}': {}", self.nonce, e))
+    }
+}
+
+/// Probabilistic (lottery) payment ticket, the wrapped ticket's amount is the face value that
+/// is paid out if the ticket wins
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LotteryTicket {
+    #[serde(flatten)]
+    pub ticket: PaymentTicket,
+    /// chance of winning in parts per million
+    pub win_probability_ppm: u32,
+}
+
+/// Ticket attached to a request, either a cumulative ticket or a lottery ticket
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum AnyTicket {
+    Lottery(LotteryTicket),
+    Cumulative(PaymentTicket),
+}
+
+impl AnyTicket {
+    /// the signed ticket, for lottery tickets its amount is the face value
+    pub fn payment_ticket(&self) -> &PaymentTicket {
+        match self {
+            Self::Lottery(lottery) => &lottery.ticket,
+            Self::Cumulative(ticket) => ticket,
+        }
+    }
+}
+
 /// Wallet creation response
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
@@ -1,11 +1,30 @@
+pub mod body_limit;
+pub mod channel_registry;
+pub mod claim_scheduler;
 pub mod config;
+pub mod connection_pool;
+pub mod free_tier;
+pub mod funds_monitor;
 pub mod hidden_service;
 pub mod hpc_service;
+pub mod lottery;
+pub mod mock_hpc;
 pub mod nimbus;
+pub mod payment_backend;
 pub mod payment_middleware;
+pub mod payment_receipt;
+pub mod payment_requirements;
+pub mod postpaid;
+pub mod pricing;
+pub mod provider_registry;
 pub mod proxy_local_client;
 pub mod proxy_tor_client;
 pub mod rpc_utils;
 pub mod server_host;
 pub mod server_user;
+pub mod spend_budget;
+pub mod ticket_ledger;
+pub mod ticket_verifier;
 pub mod tor;
+pub mod websocket;
+pub mod x402;
@@ -0,0 +1,160 @@
+use crate::hpc_service::LotteryTicket;
+use crate::ticket_verifier::{keccak256, normalize_signature};
+use anyhow::{Context, Result, anyhow};
+use std::path::Path;
+use tracing::info;
+
+/// probabilities are expressed in parts per million
+pub const PPM: u128 = 1_000_000;
+
+/// host-side rules for probabilistic (lottery) tickets
+///
+/// a lottery ticket pays its full face value if it wins and nothing otherwise, so a request is
+/// paid for when `face value * win probability` covers its price. Winners are drawn from
+/// keccak256(host secret || normalized ticket signature): the user cannot predict the outcome
+/// without the secret, and the secret is kept across restarts so it cannot be tuned per ticket
+/// and a ticket is drawn the same way however often it is presented
+///
+/// note: HiddenPaymentChannels.sol does not check lottery outcomes yet, until it does any
+/// lottery ticket is claimable at face value and users have to trust the host to only claim
+/// winners
+#[derive(Clone)]
+pub struct LotteryPolicy {
+    win_probability_ppm: u32,
+    secret: [u8; 32],
+}
+
+impl LotteryPolicy {
+    /// create a policy drawing with the secret stored at `path`, generated if missing
+    pub fn load_or_generate(win_probability_ppm: u32, path: &Path) -> Result<Self> {
+        Self::new(win_probability_ppm, load_or_generate_secret(path)?)
+    }
+
+    /// create a policy drawing with `secret`
+    pub fn new(win_probability_ppm: u32, secret: [u8; 32]) -> Result<Self> {
+        if win_probability_ppm == 0 || win_probability_ppm as u128 > PPM {
+            return Err(anyhow!(
+                "win probability must be between 1 and {} ppm, got {}",
+                PPM,
+                win_probability_ppm
+            ));
+        }
+
+        info!(
+            "lottery tickets enabled with a win probability of {} ppm",
+            win_probability_ppm
+        );
+        Ok(Self {
+            win_probability_ppm,
+            secret,
+        })
+    }
+
+    /// the win probability (ppm) tickets must be issued with
+    pub fn win_probability_ppm(&self) -> u32 {
+        self.win_probability_ppm
+    }
+
+    /// expected value (wei) of a ticket with the given face value
+    pub fn expected_value(&self, face_value: u128) -> u128 {
+        face_value.saturating_mul(self.win_probability_ppm as u128) / PPM
+    }
+
+    /// smallest face value (wei) whose expected value covers `price`
+    pub fn min_face_value(&self, price: u128) -> u128 {
+        price
+            .saturating_mul(PPM)
+            .div_ceil(self.win_probability_ppm as u128)
+    }
+
+    /// decide whether a lottery ticket wins, a signature that cannot be decoded never wins
+    ///
+    /// the draw is over the normalized signature, so writing a losing ticket's signature another
+    /// way does not draw it again
+    pub fn is_winner(&self, ticket: &LotteryTicket) -> bool {
+        let Ok(signature) = normalize_signature(&ticket.ticket.signature) else {
+            return false;
+        };
+        let mut preimage = self.secret.to_vec();
+        preimage.extend_from_slice(&signature);
+        let hash = keccak256(&preimage);
+
+        let mut draw = [0u8; 16];
+        draw.copy_from_slice(&hash[..16]);
+        (u128::from_be_bytes(draw) % PPM) < self.win_probability_ppm as u128
+    }
+}
+
+/// read the hex lottery secret at `path`, or generate one and store it there
+fn load_or_generate_secret(path: &Path) -> Result<[u8; 32]> {
+    if path.exists() {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read lottery secret {:?}", path))?;
+        return hex::decode(contents.trim().trim_start_matches("0x"))
+            .with_context(|| format!("lottery secret {:?} is not hex", path))?
+            .try_into()
+            .map_err(|_| anyhow!("lottery secret {:?} must be 32 bytes", path));
+    }
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create lottery secret directory {:?}", parent))?;
+    }
+    let secret: [u8; 32] = rand::random();
+    std::fs::write(path, hex::encode(secret))
+        .with_context(|| format!("failed to write lottery secret {:?}", path))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    }
+    info!("generated lottery secret at {:?}", path);
+    Ok(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payment_backend::TicketIssuer;
+    use k256::ecdsa::SigningKey;
+
+    #[test]
+    fn signature_encodings_draw_the_same() {
+        let policy = LotteryPolicy::new(500_000, [3u8; 32]).unwrap();
+        let issuer = TicketIssuer::new(
+            SigningKey::from_slice(&[7u8; 32]).unwrap(),
+            "0zk1host",
+            "0x00000000000000000000000000000000000000aa",
+            100,
+        );
+        let lottery = |signature: String| {
+            let mut ticket = issuer.issue().unwrap();
+            ticket.signature = signature;
+            LotteryTicket {
+                ticket,
+                win_probability_ppm: 500_000,
+            }
+        };
+
+        let mut winners = 0;
+        for _ in 0..32 {
+            let signature = issuer.issue().unwrap().signature;
+            let winner = policy.is_winner(&lottery(signature.clone()));
+            winners += usize::from(winner);
+
+            let bytes = hex::decode(signature.trim_start_matches("0x")).unwrap();
+            let mut raw_v = bytes.clone();
+            raw_v[64] -= 27;
+            for variant in [
+                hex::encode(&bytes),
+                format!("0x{}", hex::encode_upper(&bytes)),
+                format!("0x{}", hex::encode(&raw_v)),
+            ] {
+                assert_eq!(policy.is_winner(&lottery(variant)), winner);
+            }
+        }
+        // both outcomes were drawn
+        assert!(winners > 0 && winners < 32);
+        assert!(!policy.is_winner(&lottery("0x1234".to_string())));
+    }
+}
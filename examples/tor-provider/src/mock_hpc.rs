@@ -0,0 +1,134 @@
+use crate::hpc_service::{
+    PaymentTicket, TicketClaimResponse, TicketGenerateResponse, TicketValidateResponse,
+};
+use crate::payment_backend::{MemoryBackend, PaymentBackend};
+use crate::ticket_verifier::format_address;
+use axum::{
+    Json, Router,
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tower_http::trace::TraceLayer;
+use tracing::{info, warn};
+
+/// request body of the validate and claim endpoints
+#[derive(Deserialize)]
+struct TicketRequest {
+    ticket: PaymentTicket,
+}
+
+/// create a router that mimics the HiddenPaymentChannels service on top of a memory backend,
+/// tickets are signed with real secp256k1 keys and claims draw from a simulated contract balance
+pub fn create_router(backend: Arc<MemoryBackend>) -> Router {
+    let api = Router::new()
+        .route("/ticket/generate", post(generate_handler))
+        .route("/ticket/validate", post(validate_handler))
+        .route("/ticket/claim", post(claim_handler))
+        .route(
+            "/hidden-payments/available-funds",
+            get(available_funds_handler),
+        );
+
+    Router::new()
+        .nest("/api", api)
+        .layer(TraceLayer::new_for_http())
+        .with_state(backend)
+}
+
+/// serve the mock service on `listener` in the background, returns the task serving it
+pub fn spawn(listener: TcpListener, backend: Arc<MemoryBackend>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, create_router(backend)).await {
+            warn!("mock HiddenPaymentChannels service stopped: {}", e);
+        }
+    })
+}
+
+/// log the deterministic addresses the host needs for native verification
+pub fn log_identity(backend: &MemoryBackend) {
+    let issuer = backend.issuer();
+    info!(
+        "mock HiddenPaymentChannels signer: {}, contract: {}, railgun address: {}",
+        format_address(&issuer.signer_address()),
+        issuer.contract_address(),
+        issuer.to_railgun_address()
+    );
+}
+
+/// POST /api/ticket/generate
+async fn generate_handler(State(backend): State<Arc<MemoryBackend>>) -> Response {
+    match backend.generate_ticket().await {
+        Ok(ticket) => {
+            info!(
+                "generated ticket with nonce {} and amount {}",
+                ticket.nonce, ticket.amount
+            );
+            Json(TicketGenerateResponse { ticket }).into_response()
+        }
+        Err(e) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to generate ticket",
+            e,
+        ),
+    }
+}
+
+/// POST /api/ticket/validate
+async fn validate_handler(
+    State(backend): State<Arc<MemoryBackend>>,
+    Json(request): Json<TicketRequest>,
+) -> Response {
+    match backend.validate_ticket(&request.ticket).await {
+        Ok(valid) => {
+            info!(
+                "validated ticket with nonce {}: {}",
+                request.ticket.nonce, valid
+            );
+            Json(TicketValidateResponse { valid }).into_response()
+        }
+        Err(e) => error_response(StatusCode::BAD_REQUEST, "Failed to validate ticket", e),
+    }
+}
+
+/// POST /api/ticket/claim
+async fn claim_handler(
+    State(backend): State<Arc<MemoryBackend>>,
+    Json(request): Json<TicketRequest>,
+) -> Response {
+    match backend.claim_ticket(&request.ticket).await {
+        Ok(result) => Json(TicketClaimResponse { result }).into_response(),
+        Err(e) => error_response(StatusCode::BAD_REQUEST, "Failed to claim ticket", e),
+    }
+}
+
+/// GET /api/hidden-payments/available-funds
+async fn available_funds_handler(State(backend): State<Arc<MemoryBackend>>) -> Response {
+    match backend.get_available_funds().await {
+        Ok(funds) => Json(funds).into_response(),
+        Err(e) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to get balance",
+            e,
+        ),
+    }
+}
+
+/// error body in the same shape as the HiddenPaymentChannels service
+fn error_response(status: StatusCode, error: &str, details: anyhow::Error) -> Response {
+    warn!("{}: {}", error, details);
+    (
+        status,
+        Json(json!({
+            "error": error,
+            "details": details.to_string(),
+        })),
+    )
+        .into_response()
+}
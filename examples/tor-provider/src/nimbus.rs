@@ -26,8 +26,8 @@ impl NimbusConfig {
     /// get the port from the URL
     pub fn rpc_port(&self) -> u16 {
         self.rpc_url
-            .split(':')
-            .last()
+            .rsplit(':')
+            .next()
             .and_then(|s| s.parse().ok())
             .unwrap_or(8546)
     }
@@ -107,15 +107,14 @@ impl NimbusManager {
         match result {
             Ok(resp) if resp.status().is_success() => {
                 // Parse response to check sync status
-                if let Ok(body) = resp.text().await {
-                    if let Ok(json) = serde_json::from_str::<Value>(&body) {
-                        if let Some(result) = json.get("result") {
-                            if result.is_boolean() && result.as_bool() == Some(false) {
-                                info!("Nimbus is fully synced");
-                            } else {
-                                info!("Nimbus is syncing: {:?}", result);
-                            }
-                        }
+                if let Ok(body) = resp.text().await
+                    && let Ok(json) = serde_json::from_str::<Value>(&body)
+                    && let Some(result) = json.get("result")
+                {
+                    if result.is_boolean() && result.as_bool() == Some(false) {
+                        info!("Nimbus is fully synced");
+                    } else {
+                        info!("Nimbus is syncing: {:?}", result);
                     }
                 }
                 Ok(true)
@@ -0,0 +1,881 @@
+use crate::config::HpcConfig;
+use crate::hpc_service::{AvailableFundsResponse, HpcClient, PaymentTicket};
+use crate::ticket_verifier::{
+    Address, TicketVerifier, address_from_verifying_key, format_address, keccak256,
+    sign_message_hash, ticket_message_hash,
+};
+use anyhow::{Context, Result, anyhow};
+use async_trait::async_trait;
+use k256::ecdsa::SigningKey;
+use parking_lot::Mutex;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{debug, info, warn};
+
+/// railgun address tickets of the memory backend pay to
+pub const MEMORY_RAILGUN_ADDRESS: &str = "0zk1memorybackendhost";
+
+/// which payment backend to use
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PaymentBackendKind {
+    /// the HiddenPaymentChannels Node service over HTTP
+    Http,
+    /// tickets signed and verified in process, claims still go through the service
+    Native,
+    /// deterministic keys and a simulated contract, for development and tests
+    Memory,
+}
+
+/// the HiddenPaymentChannels operations the user and host proxies depend on
+#[async_trait]
+pub trait PaymentBackend: Send + Sync {
+    /// generate a payment ticket (user)
+    async fn generate_ticket(&self) -> Result<PaymentTicket>;
+
+    /// whether tickets can carry postpaid debt, see `generate_ticket_with_debt` (user)
+    fn carries_debt(&self) -> bool {
+        false
+    }
+
+    /// generate a payment ticket that also pays `debt` owed for postpaid responses (user)
+    async fn generate_ticket_with_debt(&self, debt: u128) -> Result<PaymentTicket> {
+        if debt > 0 {
+            return Err(anyhow!(
+                "this payment backend cannot add postpaid debt to a ticket"
+            ));
+        }
+        self.generate_ticket().await
+    }
+
+    /// generate a payment ticket paying `debt` like `generate_ticket_with_debt`, with a nonce of
+    /// at least `min_nonce` and an amount of at least `min_amount`, what a provider asked for
+    /// when it refused a ticket (user)
+    ///
+    /// backends that cannot choose nonces and amounts fail when the ticket they generate falls
+    /// short
+    async fn generate_ticket_meeting(
+        &self,
+        debt: u128,
+        min_nonce: u128,
+        min_amount: u128,
+    ) -> Result<PaymentTicket> {
+        let ticket = self.generate_ticket_with_debt(debt).await?;
+        if ticket.nonce_value()? < min_nonce || ticket.amount_value()? < min_amount {
+            return Err(anyhow!(
+                "this payment backend cannot issue a ticket with nonce {} and amount {} (got nonce {} and amount {})",
+                min_nonce,
+                min_amount,
+                ticket.nonce,
+                ticket.amount
+            ));
+        }
+        Ok(ticket)
+    }
+
+    /// generate a ticket that only pays `debt`, without the price of a request (user)
+    async fn generate_settlement_ticket(&self, _debt: u128) -> Result<PaymentTicket> {
+        Err(anyhow!(
+            "this payment backend cannot issue settlement tickets"
+        ))
+    }
+
+    /// continue after the latest ticket signed before a restart, with `nonce` and cumulative
+    /// `amount`, backends that keep no counters of their own ignore it (user)
+    fn resume_after(&self, _nonce: u128, _amount: u128) {}
+
+    /// give back the last generated ticket, it was refused before being sent so the next ticket
+    /// reuses its nonce and does not add its amount, backends that keep no counters of their
+    /// own ignore it (user)
+    fn release_ticket(&self, _ticket: &PaymentTicket) {}
+
+    /// validate a payment ticket, Ok(false) if the ticket is not valid (host)
+    async fn validate_ticket(&self, ticket: &PaymentTicket) -> Result<bool>;
+
+    /// claim a payment ticket onchain (host)
+    async fn claim_ticket(&self, ticket: &PaymentTicket) -> Result<bool>;
+
+    /// get available funds for hidden payments
+    async fn get_available_funds(&self) -> Result<AvailableFundsResponse>;
+}
+
+#[async_trait]
+impl PaymentBackend for HpcClient {
+    async fn generate_ticket(&self) -> Result<PaymentTicket> {
+        HpcClient::generate_ticket(self).await
+    }
+
+    async fn validate_ticket(&self, ticket: &PaymentTicket) -> Result<bool> {
+        HpcClient::validate_ticket(self, ticket).await
+    }
+
+    async fn claim_ticket(&self, ticket: &PaymentTicket) -> Result<bool> {
+        HpcClient::claim_ticket(self, ticket).await
+    }
+
+    async fn get_available_funds(&self) -> Result<AvailableFundsResponse> {
+        HpcClient::get_available_funds(self).await
+    }
+}
+
+/// create the payment backend selected in the configuration
+pub fn create_payment_backend(config: &HpcConfig) -> Result<Arc<dyn PaymentBackend>> {
+    let kind = config.payment_backend();
+    info!("using the {:?} payment backend", kind);
+
+    Ok(match kind {
+        PaymentBackendKind::Http => Arc::new(HpcClient::new(config.hpc_service_url.clone())),
+        PaymentBackendKind::Native => Arc::new(NativeBackend::from_config(config)?),
+        PaymentBackendKind::Memory => Arc::new(MemoryBackend::new(
+            config.memory_backend_funds_wei,
+            config.ticket_price_wei,
+        )),
+    })
+}
+
+/// how far a provider may ask an issuer to catch up in one ticket, in tickets: nonces skipped
+/// ahead, and the amount added on top of the next ticket in ticket prices
+///
+/// tickets go out one at a time per channel, so an honest provider is at most a few tickets
+/// ahead, after a restart or a lost response
+const MAX_CATCH_UP_TICKETS: u128 = 4;
+
+/// signs tickets like the HiddenPaymentChannels service: every ticket gets a fresh nonce and
+/// carries the cumulative amount of all requests since the last claim
+pub struct TicketIssuer {
+    signing_key: SigningKey,
+    to_railgun_address: String,
+    contract_address: String,
+    ticket_price: u128,
+    nonces: Mutex<IssuerNonces>,
+}
+
+#[derive(Debug, Default)]
+struct IssuerNonces {
+    // nonce of the last issued ticket
+    user_nonce: u128,
+    // nonce of the last claimed ticket
+    host_nonce: u128,
+    // postpaid debt paid on top of the price since the last claim
+    extra: u128,
+    // settlement tickets issued since the last claim, they do not add the price
+    unpriced: u128,
+    // the last ticket, with the counters before it to restore when it is given back
+    last_issued: Option<IssuedTicket>,
+}
+
+#[derive(Debug)]
+struct IssuedTicket {
+    signature: String,
+    user_nonce: u128,
+    extra: u128,
+    unpriced: u128,
+}
+
+impl TicketIssuer {
+    /// create an issuer for tickets paying `to_railgun_address` through `contract_address`
+    pub fn new(
+        signing_key: SigningKey,
+        to_railgun_address: impl Into<String>,
+        contract_address: impl Into<String>,
+        ticket_price: u128,
+    ) -> Self {
+        Self {
+            signing_key,
+            to_railgun_address: to_railgun_address.into(),
+            contract_address: contract_address.into(),
+            ticket_price,
+            nonces: Mutex::new(IssuerNonces::default()),
+        }
+    }
+
+    /// the address tickets are signed by
+    pub fn signer_address(&self) -> Address {
+        address_from_verifying_key(self.signing_key.verifying_key())
+    }
+
+    /// the HiddenPaymentChannels contract tickets are issued for
+    pub fn contract_address(&self) -> &str {
+        &self.contract_address
+    }
+
+    /// the railgun address tickets pay to
+    pub fn to_railgun_address(&self) -> &str {
+        &self.to_railgun_address
+    }
+
+    /// sign the next ticket
+    pub fn issue(&self) -> Result<PaymentTicket> {
+        self.issue_with_extra(0)
+    }
+
+    /// sign the next ticket, adding `extra` on top of the price (postpaid debt)
+    pub fn issue_with_extra(&self, extra: u128) -> Result<PaymentTicket> {
+        self.issue_next(true, extra, 0, 0)
+    }
+
+    /// sign the next ticket like `issue_with_extra`, skipping ahead to `min_nonce` and adding
+    /// what is missing to reach `min_amount`, to catch up with a host that refused a ticket
+    ///
+    /// a host asking to skip or add more than `MAX_CATCH_UP_TICKETS` tickets' worth is refused
+    pub fn issue_meeting(
+        &self,
+        extra: u128,
+        min_nonce: u128,
+        min_amount: u128,
+    ) -> Result<PaymentTicket> {
+        self.issue_next(true, extra, min_nonce, min_amount)
+    }
+
+    /// sign the next ticket, adding only `debt` (settles postpaid debt)
+    pub fn issue_settlement(&self, debt: u128) -> Result<PaymentTicket> {
+        self.issue_next(false, debt, 0, 0)
+    }
+
+    fn issue_next(
+        &self,
+        priced: bool,
+        extra: u128,
+        min_nonce: u128,
+        min_amount: u128,
+    ) -> Result<PaymentTicket> {
+        let mut nonces = self.nonces.lock();
+        let next_nonce = nonces
+            .user_nonce
+            .checked_add(1)
+            .ok_or_else(|| anyhow!("ticket nonces are used up"))?;
+        // nonces skipped to reach `min_nonce` do not add the price
+        let skipped = min_nonce.saturating_sub(next_nonce);
+        if skipped > MAX_CATCH_UP_TICKETS {
+            return Err(anyhow!(
+                "provider asked for nonce {} but the next ticket has nonce {}",
+                min_nonce,
+                next_nonce
+            ));
+        }
+        let nonce = next_nonce + skipped;
+        let unpriced = nonces.unpriced + skipped + u128::from(!priced);
+        let mut extra = nonces.extra.saturating_add(extra);
+        // every ticket since the last claim adds the price, settlements only their debt
+        let priced_tickets = (nonce - nonces.host_nonce).saturating_sub(unpriced);
+        let mut amount = self
+            .ticket_price
+            .saturating_mul(priced_tickets)
+            .saturating_add(extra);
+        if amount < min_amount {
+            let missing = min_amount - amount;
+            if missing > self.ticket_price.saturating_mul(MAX_CATCH_UP_TICKETS) {
+                return Err(anyhow!(
+                    "provider asked for {} wei but the next ticket carries {} wei",
+                    min_amount,
+                    amount
+                ));
+            }
+            extra = extra.saturating_add(missing);
+            amount = min_amount;
+        }
+
+        let mut ticket = PaymentTicket {
+            to_railgun_address: self.to_railgun_address.clone(),
+            nonce: nonce.to_string(),
+            amount: amount.to_string(),
+            hidden_payment_channels_contract_address: self.contract_address.clone(),
+            signature: String::new(),
+        };
+        ticket.signature = sign_message_hash(&self.signing_key, &ticket_message_hash(&ticket)?)?;
+
+        nonces.last_issued = Some(IssuedTicket {
+            signature: ticket.signature.clone(),
+            user_nonce: nonces.user_nonce,
+            extra: nonces.extra,
+            unpriced: nonces.unpriced,
+        });
+        nonces.user_nonce = nonce;
+        nonces.extra = extra;
+        nonces.unpriced = unpriced;
+        debug!("issued ticket with nonce {} and amount {}", nonce, amount);
+        Ok(ticket)
+    }
+
+    /// give back the last issued ticket when it is not sent, the counters go back to where
+    /// they were before it
+    pub fn release(&self, ticket: &PaymentTicket) {
+        let mut nonces = self.nonces.lock();
+        let Some(last) = nonces
+            .last_issued
+            .take_if(|last| last.signature == ticket.signature)
+        else {
+            return;
+        };
+        nonces.user_nonce = last.user_nonce;
+        nonces.extra = last.extra;
+        nonces.unpriced = last.unpriced;
+        debug!("released unsent ticket with nonce {}", ticket.nonce);
+    }
+
+    /// continue after a ticket with `nonce` and cumulative `amount` signed before a restart, the
+    /// next ticket gets a higher nonce and adds the price to `amount`
+    pub fn resume_after(&self, nonce: u128, amount: u128) {
+        let mut nonces = self.nonces.lock();
+        if nonce > nonces.user_nonce {
+            // the tickets up to `nonce` are carried as extra amount
+            nonces.unpriced = nonce - nonces.host_nonce.min(nonce);
+            nonces.user_nonce = nonce;
+            nonces.extra = amount;
+            nonces.last_issued = None;
+            info!("resuming tickets after nonce {} ({} wei)", nonce, amount);
+        }
+    }
+
+    /// a ticket with this nonce was claimed, the next ticket starts a new cumulative amount
+    pub fn observe_claimed_nonce(&self, nonce: u128) {
+        let mut nonces = self.nonces.lock();
+        if nonce > nonces.host_nonce {
+            nonces.host_nonce = nonce;
+            nonces.user_nonce = nonces.user_nonce.max(nonce);
+            nonces.extra = 0;
+            nonces.unpriced = 0;
+            nonces.last_issued = None;
+        }
+    }
+}
+
+/// read-only access to the HiddenPaymentChannels contract over Ethereum JSON-RPC
+#[derive(Clone)]
+pub struct ContractReader {
+    rpc_url: String,
+    contract_address: String,
+    client: Client,
+}
+
+impl ContractReader {
+    /// create a reader for the contract at `contract_address`
+    pub fn new(rpc_url: String, contract_address: String) -> Self {
+        Self {
+            rpc_url,
+            contract_address,
+            client: Client::new(),
+        }
+    }
+
+    /// nonce of the last claimed ticket
+    pub async fn last_ticket_nonce(&self) -> Result<u128> {
+        self.call_uint("lastTicketNonce()").await
+    }
+
+    /// funded, withdrawn and available amounts
+    pub async fn available_funds(&self) -> Result<AvailableFundsResponse> {
+        let total_funded = self.call_uint("totalAmountFunded()").await?;
+        let total_withdrawn = self.call_uint("totalAmountWithdrawn()").await?;
+        Ok(funds_response(total_funded, total_withdrawn))
+    }
+
+    /// call a view function without arguments that returns a uint256
+    async fn call_uint(&self, function: &str) -> Result<u128> {
+        let selector = &keccak256(function.as_bytes())[..4];
+        let request = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_call",
+            "params": [
+                { "to": self.contract_address, "data": format!("0x{}", hex::encode(selector)) },
+                "latest"
+            ],
+        });
+
+        let response: serde_json::Value = self
+            .client
+            .post(&self.rpc_url)
+            .json(&request)
+            .send()
+            .await?
+            .json()
+            .await?;
+
+        if let Some(error) = response.get("error") {
+            return Err(anyhow!("eth_call {} failed: {}", function, error));
+        }
+        let result = response
+            .get("result")
+            .and_then(|r| r.as_str())
+            .ok_or_else(|| anyhow!("eth_call {} returned no result", function))?;
+
+        let bytes = hex::decode(result.trim_start_matches("0x"))
+            .with_context(|| format!("eth_call {} returned invalid hex", function))?;
+        if bytes.len() != 32 || bytes[..16].iter().any(|b| *b != 0) {
+            return Err(anyhow!(
+                "eth_call {} returned an unexpected value",
+                function
+            ));
+        }
+
+        let mut value = [0u8; 16];
+        value.copy_from_slice(&bytes[16..]);
+        Ok(u128::from_be_bytes(value))
+    }
+}
+
+/// payment backend that signs and verifies tickets in process
+///
+/// claiming needs a Railgun shield proof which only the Node service can produce, so claims are
+/// still sent to the HiddenPaymentChannels service
+pub struct NativeBackend {
+    issuer: Option<TicketIssuer>,
+    verifier: Option<TicketVerifier>,
+    contract: Option<ContractReader>,
+    hpc_client: HpcClient,
+}
+
+impl NativeBackend {
+    /// create a native backend, tickets can only be generated with a signing key and only be
+    /// validated with a known signer address
+    pub fn new(
+        issuer: Option<TicketIssuer>,
+        verifier: Option<TicketVerifier>,
+        contract: Option<ContractReader>,
+        hpc_client: HpcClient,
+    ) -> Self {
+        Self {
+            issuer,
+            verifier,
+            contract,
+            hpc_client,
+        }
+    }
+
+    /// create a native backend from the HiddenPaymentChannels configuration
+    pub fn from_config(config: &HpcConfig) -> Result<Self> {
+        let issuer = match &config.ticket_signing_key_file {
+            Some(path) => {
+                let (Some(railgun_address), Some(contract_address)) =
+                    (&config.railgun_address, &config.hpc_contract_address)
+                else {
+                    return Err(anyhow!(
+                        "issuing tickets natively requires --railgun-address and --hpc-contract-address"
+                    ));
+                };
+                let issuer = TicketIssuer::new(
+                    load_signing_key(path)?,
+                    railgun_address.clone(),
+                    contract_address.clone(),
+                    config.ticket_price_wei,
+                );
+                info!(
+                    "native ticket issuing enabled for signer {}",
+                    format_address(&issuer.signer_address())
+                );
+                Some(issuer)
+            }
+            None => None,
+        };
+
+        let signer_address = config.ticket_signer_address.clone().or_else(|| {
+            issuer
+                .as_ref()
+                .map(|issuer| format_address(&issuer.signer_address()))
+        });
+        let verifier = match signer_address {
+            Some(signer_address) => {
+                let mut verifier = TicketVerifier::new(&signer_address)?;
+                if let Some(contract_address) = &config.hpc_contract_address {
+                    verifier = verifier.with_expected_contract_address(contract_address)?;
+                }
+                if let Some(railgun_address) = &config.railgun_address {
+                    verifier = verifier.with_expected_to_railgun_address(railgun_address.clone());
+                }
+                info!(
+                    "native ticket verification enabled for signer {}",
+                    signer_address
+                );
+                Some(verifier)
+            }
+            None => {
+                warn!("no ticket signer configured, the native backend cannot validate tickets");
+                None
+            }
+        };
+
+        let contract = match (&config.eth_rpc_url, &config.hpc_contract_address) {
+            (Some(rpc_url), Some(contract_address)) => Some(ContractReader::new(
+                rpc_url.clone(),
+                contract_address.clone(),
+            )),
+            _ => None,
+        };
+
+        Ok(Self::new(
+            issuer,
+            verifier,
+            contract,
+            HpcClient::new(config.hpc_service_url.clone()),
+        ))
+    }
+
+    /// the ticket issuer, caught up with the last claimed ticket so the next ticket restarts
+    /// the cumulative amount once the host has claimed
+    async fn synced_issuer(&self) -> Result<&TicketIssuer> {
+        let issuer = self
+            .issuer
+            .as_ref()
+            .ok_or_else(|| anyhow!("native ticket issuing requires --ticket-signing-key-file"))?;
+
+        if let Some(contract) = &self.contract {
+            match contract.last_ticket_nonce().await {
+                Ok(nonce) => issuer.observe_claimed_nonce(nonce),
+                Err(e) => warn!("failed to read the last claimed ticket nonce: {}", e),
+            }
+        }
+        Ok(issuer)
+    }
+}
+
+#[async_trait]
+impl PaymentBackend for NativeBackend {
+    async fn generate_ticket(&self) -> Result<PaymentTicket> {
+        self.generate_ticket_with_debt(0).await
+    }
+
+    fn carries_debt(&self) -> bool {
+        true
+    }
+
+    async fn generate_ticket_with_debt(&self, debt: u128) -> Result<PaymentTicket> {
+        self.synced_issuer().await?.issue_with_extra(debt)
+    }
+
+    async fn generate_ticket_meeting(
+        &self,
+        debt: u128,
+        min_nonce: u128,
+        min_amount: u128,
+    ) -> Result<PaymentTicket> {
+        self.synced_issuer()
+            .await?
+            .issue_meeting(debt, min_nonce, min_amount)
+    }
+
+    async fn generate_settlement_ticket(&self, debt: u128) -> Result<PaymentTicket> {
+        self.synced_issuer().await?.issue_settlement(debt)
+    }
+
+    fn resume_after(&self, nonce: u128, amount: u128) {
+        if let Some(issuer) = &self.issuer {
+            issuer.resume_after(nonce, amount);
+        }
+    }
+
+    fn release_ticket(&self, ticket: &PaymentTicket) {
+        if let Some(issuer) = &self.issuer {
+            issuer.release(ticket);
+        }
+    }
+
+    async fn validate_ticket(&self, ticket: &PaymentTicket) -> Result<bool> {
+        let verifier = self
+            .verifier
+            .as_ref()
+            .ok_or_else(|| anyhow!("native ticket validation requires --ticket-signer-address"))?;
+        verifier.verify(ticket)
+    }
+
+    async fn claim_ticket(&self, ticket: &PaymentTicket) -> Result<bool> {
+        self.hpc_client.claim_ticket(ticket).await
+    }
+
+    async fn get_available_funds(&self) -> Result<AvailableFundsResponse> {
+        match &self.contract {
+            Some(contract) => contract.available_funds().await,
+            None => self.hpc_client.get_available_funds().await,
+        }
+    }
+}
+
+/// payment backend with deterministic keys and a simulated contract, nothing leaves the process
+///
+/// every memory backend uses the same signer, contract and railgun address, so a user and a
+/// host both running it accept each other's tickets
+pub struct MemoryBackend {
+    issuer: TicketIssuer,
+    verifier: TicketVerifier,
+    contract: Mutex<MemoryContract>,
+}
+
+#[derive(Debug)]
+struct MemoryContract {
+    total_funded: u128,
+    total_withdrawn: u128,
+    last_ticket_nonce: u128,
+}
+
+impl MemoryBackend {
+    /// create a memory backend whose contract holds `funds` wei
+    pub fn new(funds: u128, ticket_price: u128) -> Self {
+        let signing_key = SigningKey::from_slice(&keccak256(b"tor-provider memory backend signer"))
+            .expect("hash is a valid secp256k1 scalar");
+        let issuer = TicketIssuer::new(
+            signing_key,
+            MEMORY_RAILGUN_ADDRESS,
+            memory_contract_address(),
+            ticket_price,
+        );
+        let verifier = TicketVerifier::new(&format_address(&issuer.signer_address()))
+            .expect("formatted address parses")
+            .with_expected_to_railgun_address(MEMORY_RAILGUN_ADDRESS);
+
+        info!(
+            "memory payment backend with signer {} and contract {} ({} wei)",
+            format_address(&issuer.signer_address()),
+            issuer.contract_address(),
+            funds
+        );
+        Self {
+            issuer,
+            verifier,
+            contract: Mutex::new(MemoryContract {
+                total_funded: funds,
+                total_withdrawn: 0,
+                last_ticket_nonce: 0,
+            }),
+        }
+    }
+
+    /// the ticket issuer, exposes the deterministic addresses
+    pub fn issuer(&self) -> &TicketIssuer {
+        &self.issuer
+    }
+
+    /// add funds to the simulated contract
+    pub fn top_up(&self, amount: u128) {
+        let mut contract = self.contract.lock();
+        contract.total_funded = contract.total_funded.saturating_add(amount);
+    }
+}
+
+#[async_trait]
+impl PaymentBackend for MemoryBackend {
+    async fn generate_ticket(&self) -> Result<PaymentTicket> {
+        self.issuer.issue()
+    }
+
+    fn carries_debt(&self) -> bool {
+        true
+    }
+
+    async fn generate_ticket_with_debt(&self, debt: u128) -> Result<PaymentTicket> {
+        self.issuer.issue_with_extra(debt)
+    }
+
+    async fn generate_ticket_meeting(
+        &self,
+        debt: u128,
+        min_nonce: u128,
+        min_amount: u128,
+    ) -> Result<PaymentTicket> {
+        self.issuer.issue_meeting(debt, min_nonce, min_amount)
+    }
+
+    async fn generate_settlement_ticket(&self, debt: u128) -> Result<PaymentTicket> {
+        self.issuer.issue_settlement(debt)
+    }
+
+    fn resume_after(&self, nonce: u128, amount: u128) {
+        self.issuer.resume_after(nonce, amount);
+    }
+
+    fn release_ticket(&self, ticket: &PaymentTicket) {
+        self.issuer.release(ticket);
+    }
+
+    async fn validate_ticket(&self, ticket: &PaymentTicket) -> Result<bool> {
+        if ticket.hidden_payment_channels_contract_address != self.issuer.contract_address() {
+            return Ok(false);
+        }
+        let last_ticket_nonce = self.contract.lock().last_ticket_nonce;
+        Ok(self.verifier.verify(ticket)? && ticket.nonce_value()? > last_ticket_nonce)
+    }
+
+    async fn claim_ticket(&self, ticket: &PaymentTicket) -> Result<bool> {
+        if !self.validate_ticket(ticket).await? {
+            return Err(anyhow!("ticket with nonce {} is not valid", ticket.nonce));
+        }
+
+        let amount = ticket.amount_value()?;
+        let nonce = ticket.nonce_value()?;
+        {
+            let mut contract = self.contract.lock();
+            let available = contract.total_funded - contract.total_withdrawn;
+            if amount > available {
+                return Err(anyhow!(
+                    "insufficient funds: ticket amount {} exceeds available {}",
+                    amount,
+                    available
+                ));
+            }
+            contract.total_withdrawn += amount;
+            contract.last_ticket_nonce = nonce;
+        }
+
+        self.issuer.observe_claimed_nonce(nonce);
+        info!("claimed ticket with nonce {} ({} wei)", nonce, amount);
+        Ok(true)
+    }
+
+    async fn get_available_funds(&self) -> Result<AvailableFundsResponse> {
+        let contract = self.contract.lock();
+        Ok(funds_response(
+            contract.total_funded,
+            contract.total_withdrawn,
+        ))
+    }
+}
+
+/// the contract address used by the memory backend
+pub fn memory_contract_address() -> String {
+    let hash = keccak256(b"tor-provider memory backend contract");
+    let mut address = [0u8; 20];
+    address.copy_from_slice(&hash[12..]);
+    format_address(&address)
+}
+
+/// load a hex secp256k1 private key from a file
+fn load_signing_key(path: &Path) -> Result<SigningKey> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read ticket signing key {:?}", path))?;
+    let bytes = hex::decode(contents.trim().trim_start_matches("0x"))
+        .with_context(|| format!("ticket signing key {:?} is not hex", path))?;
+    SigningKey::from_slice(&bytes).map_err(|e| anyhow!("invalid ticket signing key: {}", e))
+}
+
+fn funds_response(total_funded: u128, total_withdrawn: u128) -> AvailableFundsResponse {
+    AvailableFundsResponse {
+        total_funded: total_funded.to_string(),
+        total_withdrawn: total_withdrawn.to_string(),
+        available_funds: total_funded.saturating_sub(total_withdrawn).to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRICE: u128 = 100;
+
+    fn issuer() -> TicketIssuer {
+        TicketIssuer::new(
+            SigningKey::from_slice(&[7u8; 32]).unwrap(),
+            "0zk1host",
+            "0x00000000000000000000000000000000000000aa",
+            PRICE,
+        )
+    }
+
+    fn nonce_and_amount(ticket: &PaymentTicket) -> (u128, u128) {
+        (
+            ticket.nonce_value().unwrap(),
+            ticket.amount_value().unwrap(),
+        )
+    }
+
+    #[test]
+    fn issues_cumulative_tickets() {
+        let issuer = issuer();
+        assert_eq!(nonce_and_amount(&issuer.issue().unwrap()), (1, PRICE));
+        assert_eq!(nonce_and_amount(&issuer.issue().unwrap()), (2, 2 * PRICE));
+        assert_eq!(
+            nonce_and_amount(&issuer.issue_settlement(30).unwrap()),
+            (3, 2 * PRICE + 30)
+        );
+
+        issuer.observe_claimed_nonce(3);
+        assert_eq!(nonce_and_amount(&issuer.issue().unwrap()), (4, PRICE));
+    }
+
+    #[test]
+    fn resumes_after_a_restart() {
+        let issuer = issuer();
+        issuer.resume_after(5, 5 * PRICE + 30);
+        assert_eq!(
+            nonce_and_amount(&issuer.issue().unwrap()),
+            (6, 6 * PRICE + 30)
+        );
+
+        // once the host claimed, amounts restart
+        issuer.observe_claimed_nonce(6);
+        assert_eq!(nonce_and_amount(&issuer.issue().unwrap()), (7, PRICE));
+    }
+
+    #[test]
+    fn catches_up_with_the_nonce_and_amount_a_host_asks_for() {
+        let issuer = issuer();
+
+        // a host that saw tickets up to nonce 3 before the issuer restarted
+        let ticket = issuer.issue_meeting(0, 4, 4 * PRICE).unwrap();
+        assert_eq!(nonce_and_amount(&ticket), (4, 4 * PRICE));
+        assert_eq!(nonce_and_amount(&issuer.issue().unwrap()), (5, 5 * PRICE));
+
+        // an underpaid ticket is topped up by the missing amount only
+        let ticket = issuer.issue_meeting(0, 0, 6 * PRICE + 50).unwrap();
+        assert_eq!(nonce_and_amount(&ticket), (6, 6 * PRICE + 50));
+        assert_eq!(
+            nonce_and_amount(&issuer.issue().unwrap()),
+            (7, 7 * PRICE + 50)
+        );
+
+        // requirements the next ticket already meets change nothing
+        let ticket = issuer.issue_meeting(0, 2, PRICE).unwrap();
+        assert_eq!(nonce_and_amount(&ticket), (8, 8 * PRICE + 50));
+    }
+
+    #[test]
+    fn refuses_to_jump_far_past_its_counters() {
+        let issuer = issuer();
+        assert_eq!(nonce_and_amount(&issuer.issue().unwrap()), (1, PRICE));
+
+        // nonces the provider cannot have seen, or that overflow
+        assert!(issuer.issue_meeting(0, u128::MAX, 0).is_err());
+        assert!(
+            issuer
+                .issue_meeting(0, 3 + MAX_CATCH_UP_TICKETS, 0)
+                .is_err()
+        );
+        // amounts far above what the issuer signed for
+        assert!(issuer.issue_meeting(0, 0, u128::MAX).is_err());
+        assert!(
+            issuer
+                .issue_meeting(0, 0, (3 + MAX_CATCH_UP_TICKETS) * PRICE)
+                .is_err()
+        );
+
+        // nothing was signed, the issuer continues where it was
+        assert_eq!(nonce_and_amount(&issuer.issue().unwrap()), (2, 2 * PRICE));
+        let ticket = issuer
+            .issue_meeting(0, 3 + MAX_CATCH_UP_TICKETS, 0)
+            .unwrap();
+        assert_eq!(
+            nonce_and_amount(&ticket),
+            (3 + MAX_CATCH_UP_TICKETS, 3 * PRICE)
+        );
+
+        // a refused meeting ticket is given back and does not raise the next one
+        let refused = issuer
+            .issue_meeting(0, 0, (4 + MAX_CATCH_UP_TICKETS) * PRICE)
+            .unwrap();
+        issuer.release(&refused);
+        assert_eq!(
+            nonce_and_amount(&issuer.issue().unwrap()),
+            (4 + MAX_CATCH_UP_TICKETS, 4 * PRICE)
+        );
+        // only the last ticket can be given back
+        issuer.release(&refused);
+        assert_eq!(
+            nonce_and_amount(&issuer.issue().unwrap()),
+            (5 + MAX_CATCH_UP_TICKETS, 5 * PRICE)
+        );
+
+        // a counter at the end of the nonce space signs nothing
+        issuer.resume_after(u128::MAX, PRICE);
+        assert!(issuer.issue().is_err());
+    }
+}
@@ -1,20 +1,274 @@
-use crate::hpc_service::{HpcClient, PaymentTicket};
-use crate::rpc_utils::JsonRpcErrorResponse;
+use crate::body_limit::{self, BodyLimits};
+use crate::channel_registry::{ChannelRegistry, HostChannel};
+use crate::free_tier::FreeTierPolicy;
+use crate::hpc_service::{AnyTicket, LotteryTicket, PaymentTicket};
+use crate::lottery::LotteryPolicy;
+use crate::payment_receipt::{PAYMENT_RECEIPT_HEADER, PaymentReceipt, ReceiptSigner};
+use crate::payment_requirements::{PAYMENT_REQUIRED_HEADER, PaymentRequirements};
+use crate::postpaid::{PAYMENT_DEBT_HEADER, PostpaidPolicy};
+use crate::rpc_utils::{self, JsonRpcErrorResponse, RpcRequest};
+use crate::ticket_ledger::TicketLedger;
+use crate::websocket::{self, NOTIFICATION_METHOD};
+use crate::x402::{
+    PAYMENT_HEADER, PAYMENT_RESPONSE_HEADER, PaymentPayload, PaymentRequiredDocument,
+    SettlementResponse,
+};
+use anyhow::Context;
 use axum::{
     body::Body,
     extract::{Request, State},
     http::{Response, StatusCode},
     middleware::Next,
 };
-use tracing::{debug, info, warn};
+use tracing::{debug, error, info, warn};
 
 /// shared state for payment middleware
 #[derive(Clone)]
 pub struct PaymentMiddlewareState {
-    pub hpc_client: HpcClient,
+    // channels served, each with its own ticket validation, pricing, recipient and funds
+    pub channels: ChannelRegistry,
+    // persistent record of accepted tickets
+    pub ledger: TicketLedger,
+    // accept probabilistic (lottery) tickets, `None` only accepts cumulative tickets
+    pub lottery: Option<LotteryPolicy>,
+    // methods served without a ticket, `None` requires a ticket for every request
+    pub free_tier: Option<FreeTierPolicy>,
+    // bill responses by size on top of their price, `None` only charges the price
+    pub postpaid: Option<PostpaidPolicy>,
+    // EVM network advertised in x402 payment requirements
+    pub network: String,
+    // signs the receipt attached to every paid response
+    pub receipt_signer: ReceiptSigner,
+    // caps on the request read here and on metered responses
+    pub body_limits: BodyLimits,
+}
+
+impl PaymentMiddlewareState {
+    /// requirements of the next cumulative ticket on a contract's channel for a request costing
+    /// `price` plus any postpaid debt
+    fn requirements(
+        &self,
+        channel: &HostChannel,
+        contract_address: &str,
+        price: u128,
+    ) -> PaymentRequirements {
+        let requirements = PaymentRequirements::new(price, price).with_recipient(
+            channel.contract_address.as_deref(),
+            channel.railgun_address.as_deref(),
+        );
+        let debt = match &self.postpaid {
+            Some(_) => self.ledger.debt(contract_address).map(Some),
+            None => Ok(None),
+        };
+        match self
+            .ledger
+            .next_ticket(contract_address, price)
+            .and_then(|next| Ok((next, debt?)))
+        {
+            Ok((next, None)) => PaymentRequirements {
+                expected_amount: next.expected_amount,
+                ..requirements
+            }
+            .with_min_nonce(next.min_nonce),
+            Ok((next, Some(debt))) => PaymentRequirements {
+                expected_amount: next.expected_amount.saturating_add(debt),
+                ..requirements
+            }
+            .with_min_nonce(next.min_nonce)
+            .with_debt(debt),
+            Err(e) => {
+                error!(
+                    "failed to read the next ticket for {}: {}",
+                    contract_address, e
+                );
+                requirements
+            }
+        }
+    }
+
+    /// requirements for a request sent without a verified ticket, only its price and who to pay,
+    /// the amount and nonce of a channel are only told to a ticket signed for that channel
+    ///
+    /// the channel is not trusted yet, so no contract is asked for and the railgun address only
+    /// when every channel pays to it
+    fn unpaid_requirements(&self, price: u128) -> PaymentRequirements {
+        PaymentRequirements::new(price, price)
+            .with_recipient(None, self.channels.shared_railgun_address())
+    }
+
+    /// requirements of a lottery ticket for a request costing `price`
+    fn lottery_requirements(
+        &self,
+        policy: &LotteryPolicy,
+        channel: &HostChannel,
+        price: u128,
+    ) -> PaymentRequirements {
+        PaymentRequirements::new(price, policy.min_face_value(price))
+            .with_recipient(
+                channel.contract_address.as_deref(),
+                channel.railgun_address.as_deref(),
+            )
+            .with_win_probability_ppm(policy.win_probability_ppm())
+    }
+
+    /// debt a channel may leave unpaid (wei)
+    fn credit_limit(&self) -> u128 {
+        self.postpaid
+            .as_ref()
+            .map_or(0, |postpaid| postpaid.credit_limit())
+    }
+
+    /// accept the cumulative ticket paying for a WebSocket message, a ticket without a message
+    /// only settles the channel's debt
+    ///
+    /// notifications can only be billed as postpaid debt, so subscriptions are refused unless
+    /// postpaid billing is enabled
+    pub async fn accept_message_ticket(
+        &self,
+        ticket: &PaymentTicket,
+        rpc_request: Option<&RpcRequest>,
+    ) -> Result<MessagePayment, MessageRejection> {
+        if self.postpaid.is_none() && rpc_request.is_some_and(websocket::is_subscribe) {
+            return Err(MessageRejection {
+                message: SUBSCRIPTIONS_NEED_POSTPAID.to_string(),
+                requirements: None,
+            });
+        }
+        let contract_address = &ticket.hidden_payment_channels_contract_address;
+        let Some(channel) = self.channels.channel(contract_address) else {
+            warn!("rejected ticket for unserved contract {}", contract_address);
+            return Err(MessageRejection {
+                message: unserved_contract_message(contract_address),
+                requirements: None,
+            });
+        };
+        let price = match rpc_request {
+            Some(rpc_request) => channel.pricing.price_for_request(Some(rpc_request)),
+            None => 0,
+        };
+        // the channel's progress is only disclosed once the ticket is known to be signed by it
+        let unverified = |message: String| MessageRejection {
+            message,
+            requirements: Some(self.unpaid_requirements(price)),
+        };
+        let rejection = |message: String| MessageRejection {
+            message,
+            requirements: Some(self.requirements(channel, contract_address, price)),
+        };
+
+        match channel.payment_backend.validate_ticket(ticket).await {
+            Ok(true) => {}
+            Ok(false) => {
+                warn!("could not verify ticket with nonce {}", ticket.nonce);
+                return Err(unverified(
+                    "Invalid or expired payment ticket. Please generate a new ticket.".to_string(),
+                ));
+            }
+            Err(e) => {
+                warn!(
+                    "failed to validate ticket with nonce {}: {}",
+                    ticket.nonce, e
+                );
+                return Err(unverified("Failed to validate payment ticket".to_string()));
+            }
+        }
+
+        // a ticket worth more than the channel holds could never be claimed
+        if let Err(e) = ticket
+            .amount_value()
+            .map(|amount| channel.funds_monitor.check_amount(amount))
+            .unwrap_or(Ok(()))
+        {
+            warn!("rejected ticket with nonce {}: {}", ticket.nonce, e);
+            return Err(rejection(format!("Payment ticket rejected: {}", e)));
+        }
+
+        let debt = match self
+            .ledger
+            .accept_postpaid_ticket(ticket, price, self.credit_limit())
+        {
+            Ok(Ok((accepted, debt))) => {
+                if let Ok(amount) = accepted.ticket.amount_value() {
+                    channel.funds_monitor.record_ticket_amount(amount);
+                }
+                debt
+            }
+            Ok(Err(e)) => {
+                warn!("rejected ticket with nonce {}: {}", ticket.nonce, e);
+                return Err(rejection(format!("Payment ticket rejected: {}", e)));
+            }
+            Err(e) => {
+                error!(
+                    "failed to record ticket with nonce {} in ledger: {}",
+                    ticket.nonce, e
+                );
+                return Err(rejection("Failed to record payment ticket".to_string()));
+            }
+        };
+
+        let any_ticket = AnyTicket::Cumulative(ticket.clone());
+        let receipt = match PaymentReceipt::for_ticket(&any_ticket, price) {
+            Ok(receipt) => Some(self.receipt_signer.sign(&receipt)),
+            Err(e) => {
+                error!(
+                    "failed to create receipt for ticket with nonce {}: {}",
+                    ticket.nonce, e
+                );
+                None
+            }
+        };
+        Ok(MessagePayment { receipt, debt })
+    }
+
+    /// bill a subscription notification delivered on a channel as debt, returns what the
+    /// channel owes or `None` when its unpaid debt is already above the credit limit
+    ///
+    /// without postpaid billing no subscription is accepted, notifications of one opened
+    /// anyway are not billed
+    pub fn bill_notification(&self, contract_address: &str) -> anyhow::Result<Option<u128>> {
+        if self.postpaid.is_none() {
+            return self.ledger.debt(contract_address).map(Some);
+        }
+        if self.ledger.debt(contract_address)? > self.credit_limit() {
+            return Ok(None);
+        }
+        let channel = self
+            .channels
+            .channel(contract_address)
+            .with_context(|| format!("contract {} is not served", contract_address))?;
+        let price = channel.pricing.price_for(Some(NOTIFICATION_METHOD));
+        self.ledger.add_debt(contract_address, price).map(Some)
+    }
+}
+
+/// why a subscription is refused by a host without postpaid billing
+const SUBSCRIPTIONS_NEED_POSTPAID: &str =
+    "Subscriptions are billed as postpaid debt, which this provider does not enable";
+
+/// why a ticket for a contract no channel serves is refused
+fn unserved_contract_message(contract_address: &str) -> String {
+    format!(
+        "Payment ticket rejected: contract {} is not served by this provider",
+        contract_address
+    )
+}
+
+/// a WebSocket message ticket the host accepted
+pub struct MessagePayment {
+    // signed receipt for the ticket
+    pub receipt: Option<String>,
+    // what the channel still owes (wei)
+    pub debt: u128,
 }
 
-/// this middleware requires a valid payment ticket for all requests
+/// why a WebSocket message ticket was refused
+pub struct MessageRejection {
+    pub message: String,
+    // what the next ticket must look like, `None` when no ticket was sent
+    pub requirements: Option<PaymentRequirements>,
+}
+
+/// this middleware requires a valid payment ticket for all requests but those of the free tier
 pub async fn payment_verification_middleware(
     State(state): State<PaymentMiddlewareState>,
     request: Request,
@@ -22,38 +276,129 @@ pub async fn payment_verification_middleware(
 ) -> Result<Response<Body>, Response<Body>> {
     debug!("processing request");
 
-    // Extract payment ticket from header
+    // read the body to price the request by its JSON-RPC method(s)
+    let (parts, body) = request.into_parts();
+    let body = match axum::body::to_bytes(body, state.body_limits.request).await {
+        Ok(b) => b,
+        Err(e) if body_limit::is_too_large(&e) => {
+            warn!(
+                "request body is over the {} byte limit",
+                state.body_limits.request
+            );
+            return Err(body_limit::body_too_large_response(
+                state.body_limits.request,
+            ));
+        }
+        Err(e) => {
+            warn!("failed to read request body: {}", e);
+            return Err(create_error_response(
+                StatusCode::BAD_REQUEST,
+                "Failed to read request body",
+                None,
+            ));
+        }
+    };
+    let rpc_request = rpc_utils::parse_request(&body);
+    let rpc_request = rpc_request.as_ref();
+    let challenge = PaymentChallenge {
+        rpc_request,
+        network: &state.network,
+        resource: match parts.headers.get("host").and_then(|v| v.to_str().ok()) {
+            Some(host) => format!("http://{}{}", host, parts.uri),
+            None => parts.uri.to_string(),
+        },
+    };
+    let request = Request::from_parts(parts, Body::from(body));
+
+    // extract payment ticket from the x402 payment header or our own ticket header
+    let x402_payment = request
+        .headers()
+        .get(PAYMENT_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| PaymentPayload::from_header_value(v, &state.network));
     let ticket_header = request
         .headers()
         .get("X-Payment-Ticket")
         .and_then(|v| v.to_str().ok());
 
-    let ticket_json = match ticket_header {
-        Some(json) => json,
-        None => {
+    let ticket_json = match (&x402_payment, ticket_header) {
+        (Some(Ok(payment)), _) => serde_json::to_string(&payment.payload).unwrap_or_default(),
+        (Some(Err(e)), _) => {
+            warn!("invalid x402 payment: {}", e);
+            return Err(create_error_response(
+                StatusCode::BAD_REQUEST,
+                "Invalid X-PAYMENT header",
+                rpc_request,
+            ));
+        }
+        (None, Some(json)) => json.to_string(),
+        (None, None) => {
+            // bootstrap requests are served unpaid while the free tier has room
+            if let (Some(free_tier), Some(rpc_request)) = (&state.free_tier, rpc_request)
+                && free_tier.covers(rpc_request)
+            {
+                if free_tier.try_acquire(rpc_request) {
+                    debug!("serving free request methods={:?}", rpc_request.methods());
+                    return Ok(next.run(request).await);
+                }
+                warn!("free tier rate limit reached, payment required");
+            }
+
             warn!("payment required but no ticket provided");
+            let price = state
+                .channels
+                .default_channel()
+                .pricing
+                .price_for_request(rpc_request);
             return Err(create_payment_required_response(
                 "Payment required. Please provide a valid payment ticket.",
+                Some(&state.unpaid_requirements(price)),
+                &challenge,
             ));
         }
     };
 
-    // parse ticket
-    let ticket: PaymentTicket = match serde_json::from_str(ticket_json) {
+    // parse ticket, either cumulative or lottery
+    let any_ticket: AnyTicket = match serde_json::from_str(&ticket_json) {
         Ok(t) => t,
         Err(e) => {
             warn!("invalid ticket JSON: {}", e);
             return Err(create_error_response(
                 StatusCode::BAD_REQUEST,
                 "Invalid ticket format",
+                rpc_request,
             ));
         }
     };
 
+    let ticket = any_ticket.payment_ticket();
+
+    // route the ticket to its channel, which prices the request
+    let Some(channel) = state
+        .channels
+        .channel(&ticket.hidden_payment_channels_contract_address)
+    else {
+        warn!(
+            "rejected ticket for unserved contract {}",
+            ticket.hidden_payment_channels_contract_address
+        );
+        return Err(create_payment_required_response(
+            &unserved_contract_message(&ticket.hidden_payment_channels_contract_address),
+            None,
+            &challenge,
+        ));
+    };
+    let price = channel.pricing.price_for_request(rpc_request);
+    debug!(
+        "request methods={:?} cost {} wei on {}",
+        rpc_request.map(|r| r.methods()),
+        price,
+        ticket.hidden_payment_channels_contract_address
+    );
     debug!("validating ticket with nonce: {}", ticket.nonce);
 
-    // validate ticket with Railgun service
-    let is_valid = match state.hpc_client.validate_ticket(&ticket).await {
+    // validate ticket with the configured payment backend
+    let is_valid = match channel.payment_backend.validate_ticket(ticket).await {
         Ok(valid) => valid,
         Err(e) => {
             warn!(
@@ -63,14 +408,18 @@ pub async fn payment_verification_middleware(
             return Err(create_error_response(
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "Failed to validate payment ticket",
+                rpc_request,
             ));
         }
     };
 
     if !is_valid {
         warn!("could not verify ticket with nonce {}", ticket.nonce);
+        // anyone can send a ticket naming a contract, its usage is not disclosed to them
         return Err(create_payment_required_response(
             "Invalid or expired payment ticket. Please generate a new ticket.",
+            Some(&state.unpaid_requirements(price)),
+            &challenge,
         ));
     }
 
@@ -79,27 +428,309 @@ pub async fn payment_verification_middleware(
         ticket.nonce
     );
 
-    // process request
-    Ok(next.run(request).await)
+    // a ticket worth more than the channel holds could never be claimed
+    if let Err(e) = ticket
+        .amount_value()
+        .map(|amount| channel.funds_monitor.check_amount(amount))
+        .unwrap_or(Ok(()))
+    {
+        warn!("rejected ticket with nonce {}: {}", ticket.nonce, e);
+        return Err(create_payment_required_response(
+            &format!("Payment ticket rejected: {}", e),
+            None,
+            &challenge,
+        ));
+    }
+
+    // only accept tickets that pay for this request, the accepted ticket is recorded so it can
+    // be claimed later
+    // debt of the channel once the ticket paid what it could, set for postpaid channels
+    let mut debt_left = None;
+    let accepted = match &any_ticket {
+        // cumulative tickets must add the price on top of the previous ticket
+        AnyTicket::Cumulative(ticket) => match &state.postpaid {
+            Some(postpaid) => state
+                .ledger
+                .accept_postpaid_ticket(ticket, price, postpaid.credit_limit())
+                .map(|accepted| {
+                    accepted.map(|(accepted, debt)| {
+                        debt_left = Some(debt);
+                        accepted
+                    })
+                }),
+            None => state.ledger.accept_ticket(ticket, price),
+        },
+        AnyTicket::Lottery(lottery) => {
+            let Some(policy) = &state.lottery else {
+                warn!(
+                    "rejected lottery ticket with nonce {}, lottery tickets are disabled",
+                    ticket.nonce
+                );
+                return Err(create_payment_required_response(
+                    "Lottery tickets are not accepted by this provider",
+                    Some(&state.unpaid_requirements(price)),
+                    &challenge,
+                ));
+            };
+            let requirements = state.lottery_requirements(policy, channel, price);
+            if let Some(response) =
+                check_lottery_ticket(policy, lottery, price, &requirements, &challenge)
+            {
+                return Err(response);
+            }
+            let winner = policy.is_winner(lottery);
+            debug!(
+                "lottery ticket with nonce {} drawn (winner: {})",
+                ticket.nonce, winner
+            );
+            state
+                .ledger
+                .accept_lottery_ticket(lottery, policy.min_face_value(price), winner)
+        }
+    };
+
+    match accepted {
+        Ok(Ok(accepted)) => {
+            if !accepted.is_lottery()
+                && let Ok(amount) = accepted.ticket.amount_value()
+            {
+                channel.funds_monitor.record_ticket_amount(amount);
+            }
+        }
+        Ok(Err(rejection)) => {
+            warn!("rejected ticket with nonce {}: {}", ticket.nonce, rejection);
+            let requirements = match (&any_ticket, &state.lottery) {
+                (AnyTicket::Lottery(_), Some(policy)) => {
+                    state.lottery_requirements(policy, channel, price)
+                }
+                _ => state.requirements(
+                    channel,
+                    &ticket.hidden_payment_channels_contract_address,
+                    price,
+                ),
+            };
+            return Err(create_payment_required_response(
+                &format!("Payment ticket rejected: {}", rejection),
+                Some(&requirements),
+                &challenge,
+            ));
+        }
+        Err(e) => {
+            error!(
+                "failed to record ticket with nonce {} in ledger: {}",
+                ticket.nonce, e
+            );
+            return Err(create_error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to record payment ticket",
+                rpc_request,
+            ));
+        }
+    }
+
+    // process request, every paid response carries a signed receipt and x402 clients get a
+    // settlement response
+    let mut response = next.run(request).await;
+    match PaymentReceipt::for_ticket(&any_ticket, price) {
+        Ok(receipt) => {
+            if let Ok(value) = state.receipt_signer.sign(&receipt).parse() {
+                response.headers_mut().insert(PAYMENT_RECEIPT_HEADER, value);
+            }
+        }
+        Err(e) => error!(
+            "failed to create receipt for ticket with nonce {}: {}",
+            ticket.nonce, e
+        ),
+    }
+    if matches!(x402_payment, Some(Ok(_)))
+        && let Ok(value) = SettlementResponse::accepted(&state.network)
+            .to_header_value()
+            .parse()
+    {
+        response
+            .headers_mut()
+            .insert(PAYMENT_RESPONSE_HEADER, value);
+    }
+
+    // bill the response by size, the next ticket has to pay what the channel owes
+    if let (Some(postpaid), Some(debt)) = (&state.postpaid, debt_left) {
+        response = meter_response(
+            &state.ledger,
+            postpaid,
+            &state.body_limits,
+            &ticket.hidden_payment_channels_contract_address,
+            debt,
+            rpc_request,
+            response,
+        )
+        .await;
+    }
+    Ok(response)
+}
+
+/// record the metered cost of a postpaid response as debt and tell the user what the channel
+/// owes in the `X-Payment-Debt` header
+async fn meter_response(
+    ledger: &TicketLedger,
+    postpaid: &PostpaidPolicy,
+    body_limits: &BodyLimits,
+    contract_address: &str,
+    mut debt: u128,
+    rpc_request: Option<&RpcRequest>,
+    mut response: Response<Body>,
+) -> Response<Body> {
+    if postpaid.meters(rpc_request) {
+        let (parts, body) = response.into_parts();
+        let body = match body_limits.read(body).await {
+            Ok(b) => b,
+            Err(error) => {
+                return Response::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .header("content-type", "application/json")
+                    .body(Body::from(error.to_json_bytes_for(rpc_request)))
+                    .unwrap();
+            }
+        };
+
+        let cost = postpaid.cost(body.len());
+        match ledger.add_debt(contract_address, cost) {
+            Ok(total) => {
+                debug!(
+                    "billed {} wei for a {} byte response, {} owes {} wei",
+                    cost,
+                    body.len(),
+                    contract_address,
+                    total
+                );
+                debt = total;
+            }
+            Err(e) => error!(
+                "failed to record postpaid debt for {}: {}",
+                contract_address, e
+            ),
+        }
+        response = Response::from_parts(parts, Body::from(body));
+    }
+
+    if let Ok(value) = debt.to_string().parse() {
+        response.headers_mut().insert(PAYMENT_DEBT_HEADER, value);
+    }
+    response
+}
+
+/// check that a lottery ticket follows the host policy and that its expected value covers the
+/// price, returns the error response if it does not
+fn check_lottery_ticket(
+    policy: &LotteryPolicy,
+    lottery: &LotteryTicket,
+    price: u128,
+    requirements: &PaymentRequirements,
+    challenge: &PaymentChallenge<'_>,
+) -> Option<Response<Body>> {
+    let ticket = &lottery.ticket;
+
+    // the probability is not covered by the signature, only the host's own policy counts
+    if lottery.win_probability_ppm != policy.win_probability_ppm() {
+        warn!(
+            "lottery ticket with nonce {} has win probability {} ppm instead of {} ppm",
+            ticket.nonce,
+            lottery.win_probability_ppm,
+            policy.win_probability_ppm()
+        );
+        return Some(create_payment_required_response(
+            &format!(
+                "Lottery ticket must have a win probability of {} ppm",
+                policy.win_probability_ppm()
+            ),
+            Some(requirements),
+            challenge,
+        ));
+    }
+
+    let expected_value = match ticket.amount_value() {
+        Ok(face_value) => policy.expected_value(face_value),
+        Err(e) => {
+            warn!("invalid lottery ticket with nonce {}: {}", ticket.nonce, e);
+            return Some(create_error_response(
+                StatusCode::BAD_REQUEST,
+                "Invalid ticket format",
+                challenge.rpc_request,
+            ));
+        }
+    };
+
+    if expected_value < price {
+        warn!(
+            "lottery ticket with nonce {} is worth {} wei, request costs {} wei",
+            ticket.nonce, expected_value, price
+        );
+        return Some(create_payment_required_response(
+            &format!(
+                "Lottery ticket expected value {} is below the price {}",
+                expected_value, price
+            ),
+            Some(requirements),
+            challenge,
+        ));
+    }
+
+    debug!(
+        "lottery ticket with nonce {} is worth {} wei",
+        ticket.nonce, expected_value
+    );
+    None
+}
+
+/// what a 402 response needs to describe the payment of the current request
+struct PaymentChallenge<'a> {
+    rpc_request: Option<&'a RpcRequest>,
+    network: &'a str,
+    resource: String,
 }
 
-/// create a 402 Payment Required response
-fn create_payment_required_response(message: &str) -> Response<Body> {
+/// create a 402 Payment Required response, the requirements are sent as JSON-RPC error data,
+/// in the `X-Payment-Required` header and as an x402 document
+fn create_payment_required_response(
+    message: &str,
+    requirements: Option<&PaymentRequirements>,
+    challenge: &PaymentChallenge<'_>,
+) -> Response<Body> {
     let error = JsonRpcErrorResponse::new(crate::rpc_utils::JsonRpcError {
         code: -32000,
         message: message.to_string(),
-        data: None,
+        data: requirements.and_then(|r| serde_json::to_value(r).ok()),
     });
+    let mut body = error.to_json_bytes_for(challenge.rpc_request);
 
-    Response::builder()
+    let mut response = Response::builder()
         .status(StatusCode::PAYMENT_REQUIRED)
-        .header("content-type", "application/json")
-        .body(Body::from(error.to_json_bytes()))
-        .unwrap()
+        .header("content-type", "application/json");
+    if let Some(requirements) = requirements {
+        response = response.header(PAYMENT_REQUIRED_HEADER, requirements.to_header_value());
+
+        let description = match challenge.rpc_request {
+            Some(rpc_request) => format!("JSON-RPC {}", rpc_request.methods().join(", ")),
+            None => "JSON-RPC request".to_string(),
+        };
+        body = PaymentRequiredDocument::new(
+            requirements,
+            challenge.network,
+            &challenge.resource,
+            &description,
+            message,
+        )
+        .into_body(&body);
+    }
+
+    response.body(Body::from(body)).unwrap()
 }
 
 /// create a generic error response
-fn create_error_response(status: StatusCode, message: &str) -> Response<Body> {
+fn create_error_response(
+    status: StatusCode,
+    message: &str,
+    rpc_request: Option<&RpcRequest>,
+) -> Response<Body> {
     let error = JsonRpcErrorResponse::new(crate::rpc_utils::JsonRpcError {
         code: -32000,
         message: message.to_string(),
@@ -109,6 +740,6 @@ fn create_error_response(status: StatusCode, message: &str) -> Response<Body> {
     Response::builder()
         .status(status)
         .header("content-type", "application/json")
-        .body(Body::from(error.to_json_bytes()))
+        .body(Body::from(error.to_json_bytes_for(rpc_request)))
         .unwrap()
 }
@@ -0,0 +1,360 @@
+use crate::hpc_service::{AnyTicket, PaymentTicket};
+use crate::ticket_ledger::channel_key;
+use anyhow::{Context, Result, anyhow};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64;
+use chrono::{DateTime, Utc};
+use ed25519_dalek::{Signature, SigningKey, Verifier, VerifyingKey};
+use parking_lot::Mutex;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use tor_hscrypto::pk::{HsId, HsIdKeypair};
+use tor_llcrypto::pk::ed25519::ExpandedKeypair;
+use tracing::info;
+
+/// response header carrying the host's signed receipt of a paid request
+pub const PAYMENT_RECEIPT_HEADER: &str = "X-Payment-Receipt";
+
+/// host route serving the receipt verification key, for providers not reached through their
+/// onion address, where the key is the onion identity key and read from the address itself
+pub const RECEIPT_KEY_PATH: &str = "/receipt-key";
+
+const ALGORITHM: &str = "ed25519";
+
+/// what the host counted for a paid request
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct PaymentReceipt {
+    // channel the ticket was accepted on
+    pub hidden_payment_channels_contract_address: String,
+    // nonce of the accepted ticket
+    pub nonce: String,
+    // cumulative amount credited by the ticket (wei), the face value for lottery tickets
+    #[serde(with = "crate::pricing::wei")]
+    pub amount: u128,
+    // price of the request (wei)
+    #[serde(with = "crate::pricing::wei")]
+    pub price: u128,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub win_probability_ppm: Option<u32>,
+    pub issued_at: DateTime<Utc>,
+}
+
+impl PaymentReceipt {
+    /// receipt for an accepted ticket paying for a request costing `price`
+    pub fn for_ticket(ticket: &AnyTicket, price: u128) -> Result<Self> {
+        let payment_ticket = ticket.payment_ticket();
+        Ok(Self {
+            hidden_payment_channels_contract_address: payment_ticket
+                .hidden_payment_channels_contract_address
+                .clone(),
+            nonce: payment_ticket.nonce.clone(),
+            amount: payment_ticket.amount_value()?,
+            price,
+            win_probability_ppm: match ticket {
+                AnyTicket::Lottery(lottery) => Some(lottery.win_probability_ppm),
+                AnyTicket::Cumulative(_) => None,
+            },
+            issued_at: Utc::now(),
+        })
+    }
+
+    /// check that the receipt credits the ticket that was sent
+    pub fn check_ticket(&self, ticket: &PaymentTicket) -> Result<(), String> {
+        if channel_key(&self.hidden_payment_channels_contract_address)
+            != channel_key(&ticket.hidden_payment_channels_contract_address)
+        {
+            return Err(format!(
+                "receipt is for contract {} but the ticket was for {}",
+                self.hidden_payment_channels_contract_address,
+                ticket.hidden_payment_channels_contract_address
+            ));
+        }
+        if self.nonce != ticket.nonce {
+            return Err(format!(
+                "receipt credits nonce {} but the ticket carried nonce {}",
+                self.nonce, ticket.nonce
+            ));
+        }
+        if self.amount.to_string() != ticket.amount {
+            return Err(format!(
+                "receipt credits {} wei but the ticket carried {} wei",
+                self.amount, ticket.amount
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// public key receipts are verified with, served at `/receipt-key`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceiptKey {
+    pub algorithm: String,
+    // hex encoded ed25519 public key
+    pub public_key: String,
+}
+
+impl ReceiptKey {
+    /// the ed25519 key to verify receipts with
+    pub fn verifying_key(&self) -> Result<VerifyingKey> {
+        if self.algorithm != ALGORITHM {
+            return Err(anyhow!(
+                "unsupported receipt key algorithm {}",
+                self.algorithm
+            ));
+        }
+        let bytes: [u8; 32] = hex::decode(self.public_key.trim_start_matches("0x"))
+            .context("receipt key is not hex")?
+            .try_into()
+            .map_err(|_| anyhow!("receipt key must be 32 bytes"))?;
+        VerifyingKey::from_bytes(&bytes).map_err(|e| anyhow!("invalid receipt key: {}", e))
+    }
+}
+
+/// signs payment receipts with the host's ed25519 receipt key, which is also the identity key of
+/// its onion service
+///
+/// the key is kept expanded like Arti keeps onion identities, so an identity already in Arti's
+/// keystore can sign receipts as well
+#[derive(Clone)]
+pub struct ReceiptSigner {
+    keypair: Arc<ExpandedKeypair>,
+}
+
+impl ReceiptSigner {
+    /// load the receipt key from `path`, a new key is generated and stored if there is none
+    pub fn load_or_generate(path: &Path) -> Result<Self> {
+        if path.exists() {
+            let contents = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read receipt key {:?}", path))?;
+            let bytes: [u8; 32] = hex::decode(contents.trim().trim_start_matches("0x"))
+                .with_context(|| format!("receipt key {:?} is not hex", path))?
+                .try_into()
+                .map_err(|_| anyhow!("receipt key {:?} must be 32 bytes", path))?;
+            return Ok(Self::new(SigningKey::from_bytes(&bytes)));
+        }
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create receipt key directory {:?}", parent))?;
+        }
+        let signing_key = SigningKey::from_bytes(&rand::thread_rng().r#gen());
+        let signer = Self::new(signing_key.clone());
+        std::fs::write(path, hex::encode(signing_key.to_bytes()))
+            .with_context(|| format!("failed to write receipt key {:?}", path))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        }
+        info!("generated receipt key at {:?}", path);
+        Ok(signer)
+    }
+
+    /// a signer for `signing_key`
+    pub fn new(signing_key: SigningKey) -> Self {
+        Self {
+            keypair: Arc::new(ExpandedKeypair::from(&signing_key)),
+        }
+    }
+
+    /// a signer for the identity key of an onion service, whose address names the receipt key
+    pub fn from_onion_identity(identity: HsIdKeypair) -> Self {
+        Self {
+            keypair: Arc::new(identity.into()),
+        }
+    }
+
+    /// the receipt key as the identity keypair of an onion service, whose address then names
+    /// the receipt key
+    pub fn onion_identity(&self) -> HsIdKeypair {
+        ExpandedKeypair::from_secret_key_bytes(self.keypair.to_secret_key_bytes())
+            .expect("expanded key bytes round trip")
+            .into()
+    }
+
+    /// the public key to publish
+    pub fn receipt_key(&self) -> ReceiptKey {
+        ReceiptKey {
+            algorithm: ALGORITHM.to_string(),
+            public_key: hex::encode(self.keypair.public().to_bytes()),
+        }
+    }
+
+    /// sign a receipt for the `X-Payment-Receipt` header: base64url JSON and signature over
+    /// the JSON bytes, separated by a dot
+    pub fn sign(&self, receipt: &PaymentReceipt) -> String {
+        let json = serde_json::to_vec(receipt).expect("payment receipt serializes");
+        let signature = self.keypair.sign(&json);
+        format!(
+            "{}.{}",
+            BASE64.encode(&json),
+            BASE64.encode(signature.to_bytes())
+        )
+    }
+}
+
+/// verify an `X-Payment-Receipt` header and decode the receipt
+pub fn verify_receipt(value: &str, key: &VerifyingKey) -> Result<PaymentReceipt> {
+    let (json, signature) = value
+        .trim()
+        .split_once('.')
+        .ok_or_else(|| anyhow!("malformed payment receipt"))?;
+    let json = BASE64.decode(json).context("malformed payment receipt")?;
+    let signature: [u8; 64] = BASE64
+        .decode(signature)
+        .context("malformed payment receipt signature")?
+        .try_into()
+        .map_err(|_| anyhow!("payment receipt signature must be 64 bytes"))?;
+
+    key.verify(&json, &Signature::from_bytes(&signature))
+        .map_err(|_| anyhow!("payment receipt signature does not match the provider's key"))?;
+    serde_json::from_slice(&json).context("invalid payment receipt")
+}
+
+/// receipt keys and the last receipt of every provider (user)
+#[derive(Clone, Default)]
+pub struct ReceiptBook {
+    keys: Arc<Mutex<HashMap<String, VerifyingKey>>>,
+    receipts: Arc<Mutex<HashMap<(String, String), PaymentReceipt>>>,
+}
+
+impl ReceiptBook {
+    /// create an empty book
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the receipt key of a provider, if it was fetched already
+    pub fn key(&self, provider_url: &str) -> Option<VerifyingKey> {
+        self.keys
+            .lock()
+            .get(&receipt_key_url(provider_url))
+            .copied()
+    }
+
+    /// remember the receipt key of a provider
+    pub fn set_key(&self, provider_url: &str, key: VerifyingKey) {
+        self.keys.lock().insert(receipt_key_url(provider_url), key);
+    }
+
+    /// check a verified receipt against the ticket that was sent and the provider's previous
+    /// receipt on the channel, then remember it
+    pub fn record(
+        &self,
+        provider_url: &str,
+        receipt: PaymentReceipt,
+        ticket: &PaymentTicket,
+    ) -> Result<(), String> {
+        receipt.check_ticket(ticket)?;
+
+        let key = (
+            receipt_key_url(provider_url),
+            channel_key(&receipt.hidden_payment_channels_contract_address),
+        );
+        let mut receipts = self.receipts.lock();
+        let previous = receipts.insert(key, receipt.clone());
+
+        // the ticket must have added at least the price the host charged, amounts restart
+        // after a claim
+        if receipt.win_probability_ppm.is_none()
+            && let Some(previous) = previous
+        {
+            let added = if receipt.amount > previous.amount {
+                receipt.amount - previous.amount
+            } else {
+                receipt.amount
+            };
+            if added < receipt.price {
+                return Err(format!(
+                    "receipt charges {} wei but the ticket only added {} wei over the previous \
+                     receipt",
+                    receipt.price, added
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// the receipt key of a provider reached through its onion address, the onion service's identity
+/// key encoded in the address
+pub fn onion_receipt_key(provider_url: &str) -> Option<VerifyingKey> {
+    let uri = provider_url.parse::<hyper::Uri>().ok()?;
+    let hs_id = HsId::from_str(uri.host()?).ok()?;
+    VerifyingKey::from_bytes(hs_id.as_ref()).ok()
+}
+
+/// whether a receipt key served by the provider can be trusted: its origin is an onion
+/// service, which only its key holder can serve, or it is reached over https
+pub fn serves_trusted_receipt_key(provider_url: &str) -> bool {
+    provider_url.parse::<hyper::Uri>().is_ok_and(|uri| {
+        uri.scheme_str() == Some("https")
+            || uri
+                .host()
+                .is_some_and(|host| host.to_ascii_lowercase().ends_with(".onion"))
+    })
+}
+
+/// where a provider serves its receipt key, at the root of the provider's origin
+pub fn receipt_key_url(provider_url: &str) -> String {
+    let origin = provider_url.parse::<hyper::Uri>().ok().and_then(|uri| {
+        Some(format!(
+            "{}://{}",
+            uri.scheme_str().unwrap_or("https"),
+            uri.authority()?
+        ))
+    });
+    format!(
+        "{}{}",
+        origin.unwrap_or_else(|| provider_url.trim_end_matches('/').to_string()),
+        RECEIPT_KEY_PATH
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tor_hscrypto::pk::HsIdKey;
+
+    #[test]
+    fn onion_addresses_name_the_receipt_key() {
+        let signer = ReceiptSigner::new(SigningKey::from_bytes(&[7u8; 32]));
+        let onion = HsIdKey::from(&signer.onion_identity()).id().to_string();
+        let key = onion_receipt_key(&format!("http://{}:80/", onion)).unwrap();
+
+        let receipt = PaymentReceipt {
+            hidden_payment_channels_contract_address: "0xaa".to_string(),
+            nonce: "1".to_string(),
+            amount: 300,
+            price: 100,
+            win_probability_ppm: None,
+            issued_at: Utc::now(),
+        };
+        assert_eq!(
+            verify_receipt(&signer.sign(&receipt), &key).unwrap(),
+            receipt
+        );
+
+        let other = ReceiptSigner::new(SigningKey::from_bytes(&[8u8; 32]));
+        assert!(verify_receipt(&other.sign(&receipt), &key).is_err());
+        assert!(onion_receipt_key("https://example.com/").is_none());
+
+        // a key served over plain http could be replaced by anyone on the path
+        assert!(serves_trusted_receipt_key(&format!("http://{}:80/", onion)));
+        assert!(serves_trusted_receipt_key("https://example.com/rpc"));
+        assert!(!serves_trusted_receipt_key("http://example.com/rpc"));
+
+        // an identity already in the Tor keystore signs for its own address
+        let stored = ReceiptSigner::from_onion_identity(other.onion_identity());
+        let onion = HsIdKey::from(&stored.onion_identity()).id().to_string();
+        let key = onion_receipt_key(&format!("http://{}:80/", onion)).unwrap();
+        assert_eq!(stored.receipt_key().verifying_key().unwrap(), key);
+        assert!(verify_receipt(&stored.sign(&receipt), &key).is_ok());
+    }
+}
@@ -0,0 +1,158 @@
+use crate::hpc_service::PaymentTicket;
+use crate::ticket_ledger::channel_key;
+use anyhow::{Context, Result};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// response header carrying the JSON encoded payment requirements of a 402 response
+pub const PAYMENT_REQUIRED_HEADER: &str = "X-Payment-Required";
+
+/// what a ticket must look like for the host to accept a request, sent with every 402 response
+/// in the JSON-RPC error `data` and in the `X-Payment-Required` header
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct PaymentRequirements {
+    // price of the rejected request (wei)
+    #[serde(with = "crate::pricing::wei")]
+    pub price: u128,
+
+    // minimum (cumulative) amount the next ticket must carry (wei)
+    #[serde(with = "crate::pricing::wei")]
+    pub expected_amount: u128,
+
+    // the next ticket's nonce must be at least this
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_nonce: Option<String>,
+
+    // HiddenPaymentChannels contract the ticket must be issued for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hidden_payment_channels_contract_address: Option<String>,
+
+    // railgun address the ticket must pay to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to_railgun_address: Option<String>,
+
+    // win probability lottery tickets must carry, `expectedAmount` is then the face value
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub win_probability_ppm: Option<u32>,
+
+    // postpaid debt of the channel (wei), included in `expectedAmount`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub debt: Option<String>,
+}
+
+impl PaymentRequirements {
+    /// requirements for a request costing `price` whose ticket must carry `expected_amount`
+    pub fn new(price: u128, expected_amount: u128) -> Self {
+        Self {
+            price,
+            expected_amount,
+            min_nonce: None,
+            hidden_payment_channels_contract_address: None,
+            to_railgun_address: None,
+            win_probability_ppm: None,
+            debt: None,
+        }
+    }
+
+    /// the ticket must pay `to_railgun_address` through `contract_address`
+    pub fn with_recipient(
+        mut self,
+        contract_address: Option<&str>,
+        to_railgun_address: Option<&str>,
+    ) -> Self {
+        self.hidden_payment_channels_contract_address = contract_address.map(str::to_string);
+        self.to_railgun_address = to_railgun_address.map(str::to_string);
+        self
+    }
+
+    /// the ticket's nonce must be at least `min_nonce`
+    pub fn with_min_nonce(mut self, min_nonce: u128) -> Self {
+        self.min_nonce = Some(min_nonce.to_string());
+        self
+    }
+
+    /// the ticket must be a lottery ticket with this win probability
+    pub fn with_win_probability_ppm(mut self, win_probability_ppm: u32) -> Self {
+        self.win_probability_ppm = Some(win_probability_ppm);
+        self
+    }
+
+    /// the channel owes `debt` for postpaid responses
+    pub fn with_debt(mut self, debt: u128) -> Self {
+        self.debt = Some(debt.to_string());
+        self
+    }
+
+    /// encode for the `X-Payment-Required` header
+    pub fn to_header_value(&self) -> String {
+        serde_json::to_string(self).expect("payment requirements serialize")
+    }
+
+    /// decode the `X-Payment-Required` header
+    pub fn from_header_value(value: &str) -> Result<Self> {
+        serde_json::from_str(value).context("invalid payment requirements header")
+    }
+
+    /// extract the requirements from a 402 JSON-RPC error body (single or batch)
+    pub fn from_error_body(body: &[u8]) -> Option<Self> {
+        let value: serde_json::Value = serde_json::from_slice(body).ok()?;
+        let error = match &value {
+            serde_json::Value::Array(responses) => responses.first()?.get("error")?,
+            response => response.get("error")?,
+        };
+        serde_json::from_value(error.get("data")?.clone()).ok()
+    }
+
+    /// check that a ticket pays the recipient these requirements ask for, amounts and nonces
+    /// are not checked since they move with every request
+    pub fn check_recipient(&self, ticket: &PaymentTicket) -> Result<(), String> {
+        if let Some(contract_address) = &self.hidden_payment_channels_contract_address
+            && channel_key(contract_address)
+                != channel_key(&ticket.hidden_payment_channels_contract_address)
+        {
+            return Err(format!(
+                "ticket is for contract {} but the provider expects {}",
+                ticket.hidden_payment_channels_contract_address, contract_address
+            ));
+        }
+
+        if let Some(to_railgun_address) = &self.to_railgun_address
+            && to_railgun_address != &ticket.to_railgun_address
+        {
+            return Err(format!(
+                "ticket pays {} but the provider expects {}",
+                ticket.to_railgun_address, to_railgun_address
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// last payment requirements seen per provider (user)
+#[derive(Clone, Default)]
+pub struct RequirementsCache {
+    providers: Arc<Mutex<HashMap<String, PaymentRequirements>>>,
+}
+
+impl RequirementsCache {
+    /// create an empty cache
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the last requirements a provider sent
+    pub fn get(&self, provider_url: &str) -> Option<PaymentRequirements> {
+        self.providers.lock().get(provider_url).cloned()
+    }
+
+    /// remember the requirements a provider sent
+    pub fn update(&self, provider_url: &str, requirements: PaymentRequirements) {
+        self.providers
+            .lock()
+            .insert(provider_url.to_string(), requirements);
+    }
+}
@@ -0,0 +1,114 @@
+use crate::rpc_utils::RpcRequest;
+use parking_lot::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// response header carrying what the channel owes for postpaid responses (wei)
+pub const PAYMENT_DEBT_HEADER: &str = "X-Payment-Debt";
+
+/// host-side metering of responses by size
+///
+/// some methods (`eth_getLogs`, `eth_getBlockByNumber` with full transactions) return very
+/// different amounts of data, so on top of their price they are billed by the size of the
+/// response once it is known. The cost is recorded as debt of the channel, the next ticket has
+/// to pay it and service is refused while the unpaid debt is above the credit limit
+#[derive(Clone)]
+pub struct PostpaidPolicy {
+    wei_per_kib: u128,
+    credit_limit: u128,
+    // methods billed by response size, empty meters every request
+    methods: Arc<HashSet<String>>,
+}
+
+impl PostpaidPolicy {
+    /// create a policy billing `wei_per_kib` for every started KiB of a metered response
+    pub fn new(wei_per_kib: u128, credit_limit: u128, methods: &[String]) -> Self {
+        info!(
+            "postpaid metering enabled at {} wei per KiB (credit limit: {} wei, methods: {:?})",
+            wei_per_kib, credit_limit, methods
+        );
+        Self {
+            wei_per_kib,
+            credit_limit,
+            methods: Arc::new(methods.iter().cloned().collect()),
+        }
+    }
+
+    /// debt a ticket may leave unpaid (wei)
+    pub fn credit_limit(&self) -> u128 {
+        self.credit_limit
+    }
+
+    /// whether the response to a request is billed by size
+    pub fn meters(&self, request: Option<&RpcRequest>) -> bool {
+        if self.methods.is_empty() {
+            return true;
+        }
+        request.is_some_and(|request| {
+            request
+                .methods()
+                .iter()
+                .any(|method| self.methods.contains(*method))
+        })
+    }
+
+    /// cost of a response of `bytes` bytes (wei)
+    pub fn cost(&self, bytes: usize) -> u128 {
+        (bytes as u128)
+            .div_ceil(1024)
+            .saturating_mul(self.wei_per_kib)
+    }
+}
+
+/// what every provider last said the user owes for postpaid responses (user)
+#[derive(Clone, Default)]
+pub struct DebtBook {
+    debts: Arc<Mutex<HashMap<String, u128>>>,
+    // what a response may add to the debt on top of the price of its request (wei)
+    allowance: u128,
+}
+
+impl DebtBook {
+    /// create an empty book, a response may add `allowance` on top of its price for metering
+    pub fn new(allowance: u128) -> Self {
+        Self {
+            debts: Arc::default(),
+            allowance,
+        }
+    }
+
+    /// the debt the next ticket to `provider_url` has to pay
+    pub fn get(&self, provider_url: &str) -> u128 {
+        self.debts
+            .lock()
+            .get(provider_url)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// remember the debt a provider reported
+    pub fn set(&self, provider_url: &str, debt: u128) {
+        self.debts.lock().insert(provider_url.to_string(), debt);
+    }
+
+    /// remember the debt a provider reported with the response to a request costing `price`,
+    /// a debt that grew by more than the price and the allowance is refused and the debt left
+    /// as it was, returns whether it was recorded
+    pub fn report(&self, provider_url: &str, debt: u128, price: u128) -> bool {
+        let mut debts = self.debts.lock();
+        let previous = debts.get(provider_url).copied().unwrap_or_default();
+        let bound = previous
+            .saturating_add(price)
+            .saturating_add(self.allowance);
+        if debt > bound {
+            warn!(
+                "{} reports a debt of {} wei, more than the {} wei its requests can have cost",
+                provider_url, debt, bound
+            );
+            return false;
+        }
+        debts.insert(provider_url.to_string(), debt);
+        true
+    }
+}
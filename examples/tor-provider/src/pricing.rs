@@ -0,0 +1,277 @@
+use crate::rpc_utils::RpcRequest;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use tracing::info;
+
+/// per JSON-RPC method pricing, loaded from TOML:
+///
+/// ```toml
+/// default = 300000000
+///
+/// [methods]
+/// eth_chainId = 0
+/// "debug_*" = "3000000000"
+/// ```
+///
+/// method keys are either exact method names or glob patterns where `*` matches any sequence
+/// of characters, exact names win over patterns and longer patterns win over shorter ones
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PricingTable {
+    // price (wei) of methods that are not listed
+    #[serde(with = "wei")]
+    pub default: u128,
+
+    // method name or glob pattern -> price (wei)
+    #[serde(default, with = "wei_map")]
+    pub methods: BTreeMap<String, u128>,
+}
+
+/// pricing file as written by the operator, `default` falls back to the configured ticket price
+#[derive(Deserialize)]
+struct PricingFile {
+    #[serde(default, with = "wei_option")]
+    default: Option<u128>,
+    #[serde(default, with = "wei_map")]
+    methods: BTreeMap<String, u128>,
+}
+
+impl PricingTable {
+    /// every method costs the same
+    pub fn flat(price: u128) -> Self {
+        Self {
+            default: price,
+            methods: BTreeMap::new(),
+        }
+    }
+
+    /// load a pricing table from a TOML file
+    pub fn load(path: &Path, default_price: u128) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read pricing file {:?}", path))?;
+        let file: PricingFile = toml::from_str(&contents)
+            .with_context(|| format!("failed to parse pricing file {:?}", path))?;
+
+        let table = Self {
+            default: file.default.unwrap_or(default_price),
+            methods: file.methods,
+        };
+        info!(
+            "loaded pricing for {} methods from {:?} (default: {} wei)",
+            table.methods.len(),
+            path,
+            table.default
+        );
+        Ok(table)
+    }
+
+    /// price (wei) of a single call to `method`, unknown or missing methods cost the default
+    pub fn price_for(&self, method: Option<&str>) -> u128 {
+        let Some(method) = method else {
+            return self.default;
+        };
+
+        if let Some(price) = self.methods.get(method) {
+            return *price;
+        }
+
+        self.methods
+            .iter()
+            .filter(|(pattern, _)| pattern.contains('*') && glob_matches(pattern, method))
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map(|(_, price)| *price)
+            .unwrap_or(self.default)
+    }
+
+    /// price (wei) of a request, a batch costs the sum of its calls
+    pub fn price_for_request(&self, request: Option<&RpcRequest>) -> u128 {
+        match request {
+            Some(RpcRequest::Batch(calls)) if !calls.is_empty() => calls
+                .iter()
+                .map(|call| self.price_for(call.method.as_deref()))
+                .fold(0u128, u128::saturating_add),
+            Some(RpcRequest::Single(call)) => self.price_for(call.method.as_deref()),
+            _ => self.default,
+        }
+    }
+}
+
+/// match `value` against a pattern where `*` matches any (possibly empty) sequence
+fn glob_matches(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+
+    let Some(mut rest) = value.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // no wildcard at all
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+/// serialize wei amounts as decimal strings (like ticket amounts), accept strings or integers
+pub mod wei {
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    pub(super) enum WeiValue {
+        Integer(u64),
+        String(String),
+    }
+
+    impl WeiValue {
+        pub(super) fn into_wei<E: Error>(self) -> Result<u128, E> {
+            match self {
+                Self::Integer(value) => Ok(value as u128),
+                Self::String(value) => value
+                    .parse()
+                    .map_err(|e| E::custom(format!("invalid wei amount '{}': {}", value, e))),
+            }
+        }
+    }
+
+    pub fn serialize<S: Serializer>(value: &u128, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&value.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u128, D::Error> {
+        WeiValue::deserialize(deserializer)?.into_wei()
+    }
+}
+
+pub mod wei_option {
+    use super::wei::WeiValue;
+    use serde::{Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<u128>, D::Error> {
+        Option::<WeiValue>::deserialize(deserializer)?
+            .map(WeiValue::into_wei)
+            .transpose()
+    }
+}
+
+mod wei_map {
+    use super::wei::WeiValue;
+    use serde::{Deserialize, Deserializer, Serializer, ser::SerializeMap};
+    use std::collections::BTreeMap;
+
+    pub fn serialize<S: Serializer>(
+        value: &BTreeMap<String, u128>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(value.len()))?;
+        for (method, price) in value {
+            map.serialize_entry(method, &price.to_string())?;
+        }
+        map.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<String, u128>, D::Error> {
+        BTreeMap::<String, WeiValue>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(method, price)| Ok((method, price.into_wei()?)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc_utils::parse_request;
+
+    fn table() -> PricingTable {
+        PricingTable {
+            default: 100,
+            methods: BTreeMap::from([
+                ("eth_chainId".to_string(), 0),
+                ("eth_*".to_string(), 200),
+                ("eth_get*".to_string(), 300),
+                ("debug_*".to_string(), 1000),
+            ]),
+        }
+    }
+
+    #[test]
+    fn globs_match_any_sequence() {
+        assert!(glob_matches("eth_*", "eth_call"));
+        assert!(glob_matches("eth_*", "eth_"));
+        assert!(glob_matches("*_call", "eth_call"));
+        assert!(glob_matches("eth_*Block*", "eth_getBlockByNumber"));
+        assert!(glob_matches("*", "anything"));
+        assert!(!glob_matches("eth_*", "debug_traceCall"));
+        assert!(!glob_matches("eth_*Block", "eth_getBlockByNumber"));
+        assert!(!glob_matches("eth_call", "eth_calls"));
+        // prefix and suffix must not overlap
+        assert!(!glob_matches("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn exact_names_and_longer_patterns_win() {
+        let table = table();
+        assert_eq!(table.price_for(Some("eth_chainId")), 0);
+        assert_eq!(table.price_for(Some("eth_call")), 200);
+        assert_eq!(table.price_for(Some("eth_getLogs")), 300);
+        assert_eq!(table.price_for(Some("debug_traceCall")), 1000);
+        assert_eq!(table.price_for(Some("net_version")), 100);
+        assert_eq!(table.price_for(None), 100);
+        assert_eq!(PricingTable::flat(42).price_for(Some("eth_call")), 42);
+    }
+
+    #[test]
+    fn batches_cost_the_sum_of_their_calls() {
+        let table = table();
+        let request = |body: &str| parse_request(body.as_bytes());
+
+        let single = request(r#"{"jsonrpc":"2.0","id":1,"method":"eth_getLogs"}"#);
+        assert_eq!(table.price_for_request(single.as_ref()), 300);
+
+        let batch = request(
+            r#"[{"jsonrpc":"2.0","id":1,"method":"eth_chainId"},
+                {"jsonrpc":"2.0","id":2,"method":"eth_call"},
+                {"jsonrpc":"2.0","id":3,"method":"debug_traceCall"},
+                {"jsonrpc":"2.0","id":4}]"#,
+        );
+        assert_eq!(table.price_for_request(batch.as_ref()), 1300);
+
+        // nothing to price by
+        assert_eq!(table.price_for_request(request("[]").as_ref()), 100);
+        assert_eq!(table.price_for_request(None), 100);
+    }
+
+    #[test]
+    fn loads_wei_amounts_as_strings_or_integers() {
+        let path = std::env::temp_dir().join(format!("pricing-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "[methods]\neth_chainId = 0\n\"debug_*\" = \"3000000000000000000000\"\n",
+        )
+        .unwrap();
+        let table = PricingTable::load(&path, 100).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(table.default, 100);
+        assert_eq!(table.price_for(Some("eth_chainId")), 0);
+        assert_eq!(
+            table.price_for(Some("debug_traceCall")),
+            3_000_000_000_000_000_000_000
+        );
+    }
+}
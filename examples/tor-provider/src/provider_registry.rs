@@ -0,0 +1,302 @@
+use crate::config::HpcConfig;
+use crate::funds_monitor::FundsMonitor;
+use crate::hpc_service::PaymentTicket;
+use crate::payment_backend::{PaymentBackend, PaymentBackendKind, create_payment_backend};
+use crate::spend_budget::{SpendBudget, SpendLimits};
+use crate::ticket_ledger::channel_key;
+use anyhow::{Context, Result, anyhow};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use tokio::task::JoinHandle;
+use tracing::info;
+
+/// the turn to issue a ticket on a channel, see `UserChannel::ticket_turn`
+pub type TicketTurn = OwnedMutexGuard<()>;
+
+/// the channel a user pays a provider through
+#[derive(Clone)]
+pub struct UserChannel {
+    // HiddenPaymentChannels contract tickets are issued for, if known
+    pub contract_address: Option<String>,
+    // railgun address tickets must pay, a ticket for anyone else is never sent
+    pub railgun_address: Option<String>,
+    // issues this channel's tickets and reads its funds
+    pub payment_backend: Arc<dyn PaymentBackend>,
+    pub funds_monitor: FundsMonitor,
+    // pay the postpaid debt the provider reports, see `UserConfig::postpaid`
+    pub postpaid: bool,
+    // configured price of a request (wei), what a ticket is expected to spend until the
+    // provider tells its prices
+    pub ticket_price: u128,
+    // one ticket at a time, see `ticket_turn`
+    sequencer: Arc<Mutex<()>>,
+}
+
+impl UserChannel {
+    /// a channel for the given HiddenPaymentChannels configuration, paying postpaid debt
+    /// needs a backend whose tickets can carry it
+    pub fn from_config(
+        config: &HpcConfig,
+        postpaid: bool,
+        warn_thresholds: &[u128],
+    ) -> Result<Self> {
+        let payment_backend = create_payment_backend(config)?;
+        if postpaid && !payment_backend.carries_debt() {
+            return Err(anyhow!(
+                "postpaid providers need a payment backend that can add debt to tickets (native or memory), the {:?} backend cannot",
+                config.payment_backend()
+            ));
+        }
+        Ok(Self {
+            contract_address: config.contract_address(),
+            railgun_address: config.railgun_address(),
+            funds_monitor: FundsMonitor::new(payment_backend.clone(), warn_thresholds.to_vec()),
+            payment_backend,
+            postpaid,
+            ticket_price: config.ticket_price_wei,
+            sequencer: Arc::new(Mutex::new(())),
+        })
+    }
+
+    /// wait for the turn to issue a ticket on this channel
+    ///
+    /// the turn is held from signing a ticket until the provider answered the request carrying
+    /// it, so concurrent requests cannot sign overlapping tickets and the host settles them in
+    /// the order they were signed, with nonces and amounts rising strictly, see
+    /// `ProxyTorClient::send_request`
+    pub async fn ticket_turn(&self) -> TicketTurn {
+        self.sequencer.clone().lock_owned().await
+    }
+
+    /// check that a generated ticket pays the expected recipient through this channel
+    pub fn check_ticket(&self, ticket: &PaymentTicket) -> Result<(), String> {
+        if let Some(contract_address) = &self.contract_address
+            && channel_key(contract_address)
+                != channel_key(&ticket.hidden_payment_channels_contract_address)
+        {
+            return Err(format!(
+                "ticket is for contract {} but the provider is paid through {}",
+                ticket.hidden_payment_channels_contract_address, contract_address
+            ));
+        }
+        if let Some(railgun_address) = &self.railgun_address
+            && railgun_address != &ticket.to_railgun_address
+        {
+            return Err(format!(
+                "ticket pays {} but the provider's railgun address is {}",
+                ticket.to_railgun_address, railgun_address
+            ));
+        }
+        Ok(())
+    }
+
+    /// seed the funds monitor and the ticket issuer with the latest ticket signed on this
+    /// channel, so a restarted proxy neither forgets what it promised nor reuses nonces
+    fn restore_latest_ticket(&self, spend_budget: &SpendBudget) -> Result<()> {
+        let Some((nonce, amount)) = spend_budget.latest_ticket(self.contract_address.as_deref())?
+        else {
+            return Ok(());
+        };
+        self.funds_monitor.record_ticket_amount(amount);
+        if self.contract_address.is_some() {
+            self.payment_backend.resume_after(nonce, amount);
+        }
+        Ok(())
+    }
+}
+
+/// a provider from the providers file
+#[derive(Clone)]
+pub struct Provider {
+    pub name: String,
+    // onion URL requests to the provider are forwarded to
+    pub url: String,
+    pub channel: UserChannel,
+}
+
+/// providers file as written by the user, keyed by provider name:
+///
+/// ```toml
+/// [providers.alice]
+/// url = "http://alice...onion/"
+/// hpc_contract_address = "0x5FbDB2315678afecb367f032d93F642f64180aa3"
+/// to_railgun_address = "0zk1..."
+/// ticket_signing_key_file = "alice-ticket-key"
+/// daily_budget_wei = "100000000000000000"
+/// postpaid = true
+/// ```
+///
+/// unset channel settings default to the command line, `ticket_signing_key_file` is relative to
+/// the providers file and the budgets replace the `--provider-*-budget-wei` limits
+#[derive(Deserialize)]
+struct ProvidersFile {
+    #[serde(default)]
+    providers: BTreeMap<String, ProviderEntry>,
+}
+
+#[derive(Deserialize)]
+struct ProviderEntry {
+    url: String,
+    payment_backend: Option<PaymentBackendKind>,
+    hpc_service_url: Option<String>,
+    hpc_contract_address: Option<String>,
+    to_railgun_address: Option<String>,
+    ticket_signing_key_file: Option<PathBuf>,
+    #[serde(default, with = "crate::pricing::wei_option")]
+    ticket_price_wei: Option<u128>,
+    postpaid: Option<bool>,
+    #[serde(default, with = "crate::pricing::wei_option")]
+    hourly_budget_wei: Option<u128>,
+    #[serde(default, with = "crate::pricing::wei_option")]
+    daily_budget_wei: Option<u128>,
+    #[serde(default, with = "crate::pricing::wei_option")]
+    lifetime_budget_wei: Option<u128>,
+}
+
+impl ProviderEntry {
+    /// the command line configuration with this provider's channel settings applied
+    fn hpc_config(&self, config: &HpcConfig, base_dir: &Path) -> HpcConfig {
+        let mut config = config.clone();
+        if let Some(payment_backend) = self.payment_backend {
+            config.payment_backend = Some(payment_backend);
+        }
+        if let Some(hpc_service_url) = &self.hpc_service_url {
+            config.hpc_service_url = hpc_service_url.clone();
+        }
+        if let Some(contract_address) = &self.hpc_contract_address {
+            config.hpc_contract_address = Some(contract_address.clone());
+        }
+        if let Some(railgun_address) = &self.to_railgun_address {
+            config.railgun_address = Some(railgun_address.clone());
+        }
+        if let Some(path) = &self.ticket_signing_key_file {
+            config.ticket_signing_key_file = Some(base_dir.join(path));
+        }
+        if let Some(ticket_price_wei) = self.ticket_price_wei {
+            config.ticket_price_wei = ticket_price_wei;
+        }
+        config
+    }
+
+    /// the provider's budget, `None` when the file sets none
+    fn limits(&self) -> Option<SpendLimits> {
+        let limits = SpendLimits {
+            hourly: self.hourly_budget_wei,
+            daily: self.daily_budget_wei,
+            lifetime: self.lifetime_budget_wei,
+        };
+        (limits.hourly.is_some() || limits.daily.is_some() || limits.lifetime.is_some())
+            .then_some(limits)
+    }
+}
+
+/// the providers a user pays, requests are paid through their provider's channel and requests
+/// to other providers through the default channel
+#[derive(Clone)]
+pub struct ProviderRegistry {
+    default: UserChannel,
+    providers: Arc<Vec<Provider>>,
+}
+
+impl ProviderRegistry {
+    /// a registry paying every provider through the default channel
+    pub fn single(default: UserChannel) -> Self {
+        Self {
+            default,
+            providers: Arc::new(Vec::new()),
+        }
+    }
+
+    /// load the providers of a providers file, returns the registry and the budgets it sets by
+    /// provider URL
+    pub fn load(
+        path: &Path,
+        default: UserChannel,
+        config: &HpcConfig,
+        postpaid: bool,
+        warn_thresholds: &[u128],
+    ) -> Result<(Self, HashMap<String, SpendLimits>)> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read providers file {:?}", path))?;
+        let file: ProvidersFile = toml::from_str(&contents)
+            .with_context(|| format!("failed to parse providers file {:?}", path))?;
+        let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+
+        let mut providers = Vec::new();
+        let mut budgets = HashMap::new();
+        for (name, entry) in file.providers {
+            if !entry.url.starts_with("http://") && !entry.url.starts_with("https://") {
+                return Err(anyhow!(
+                    "provider {} URL must start with http:// or https://",
+                    name
+                ));
+            }
+            let hpc_config = entry.hpc_config(config, base_dir);
+            let postpaid = entry.postpaid.unwrap_or(postpaid);
+            let channel = UserChannel::from_config(&hpc_config, postpaid, warn_thresholds)
+                .with_context(|| format!("failed to set up the channel of provider {}", name))?;
+
+            info!(
+                "registered provider {} at {} (contract: {:?}, railgun: {:?})",
+                name, entry.url, channel.contract_address, channel.railgun_address
+            );
+            if let Some(limits) = entry.limits() {
+                budgets.insert(entry.url.clone(), limits);
+            }
+            providers.push(Provider {
+                name,
+                url: entry.url,
+                channel,
+            });
+        }
+
+        info!("loaded {} providers from {:?}", providers.len(), path);
+        Ok((
+            Self {
+                default,
+                providers: Arc::new(providers),
+            },
+            budgets,
+        ))
+    }
+
+    /// resolve the `p` query parameter, a provider name or URL, to the URL to forward to and
+    /// the channel to pay through
+    pub fn resolve(&self, provider: &str) -> (String, &UserChannel) {
+        match self
+            .providers
+            .iter()
+            .find(|p| p.name == provider || same_url(&p.url, provider))
+        {
+            Some(p) => (p.url.clone(), &p.channel),
+            None => (provider.to_string(), &self.default),
+        }
+    }
+
+    /// every channel, the default one first
+    pub fn channels(&self) -> impl Iterator<Item = &UserChannel> {
+        std::iter::once(&self.default).chain(self.providers.iter().map(|p| &p.channel))
+    }
+
+    /// seed every funds monitor and ticket issuer with the latest ticket signed on its channel
+    pub fn restore_latest_tickets(&self, spend_budget: &SpendBudget) -> Result<()> {
+        self.channels()
+            .try_for_each(|channel| channel.restore_latest_ticket(spend_budget))
+    }
+
+    /// poll the funds of every channel in the background
+    pub fn spawn_funds_monitors(&self, interval: Duration) -> Vec<JoinHandle<()>> {
+        self.channels()
+            .map(|channel| channel.funds_monitor.spawn(interval))
+            .collect()
+    }
+}
+
+/// whether two provider URLs are the same, ignoring a trailing slash
+fn same_url(a: &str, b: &str) -> bool {
+    a.trim_end_matches('/') == b.trim_end_matches('/')
+}
@@ -1,20 +1,48 @@
-use crate::tor::TorClientManager;
+use crate::config::IsolationPolicy;
+use crate::connection_pool::{ConnectionPool, Sender};
+use crate::provider_registry::TicketTurn;
+use crate::tor::{CircuitIsolation, TorClientManager};
+use crate::websocket::websocket_url;
+use crate::x402::{PAYMENT_HEADER, PaymentPayload};
 use anyhow::Result;
+use arti_client::{IsolationToken, StreamPrefs};
 use bytes::Bytes;
-use hyper::{Body, Request, Response, Uri};
+use hyper::client::conn::{http1, http2};
+use hyper::{Body, Method, Request, Response, Uri};
 use rustls::RootCertStore;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::time::Duration;
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::oneshot;
 use tokio_rustls::rustls::ServerName;
+use tokio_tungstenite::WebSocketStream;
 use tracing::{debug, error, info};
 
+/// a stream to a provider, over TOR and TLS for https providers
+pub trait TunnelStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> TunnelStream for S {}
+
 /// HTTP client that routes all requests through TOR (used in user mode)
 #[derive(Clone)]
 pub struct ProxyTorClient {
     tor_manager: Arc<TorClientManager>,
     tls_connector: tokio_rustls::TlsConnector,
+    // TLS connector offering HTTP/2 over ALPN
+    h2_tls_connector: tokio_rustls::TlsConnector,
+    // negotiate HTTP/2 with providers
+    http2: bool,
     timeout: Duration,
+    // idle keep-alive connections to providers
+    pool: ConnectionPool,
+    // which requests may share Tor circuits
+    isolation: CircuitIsolation,
+    // local client the requests are made for, see `IsolationPolicy::PerLocalClient`
+    local_client: Option<String>,
 }
 
 impl ProxyTorClient {
@@ -40,7 +68,11 @@ impl ProxyTorClient {
             .with_root_certificates(root_cert_store)
             .with_no_client_auth();
 
+        let mut h2_config = config.clone();
+        h2_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
         let tls_connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+        let h2_tls_connector = tokio_rustls::TlsConnector::from(Arc::new(h2_config));
         info!(
             "TLS connector created with {} root certificates",
             webpki_roots::TLS_SERVER_ROOTS.len()
@@ -50,173 +82,346 @@ impl ProxyTorClient {
             tor_manager,
             timeout,
             tls_connector,
+            h2_tls_connector,
+            http2: false,
+            pool: ConnectionPool::new(0, Duration::ZERO),
+            isolation: CircuitIsolation::new(IsolationPolicy::PerProvider, Duration::ZERO),
+            local_client: None,
         })
     }
 
+    /// keep requests on separate Tor circuits by an isolation policy, requests to different
+    /// providers are isolated by default
+    pub fn with_isolation(mut self, isolation: CircuitIsolation) -> Self {
+        self.isolation = isolation;
+        self
+    }
+
+    /// a client making requests for a local client (a wallet or dapp), requests of different
+    /// local clients never share circuits under `IsolationPolicy::PerLocalClient`
+    pub fn for_local_client(&self, local_client: Option<String>) -> Self {
+        Self {
+            local_client,
+            ..self.clone()
+        }
+    }
+
+    /// speak HTTP/2 with providers that support it, with prior knowledge to onion services and
+    /// over ALPN to https providers, so concurrent requests share one Tor stream
+    pub fn with_http2(mut self, enabled: bool) -> Self {
+        self.http2 = enabled;
+        self
+    }
+
+    /// reuse connections to providers from a pool, without one every request opens a new Tor
+    /// stream
+    pub fn with_pool(mut self, pool: ConnectionPool) -> Self {
+        self.pool = pool;
+        self
+    }
+
     /// forward a request to the upstream RPC endpoint over TOR, no ticket
     pub async fn forward_request(
         &self,
         body: Bytes,
         provider_url: String,
     ) -> Result<Response<Body>> {
-        self.forward_request_with_payment(body, provider_url, None)
+        self.forward_request_with_payment(body, provider_url, None, None)
             .await
     }
 
-    /// forward a request to the upstream RPC endpoint over TOR, with optional payment ticket
+    /// forward a request to the upstream RPC endpoint over TOR, with optional payment ticket,
+    /// the channel's ticket `turn` is given back once the provider answered
     pub async fn forward_request_with_payment(
         &self,
         body: Bytes,
         provider_url: String,
         payment_ticket: Option<&crate::hpc_service::PaymentTicket>,
+        turn: Option<TicketTurn>,
+    ) -> Result<Response<Body>> {
+        // add payment ticket header if provided
+        let payment_header = match payment_ticket {
+            Some(ticket) => {
+                let ticket_json = serde_json::to_string(ticket).map_err(|e| {
+                    error!("failed to serialize payment ticket: {}", e);
+                    anyhow::anyhow!("Failed to serialize payment ticket: {}", e)
+                })?;
+
+                debug!("attaching payment ticket with nonce: {}", ticket.nonce);
+                Some(("X-Payment-Ticket", ticket_json))
+            }
+            None => None,
+        };
+
+        self.send_request(Method::POST, body, provider_url, payment_header, turn)
+            .await
+    }
+
+    /// forward a request to the upstream RPC endpoint over TOR, paying with an x402 payment,
+    /// the channel's ticket `turn` is given back once the provider answered
+    pub async fn forward_request_with_x402_payment(
+        &self,
+        body: Bytes,
+        provider_url: String,
+        payment: &PaymentPayload,
+        turn: Option<TicketTurn>,
+    ) -> Result<Response<Body>> {
+        debug!(
+            "attaching x402 payment with nonce: {}",
+            payment.payload.payment_ticket().nonce
+        );
+        self.send_request(
+            Method::POST,
+            body,
+            provider_url,
+            Some((PAYMENT_HEADER, payment.to_header_value())),
+            turn,
+        )
+        .await
+    }
+
+    /// fetch a resource from a provider over TOR
+    pub async fn get(&self, url: String) -> Result<Response<Body>> {
+        self.send_request(Method::GET, Bytes::new(), url, None, None)
+            .await
+    }
+
+    /// send a request to a provider over TOR with an optional extra header, on a pooled
+    /// connection of its isolation group when there is one
+    ///
+    /// the ticket `turn` of a paid request is given back once the response headers arrive. The
+    /// provider only answers after it settled the ticket, and it may handle concurrent requests
+    /// in any order, even the streams of one HTTP/2 connection, so the next ticket of the
+    /// channel only goes out after this one was accepted or refused
+    async fn send_request(
+        &self,
+        method: Method,
+        body: Bytes,
+        provider_url: String,
+        payment_header: Option<(&'static str, String)>,
+        turn: Option<TicketTurn>,
     ) -> Result<Response<Body>> {
         debug!(
-            "forwarding request to {} ({} bytes)",
+            "sending {} request to {} ({} bytes)",
+            method,
             provider_url,
             body.len()
         );
 
         // Parse the upstream URL
         let uri: Uri = provider_url.parse()?;
-        let host = uri
-            .host()
-            .ok_or_else(|| anyhow::anyhow!("no RPC host in URL"))?;
-        let scheme = uri.scheme_str().unwrap_or("https");
-        let is_https = scheme == "https";
-
-        let port = match uri.port_u16() {
-            Some(p) => p,
-            _ if is_https => 443,
-            _ => 80,
+        let (host, port, is_https) = endpoint(&uri)?;
+        let origin = origin(host, port, is_https);
+        let isolation = self
+            .isolation
+            .isolate(&origin, self.local_client.as_deref());
+        // connections are only reused within an isolation group
+        let key = isolation.pool_key(&origin);
+
+        let request = |http2: bool| {
+            build_request(
+                method.clone(),
+                &origin,
+                host,
+                &uri,
+                body.clone(),
+                payment_header.clone(),
+                http2,
+            )
+        };
+        // hand the request to the connection, the future resolves to the response
+        let dispatch = |sender: &mut Sender| {
+            Ok::<_, anyhow::Error>(sender.send_request(request(sender.is_http2())?))
         };
-
-        debug!(
-            "connecting to {}:{} via TOR (https={})",
-            host, port, is_https
-        );
 
         // send the request with timeout
-        let response = tokio::time::timeout(self.timeout, async {
-            debug!("establishing Tor circuit to {}:{}", host, port);
-
-            // connect through TOR
-            let stream = self
-                .tor_manager
-                .client()
-                .connect((host, port))
-                .await
-                .map_err(|e| {
-                    error!("failed to connect through TOR: {}", e);
-                    anyhow::anyhow!("TOR connection failed: {}", e)
-                })?;
-
-            debug!("TOR circuit established");
-
-            // wrap with TLS if needed
-            if is_https {
-                debug!("initiating TLS handshake with {}", host);
-
-                let server_name = ServerName::try_from(host).map_err(|e| {
-                    error!("invalid DNS name '{}': {}", host, e);
-                    anyhow::anyhow!("invalid DNS name: {}", e)
-                })?;
+        let (response, sender) = tokio::time::timeout(self.timeout, async {
+            let pooled = match &key {
+                Some(key) => self.pool.checkout(key).await,
+                None => None,
+            };
+            if let Some(mut sender) = pooled {
+                match dispatch(&mut sender)?.await {
+                    Ok(response) => return Ok((response, sender)),
+                    // the provider closed the idle connection before the request was written,
+                    // a request that went out (even without an answer) is never sent again
+                    Err(e) if e.is_canceled() => debug!(
+                        "pooled connection to {} failed ({}), retrying on a new connection",
+                        origin, e
+                    ),
+                    Err(e) => {
+                        error!("failed to send request: {}", e);
+                        return Err(anyhow::anyhow!("failed to send request: {}", e));
+                    }
+                }
+            }
 
-                let tls_stream = self
-                    .tls_connector
-                    .connect(server_name, stream)
-                    .await
-                    .map_err(|e| {
-                        error!("TLS handshake failed: {}", e);
-                        error!("error details: {:?}", e);
-                        anyhow::anyhow!("TLS handshake failed: {}", e)
-                    })?;
-
-                debug!("TLS handshake successful");
-                self.make_request_with_ticket(host, &uri, body, payment_ticket, tls_stream)
-                    .await
-            } else {
-                self.make_request_with_ticket(host, &uri, body, payment_ticket, stream)
-                    .await
+            let mut sender = self.connect(&uri, &origin, isolation.token).await?;
+            // concurrent requests share an HTTP/2 connection as soon as it is up
+            if let (Sender::Http2(h2), Some(key)) = (&sender, &key) {
+                self.pool.share(key, h2.clone());
             }
+            debug!("sending HTTP request");
+            let response = dispatch(&mut sender)?.await.map_err(|e| {
+                error!("failed to send request: {}", e);
+                anyhow::anyhow!("failed to send request: {}", e)
+            })?;
+            Ok::<_, anyhow::Error>((response, sender))
         })
         .await
         .map_err(|_| {
             error!("request timeout after {:?}", self.timeout);
             anyhow::anyhow!("request timeout after {:?}", self.timeout)
         })??;
+        // the provider settled the ticket, the channel's next one may go out
+        drop(turn);
 
         debug!("received response: status={}", response.status());
 
-        Ok(response)
+        // the connection is reused once the response body was read
+        let (parts, body) = response.into_parts();
+        let body = match key {
+            Some(key) => self.pool.return_on_end(key, sender, body),
+            None => body,
+        };
+        Ok(Response::from_parts(parts, body))
     }
 
-    /// make an HTTP request over an established stream with optional payment ticket
-    async fn make_request_with_ticket<S>(
+    /// open a WebSocket to a provider over TOR, the provider's http(s) URL is upgraded at the
+    /// same path
+    pub async fn connect_websocket(
         &self,
-        host: &str,
-        uri: &Uri,
-        body: Bytes,
-        payment_ticket: Option<&crate::hpc_service::PaymentTicket>,
-        stream: S,
-    ) -> Result<Response<Body>>
+        provider_url: &str,
+    ) -> Result<WebSocketStream<Box<dyn TunnelStream>>> {
+        let uri: Uri = provider_url.parse()?;
+        let (host, port, is_https) = endpoint(&uri)?;
+        let isolation = self
+            .isolation
+            .isolate(&origin(host, port, is_https), self.local_client.as_deref());
+        let ws_url = websocket_url(provider_url);
+        debug!("opening websocket to {}", ws_url);
+
+        tokio::time::timeout(self.timeout, async {
+            let (stream, _) = self.open_stream(&uri, false, isolation.token).await?;
+            let (websocket, _) = tokio_tungstenite::client_async(ws_url.as_str(), stream)
+                .await
+                .map_err(|e| {
+                    error!("websocket handshake failed: {}", e);
+                    anyhow::anyhow!("websocket handshake failed: {}", e)
+                })?;
+            debug!("websocket handshake successful");
+            Ok(websocket)
+        })
+        .await
+        .map_err(|_| {
+            error!("websocket connect timeout after {:?}", self.timeout);
+            anyhow::anyhow!("websocket connect timeout after {:?}", self.timeout)
+        })?
+    }
+
+    /// open a new connection to a provider, HTTP/2 when the provider agreed to it over ALPN or
+    /// it is an onion service not known to speak HTTP/1 only
+    ///
+    /// an onion service spoken to with prior knowledge must answer the HTTP/2 preface with its
+    /// SETTINGS before the connection is used, one that does not is remembered as HTTP/1 only
+    /// and a new HTTP/1 connection is opened, so no request is ever sent twice
+    async fn connect(&self, uri: &Uri, origin: &str, isolation: IsolationToken) -> Result<Sender> {
+        self.connect_with(uri, origin, |offer_http2| {
+            self.open_stream(uri, offer_http2, isolation)
+        })
+        .await
+    }
+
+    /// `connect` over the streams `open` opens, offering HTTP/2 or not
+    async fn connect_with<F, Fut>(&self, uri: &Uri, origin: &str, open: F) -> Result<Sender>
     where
-        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        F: Fn(bool) -> Fut,
+        Fut: Future<Output = Result<(Box<dyn TunnelStream>, bool)>>,
     {
-        debug!("performing HTTP handshake");
-
-        // perform HTTP handshake
-        let (mut request_sender, connection) = hyper::client::conn::http1::handshake(stream)
-            .await
-            .map_err(|e| {
-                error!("HTTP handshake failed: {}", e);
-                anyhow::anyhow!("HTTP handshake failed: {}", e)
-            })?;
+        let (host, _, is_https) = endpoint(uri)?;
+        let prior_knowledge =
+            self.http2 && !is_https && host.ends_with(".onion") && !self.pool.is_http1_only(origin);
 
-        // spawn a task to poll the connection
-        tokio::spawn(async move {
-            if let Err(e) = connection.await {
-                error!("HTTP connection error: {}", e);
+        let (mut stream, http2) = open(self.http2 && (is_https || prior_knowledge)).await?;
+        if http2 && !prior_knowledge {
+            return Ok(Sender::Http2(handshake_http2(stream).await?));
+        }
+        if http2 {
+            let (watched, preface) = PrefaceWatch::new(stream);
+            let sender = handshake_http2(Box::new(watched)).await?;
+            if preface.await.unwrap_or(false) {
+                return Ok(Sender::Http2(sender));
             }
-        });
-
-        debug!("building HTTP request");
+            info!(
+                "{} did not answer the HTTP/2 preface, falling back to HTTP/1",
+                origin
+            );
+            self.pool.mark_http1_only(origin);
+            (stream, _) = open(false).await?;
+        }
+        Ok(Sender::Http1(handshake(stream).await?))
+    }
 
-        // build the request
-        let path = uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
-        let mut request_builder = Request::builder()
-            .method("POST")
-            .uri(path)
-            .header("Host", host)
-            .header("content-type", "application/json")
-            .header("user-agent", "tor-provider/1.0");
+    /// connect to a provider through TOR on a circuit of the isolation group, wrapped in TLS for
+    /// https URLs, and whether HTTP/2 is spoken on it, agreed over ALPN for https or as offered
+    /// for plain http
+    async fn open_stream(
+        &self,
+        uri: &Uri,
+        offer_http2: bool,
+        isolation: IsolationToken,
+    ) -> Result<(Box<dyn TunnelStream>, bool)> {
+        let (host, port, is_https) = endpoint(uri)?;
 
-        // add payment ticket header if provided
-        if let Some(ticket) = payment_ticket {
-            let ticket_json = serde_json::to_string(ticket).map_err(|e| {
-                error!("failed to serialize payment ticket: {}", e);
-                anyhow::anyhow!("Failed to serialize payment ticket: {}", e)
+        debug!(
+            "connecting to {}:{} via TOR (https={})",
+            host, port, is_https
+        );
+        debug!("establishing Tor circuit to {}:{}", host, port);
+
+        // connect through TOR
+        let mut prefs = StreamPrefs::new();
+        prefs.set_isolation(isolation);
+        let stream = self
+            .tor_manager
+            .client()
+            .connect_with_prefs((host, port), &prefs)
+            .await
+            .map_err(|e| {
+                error!("failed to connect through TOR: {}", e);
+                anyhow::anyhow!("TOR connection failed: {}", e)
             })?;
 
-            debug!("attaching payment ticket with nonce: {}", ticket.nonce);
-            request_builder = request_builder.header("X-Payment-Ticket", ticket_json);
-        }
+        debug!("TOR circuit established");
 
-        let request = request_builder.body(Body::from(body)).map_err(|e| {
-            error!("failed to build request: {}", e);
-            anyhow::anyhow!("failed to build request: {}", e)
-        })?;
-
-        debug!("sending HTTP request");
+        // wrap with TLS if needed
+        if !is_https {
+            return Ok((Box::new(stream), offer_http2));
+        }
+        debug!("initiating TLS handshake with {}", host);
 
-        // send the request
-        let response = request_sender.send_request(request).await.map_err(|e| {
-            error!("failed to send request: {}", e);
-            anyhow::anyhow!("failed to send request: {}", e)
+        let server_name = ServerName::try_from(host).map_err(|e| {
+            error!("invalid DNS name '{}': {}", host, e);
+            anyhow::anyhow!("invalid DNS name: {}", e)
         })?;
 
-        debug!("HTTP request completed successfully");
+        let tls_connector = if offer_http2 {
+            &self.h2_tls_connector
+        } else {
+            &self.tls_connector
+        };
+        let tls_stream = tls_connector
+            .connect(server_name, stream)
+            .await
+            .map_err(|e| {
+                error!("TLS handshake failed: {}", e);
+                error!("error details: {:?}", e);
+                anyhow::anyhow!("TLS handshake failed: {}", e)
+            })?;
 
-        Ok(response)
+        let http2 = tls_stream.get_ref().1.alpn_protocol() == Some(b"h2");
+        debug!("TLS handshake successful (http2={})", http2);
+        Ok((Box::new(tls_stream), http2))
     }
 
     /// convert a hyper response body to bytes
@@ -234,3 +439,283 @@ impl ProxyTorClient {
         Ok((response_without_body, bytes))
     }
 }
+
+/// host, port and whether TLS is used for a provider URL
+fn endpoint(uri: &Uri) -> Result<(&str, u16, bool)> {
+    let host = uri
+        .host()
+        .ok_or_else(|| anyhow::anyhow!("no RPC host in URL"))?;
+    let is_https = matches!(uri.scheme_str(), Some("https") | Some("wss") | None);
+
+    let port = match uri.port_u16() {
+        Some(p) => p,
+        _ if is_https => 443,
+        _ => 80,
+    };
+    Ok((host, port, is_https))
+}
+
+/// origin of a provider (`scheme://host:port`)
+fn origin(host: &str, port: u16, is_https: bool) -> String {
+    format!(
+        "{}://{}:{}",
+        if is_https { "https" } else { "http" },
+        host,
+        port
+    )
+}
+
+/// perform the HTTP handshake over an established stream
+async fn handshake(stream: Box<dyn TunnelStream>) -> Result<http1::SendRequest<Body>> {
+    debug!("performing HTTP handshake");
+
+    let (request_sender, connection) = http1::handshake(stream).await.map_err(|e| {
+        error!("HTTP handshake failed: {}", e);
+        anyhow::anyhow!("HTTP handshake failed: {}", e)
+    })?;
+
+    // spawn a task to poll the connection
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            error!("HTTP connection error: {}", e);
+        }
+    });
+
+    Ok(request_sender)
+}
+
+/// perform the HTTP/2 handshake over an established stream
+async fn handshake_http2(stream: Box<dyn TunnelStream>) -> Result<http2::SendRequest<Body>> {
+    debug!("performing HTTP/2 handshake");
+
+    let (request_sender, connection) =
+        http2::handshake(TokioExecutor, stream).await.map_err(|e| {
+            error!("HTTP/2 handshake failed: {}", e);
+            anyhow::anyhow!("HTTP/2 handshake failed: {}", e)
+        })?;
+
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            error!("HTTP/2 connection error: {}", e);
+        }
+    });
+
+    Ok(request_sender)
+}
+
+/// length of an HTTP/2 frame header
+const FRAME_HEADER_LEN: usize = 9;
+
+/// type of the HTTP/2 SETTINGS frame, the first frame of every HTTP/2 server
+const SETTINGS_FRAME: u8 = 0x4;
+
+/// a stream telling whether the first frame a provider sends is an HTTP/2 SETTINGS frame, the
+/// server side of the HTTP/2 preface
+struct PrefaceWatch<S> {
+    stream: S,
+    // first bytes read, up to a frame header
+    header: Vec<u8>,
+    preface: Option<oneshot::Sender<bool>>,
+}
+
+impl<S> PrefaceWatch<S> {
+    fn new(stream: S) -> (Self, oneshot::Receiver<bool>) {
+        let (preface, received) = oneshot::channel();
+        let watch = Self {
+            stream,
+            header: Vec::with_capacity(FRAME_HEADER_LEN),
+            preface: Some(preface),
+        };
+        (watch, received)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PrefaceWatch<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut this.stream).poll_read(cx, buf);
+        if let Poll::Ready(read) = &result
+            && this.preface.is_some()
+        {
+            let bytes = &buf.filled()[filled..];
+            let missing = FRAME_HEADER_LEN - this.header.len();
+            this.header
+                .extend_from_slice(&bytes[..bytes.len().min(missing)]);
+            // the header is complete, or the provider closed or failed the stream first
+            if read.is_err() || bytes.is_empty() || this.header.len() == FRAME_HEADER_LEN {
+                let settings =
+                    this.header.len() == FRAME_HEADER_LEN && this.header[3] == SETTINGS_FRAME;
+                if let Some(preface) = this.preface.take() {
+                    let _ = preface.send(settings);
+                }
+            }
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PrefaceWatch<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// runs the streams of HTTP/2 connections on tokio
+#[derive(Clone, Copy)]
+struct TokioExecutor;
+
+impl<F> hyper::rt::Executor<F> for TokioExecutor
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    fn execute(&self, future: F) {
+        tokio::spawn(future);
+    }
+}
+
+/// build an HTTP request to a provider with an optional payment header, HTTP/2 requests carry
+/// the provider's origin (`scheme://host:port`) in the URI instead of a Host header
+fn build_request(
+    method: Method,
+    origin: &str,
+    host: &str,
+    uri: &Uri,
+    body: Bytes,
+    payment_header: Option<(&'static str, String)>,
+    http2: bool,
+) -> Result<Request<Body>> {
+    debug!("building HTTP request");
+
+    let path = uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+    let mut request_builder = if http2 {
+        Request::builder().uri(format!("{}{}", origin, path))
+    } else {
+        Request::builder().uri(path).header("Host", host)
+    };
+    request_builder = request_builder
+        .method(method.clone())
+        .header("user-agent", "tor-provider/1.0");
+    if method == Method::POST {
+        request_builder = request_builder.header("content-type", "application/json");
+    }
+
+    // add payment header if provided
+    if let Some((name, value)) = payment_header {
+        request_builder = request_builder.header(name, value);
+    }
+
+    request_builder.body(Body::from(body)).map_err(|e| {
+        error!("failed to build request: {}", e);
+        anyhow::anyhow!("failed to build request: {}", e)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// what a `PrefaceWatch` makes of the first bytes a provider sends
+    async fn preface(answer: &'static [u8]) -> bool {
+        let (client, mut provider) = tokio::io::duplex(64);
+        let (mut watch, preface) = PrefaceWatch::new(client);
+        tokio::spawn(async move {
+            provider.write_all(answer).await.unwrap();
+        });
+        let mut buf = [0u8; 64];
+        while watch.read(&mut buf).await.unwrap() > 0 {}
+        preface.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn preface_watch_tells_http2_from_http1() {
+        // empty SETTINGS frame
+        assert!(preface(&[0, 0, 0, 4, 0, 0, 0, 0, 0]).await);
+        assert!(!preface(b"HTTP/1.1 400 Bad Request\r\n\r\n").await);
+        assert!(!preface(b"").await);
+    }
+
+    #[tokio::test]
+    async fn dials_http1_only_providers_without_prior_knowledge() {
+        let state_dir = std::env::temp_dir().join(format!("tor-client-{}", std::process::id()));
+        let tor_manager = Arc::new(TorClientManager::unbootstrapped(&state_dir).unwrap());
+        let pool = ConnectionPool::new(4, Duration::from_secs(60));
+        let client = ProxyTorClient::new(tor_manager, Duration::from_secs(5))
+            .unwrap()
+            .with_http2(true)
+            .with_pool(pool.clone());
+
+        let uri: Uri = "http://provider.onion/".parse().unwrap();
+        let origin = "http://provider.onion:80";
+        // streams to an HTTP/1 provider, and whether each one offered HTTP/2
+        let offers = Arc::new(Mutex::new(Vec::new()));
+        let open = |offer_http2: bool| {
+            offers.lock().push(offer_http2);
+            async move {
+                let (client, mut provider) = tokio::io::duplex(1024);
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    // an HTTP/1 server answers the HTTP/2 preface like a malformed request
+                    if provider.read(&mut buf).await.is_ok_and(|n| n > 0) {
+                        let _ = provider
+                            .write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")
+                            .await;
+                    }
+                    while provider.read(&mut buf).await.is_ok_and(|n| n > 0) {}
+                });
+                Ok((Box::new(client) as Box<dyn TunnelStream>, offer_http2))
+            }
+        };
+
+        // the first connection falls back to HTTP/1 on a new stream
+        let sender = client.connect_with(&uri, origin, &open).await.unwrap();
+        assert!(!sender.is_http2());
+        assert_eq!(*offers.lock(), [true, false]);
+
+        // the next one is dialed without prior knowledge
+        let sender = client.connect_with(&uri, origin, &open).await.unwrap();
+        assert!(!sender.is_http2());
+        assert_eq!(*offers.lock(), [true, false, false]);
+
+        // and pooled as HTTP/1, handed to one request at a time
+        let body = pool.return_on_end(origin.to_string(), sender, Body::empty());
+        hyper::body::to_bytes(body).await.unwrap();
+        assert!(matches!(
+            pool.checkout(origin).await,
+            Some(Sender::Http1(_))
+        ));
+        assert!(pool.checkout(origin).await.is_none());
+        let _ = std::fs::remove_dir_all(&state_dir);
+    }
+}
@@ -14,7 +14,7 @@ pub enum JsonRpcErrorCode {
 }
 
 /// JSON-RPC 2.0 error response
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct JsonRpcError {
     pub code: i32,
     pub message: String,
@@ -93,7 +93,6 @@ impl JsonRpcErrorResponse {
     }
 
     /// create an error response for body size limit exceeded
-    #[allow(dead_code)]
     pub fn body_too_large(limit: usize) -> Self {
         Self::new(JsonRpcError::server_error_with_data(
             "Request body too large",
@@ -101,6 +100,14 @@ impl JsonRpcErrorResponse {
         ))
     }
 
+    /// create an error response for an upstream response over the size limit
+    pub fn response_too_large(limit: usize) -> Self {
+        Self::new(JsonRpcError::server_error_with_data(
+            "Response body too large",
+            json!({ "max_bytes": limit }),
+        ))
+    }
+
     /// create an error response for invalid JSON
     pub fn parse_error(details: impl Into<String>) -> Self {
         Self::new(JsonRpcError::with_data(
@@ -110,6 +117,32 @@ impl JsonRpcErrorResponse {
         ))
     }
 
+    /// serialize as the reply to `request`, a batch gets one error per call carrying the call's id
+    pub fn to_json_bytes_for(&self, request: Option<&RpcRequest>) -> Vec<u8> {
+        let Some(RpcRequest::Batch(calls)) = request else {
+            let id = request
+                .and_then(|r| r.calls().first())
+                .and_then(|c| c.id.clone());
+            return match (&self.id, id) {
+                (None, Some(id)) => Self::with_id(self.error.clone(), id).to_json_bytes(),
+                _ => self.to_json_bytes(),
+            };
+        };
+
+        // notifications (calls without an id) do not get a reply
+        let errors: Vec<Self> = calls
+            .iter()
+            .filter_map(|call| call.id.clone())
+            .map(|id| Self::with_id(self.error.clone(), id))
+            .collect();
+
+        if errors.is_empty() {
+            return self.to_json_bytes();
+        }
+
+        serde_json::to_vec(&errors).unwrap_or_else(|_| self.to_json_bytes())
+    }
+
     /// serialize to JSON bytes
     pub fn to_json_bytes(&self) -> Vec<u8> {
         serde_json::to_vec(self).unwrap_or_else(|_| {
@@ -119,30 +152,72 @@ impl JsonRpcErrorResponse {
     }
 }
 
-/// try to extract the "id" field from a JSON-RPC request body
-/// this is best-effort and returns None if parsing fails
-pub fn extract_request_id(body: &[u8]) -> Option<serde_json::Value> {
-    #[derive(Deserialize)]
-    struct RequestId {
-        id: Option<serde_json::Value>,
+/// a single call of a JSON-RPC request, parsed best-effort
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RpcCall {
+    // `None` only for notifications, which have no `id` member, `"id": null` is kept as
+    // `Some(Value::Null)`
+    #[serde(default, deserialize_with = "present")]
+    pub id: Option<serde_json::Value>,
+    #[serde(default)]
+    pub method: Option<String>,
+    #[serde(default)]
+    pub params: Option<serde_json::Value>,
+}
+
+/// a JSON-RPC request body, either a single call or a batch of calls
+#[derive(Debug, Clone)]
+pub enum RpcRequest {
+    Single(RpcCall),
+    Batch(Vec<RpcCall>),
+}
+
+impl RpcRequest {
+    /// all calls in the request
+    pub fn calls(&self) -> &[RpcCall] {
+        match self {
+            Self::Single(call) => std::slice::from_ref(call),
+            Self::Batch(calls) => calls,
+        }
     }
 
-    serde_json::from_slice::<RequestId>(body)
-        .ok()
-        .and_then(|r| r.id)
+    /// whether the request is a batch
+    pub fn is_batch(&self) -> bool {
+        matches!(self, Self::Batch(_))
+    }
+
+    /// the methods of all calls, for logging
+    pub fn methods(&self) -> Vec<&str> {
+        self.calls()
+            .iter()
+            .map(|call| call.method.as_deref().unwrap_or("?"))
+            .collect()
+    }
 }
 
-/// try to extract the "method" field from a JSON-RPC request body
-/// this is best-effort and returns None if parsing fails
-pub fn extract_request_method(body: &[u8]) -> Option<String> {
-    #[derive(Deserialize)]
-    struct RequestMethod {
-        method: Option<String>,
+/// parse a JSON-RPC request body, single or batch
+/// this is best-effort and returns None if the body is not a JSON object or array, batch
+/// entries that are not valid calls are kept as empty calls so they are still counted
+pub fn parse_request(body: &[u8]) -> Option<RpcRequest> {
+    match serde_json::from_slice::<serde_json::Value>(body).ok()? {
+        serde_json::Value::Array(entries) => Some(RpcRequest::Batch(
+            entries
+                .into_iter()
+                .map(|entry| serde_json::from_value(entry).unwrap_or_default())
+                .collect(),
+        )),
+        entry @ serde_json::Value::Object(_) => {
+            serde_json::from_value(entry).ok().map(RpcRequest::Single)
+        }
+        _ => None,
     }
+}
 
-    serde_json::from_slice::<RequestMethod>(body)
-        .ok()
-        .and_then(|r| r.method)
+/// a member that is present, even as `null`
+fn present<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<serde_json::Value>, D::Error> {
+    serde_json::Value::deserialize(deserializer).map(Some)
 }
 
 /// try to extract the "params" field from a JSON-RPC request body (truncated)
@@ -165,3 +240,50 @@ pub fn extract_request_params(body: &[u8], max_len: usize) -> Option<String> {
             }
         })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_single_and_batch_requests() {
+        let single = parse_request(br#"{"jsonrpc":"2.0","id":1,"method":"eth_call"}"#).unwrap();
+        assert!(!single.is_batch());
+        assert_eq!(single.methods(), vec!["eth_call"]);
+
+        let batch = parse_request(
+            br#"[{"jsonrpc":"2.0","id":1,"method":"eth_chainId"},42,{"jsonrpc":"2.0","method":"eth_blockNumber"}]"#,
+        )
+        .unwrap();
+        assert!(batch.is_batch());
+        assert_eq!(batch.methods(), vec!["eth_chainId", "?", "eth_blockNumber"]);
+
+        assert!(parse_request(b"\"eth_call\"").is_none());
+        assert!(parse_request(b"not json").is_none());
+    }
+
+    #[test]
+    fn only_calls_without_an_id_are_notifications() {
+        let request = parse_request(
+            br#"[{"jsonrpc":"2.0","id":null,"method":"eth_call"},{"jsonrpc":"2.0","method":"eth_call"},{"jsonrpc":"2.0","id":"a","method":"eth_call"}]"#,
+        )
+        .unwrap();
+        let ids: Vec<_> = request.calls().iter().map(|call| call.id.clone()).collect();
+        assert_eq!(
+            ids,
+            vec![Some(serde_json::Value::Null), None, Some(json!("a"))]
+        );
+
+        // the notification gets no reply
+        let error = JsonRpcErrorResponse::new(JsonRpcError::server_error("Payment required"));
+        let replies: serde_json::Value =
+            serde_json::from_slice(&error.to_json_bytes_for(Some(&request))).unwrap();
+        let reply_ids: Vec<_> = replies
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|reply| reply["id"].clone())
+            .collect();
+        assert_eq!(reply_ids, vec![serde_json::Value::Null, json!("a")]);
+    }
+}
@@ -1,22 +1,34 @@
 use crate::{
-    hpc_service::HpcClient,
-    payment_middleware::PaymentMiddlewareState,
+    body_limit::{self, BodyLimits},
+    channel_registry::ChannelRegistry,
+    free_tier::FreeTierPolicy,
+    lottery::LotteryPolicy,
+    payment_middleware::{MessageRejection, PaymentMiddlewareState},
+    payment_receipt::{RECEIPT_KEY_PATH, ReceiptSigner},
+    postpaid::PostpaidPolicy,
     proxy_local_client::ProxyLocalClient,
-    rpc_utils::{self, JsonRpcErrorResponse},
+    rpc_utils::{self, JsonRpcErrorResponse, RpcRequest},
+    ticket_ledger::TicketLedger,
+    websocket::{self, HostFrame, PAYMENT_REQUIRED_CLOSE_REASON, UserFrame},
 };
 use axum::{
     Router,
     body::Body,
-    extract::{Request, State},
+    extract::{
+        Query, Request, State,
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade, close_code},
+    },
     http::{Response, StatusCode},
     response::IntoResponse,
-    routing::post,
+    routing::{get, post},
 };
-use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt, stream::SplitSink};
+use serde::Deserialize;
 use tokio::sync::watch;
+use tokio_tungstenite::tungstenite::Message as UpstreamMessage;
 use tower::ServiceBuilder;
 use tower_http::trace::{DefaultOnResponse, TraceLayer};
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
 
 /// types of errors that can occur
 #[derive(Debug, Clone, Copy)]
@@ -32,33 +44,64 @@ pub struct AppState {
     pub local_client: ProxyLocalClient,
     pub validate_tickets: bool,
     pub nimbus_rpc_url: String,
+    // subscriptions and other WebSocket traffic is proxied here
+    pub nimbus_ws_url: String,
     pub ready_rx: watch::Receiver<bool>,
-    pub hpc_client: HpcClient,
+    pub channels: ChannelRegistry,
+    pub ledger: TicketLedger,
+    pub lottery: Option<LotteryPolicy>,
+    pub free_tier: Option<FreeTierPolicy>,
+    pub postpaid: Option<PostpaidPolicy>,
+    pub network: String,
+    pub receipt_signer: ReceiptSigner,
+    // caps on request and response bodies
+    pub body_limits: BodyLimits,
+}
+
+impl AppState {
+    /// state of the payment checks
+    fn payment_state(&self) -> PaymentMiddlewareState {
+        PaymentMiddlewareState {
+            channels: self.channels.clone(),
+            ledger: self.ledger.clone(),
+            lottery: self.lottery.clone(),
+            free_tier: self.free_tier.clone(),
+            postpaid: self.postpaid.clone(),
+            network: self.network.clone(),
+            receipt_signer: self.receipt_signer.clone(),
+            body_limits: self.body_limits,
+        }
+    }
 }
 
 /// create the axum router with all routes and middleware
 pub fn create_router(state: AppState) -> Router {
     let mut router = Router::new();
 
-    // Main RPC endpoint - with payment middleware in host mode if payments enabled
+    // Main RPC endpoint - with payment middleware in host mode if payments enabled, WebSocket
+    // sessions check the ticket of every message themselves
     if state.validate_tickets {
         info!("validate tickets enabled");
 
-        let payment_state = PaymentMiddlewareState {
-            hpc_client: state.hpc_client.clone(),
-        };
-
         router = router.route(
             "/",
-            post(rpc_handler).layer(axum::middleware::from_fn_with_state(
-                payment_state,
-                crate::payment_middleware::payment_verification_middleware,
-            )),
+            post(rpc_handler)
+                .layer(axum::middleware::from_fn_with_state(
+                    state.payment_state(),
+                    crate::payment_middleware::payment_verification_middleware,
+                ))
+                .get(ws_handler),
         );
     } else {
-        router = router.route("/", post(rpc_handler));
+        router = router.route("/", post(rpc_handler).get(ws_handler));
     }
 
+    // advertise the pricing table so users know what they will pay
+    router = router.route("/pricing", get(pricing_handler));
+
+    // publish the key payment receipts are signed with
+    router = router.route(RECEIPT_KEY_PATH, get(receipt_key_handler));
+
     // request tracing
     router
         .layer(
@@ -67,11 +110,43 @@ pub fn create_router(state: AppState) -> Router {
                 .layer(
                     TraceLayer::new_for_http()
                         .on_response(DefaultOnResponse::new().level(tracing::Level::INFO)),
-                ),
+                )
+                // request size cap, answered with a JSON-RPC error
+                .layer(axum::middleware::map_response_with_state(
+                    state.body_limits.request,
+                    body_limit::json_rpc_too_large,
+                ))
+                .layer(state.body_limits.request_layer()),
         )
         .with_state(state)
 }
 
+/// pricing query, `contract` selects a channel, the configured one by default
+#[derive(Deserialize)]
+struct PricingQuery {
+    contract: Option<String>,
+}
+
+/// pricing handler - returns the per-method pricing table of a channel
+async fn pricing_handler(
+    State(state): State<AppState>,
+    Query(query): Query<PricingQuery>,
+) -> Response<Body> {
+    let channel = match &query.contract {
+        Some(contract_address) => state.channels.channel(contract_address),
+        None => Some(state.channels.default_channel()),
+    };
+    match channel {
+        Some(channel) => axum::Json(channel.pricing.as_ref().clone()).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// receipt key handler - returns the public key payment receipts are signed with
+async fn receipt_key_handler(State(state): State<AppState>) -> impl IntoResponse {
+    axum::Json(state.receipt_signer.receipt_key())
+}
+
 /// main RPC handler - forwards JSON-RPC requests from TOR to Numbus
 async fn rpc_handler(State(state): State<AppState>, request: Request) -> impl IntoResponse {
     let start_time = std::time::Instant::now();
@@ -82,13 +157,21 @@ async fn rpc_handler(State(state): State<AppState>, request: Request) -> impl In
     let headers = parts.headers;
 
     // read the body
-    let body = match axum::body::to_bytes(body, usize::MAX).await {
+    let body = match axum::body::to_bytes(body, state.body_limits.request).await {
         Ok(b) => b,
+        Err(e) if body_limit::is_too_large(&e) => {
+            warn!(
+                "request body is over the {} byte limit",
+                state.body_limits.request
+            );
+            return body_limit::body_too_large_response(state.body_limits.request);
+        }
         Err(e) => {
             error!("Failed to read request body: {}", e);
             return create_error_response(
                 StatusCode::BAD_REQUEST,
                 JsonRpcErrorResponse::parse_error(format!("Failed to read request body: {}", e)),
+                None,
             );
         }
     };
@@ -104,30 +187,32 @@ async fn rpc_handler(State(state): State<AppState>, request: Request) -> impl In
         return create_error_response(
             StatusCode::BAD_REQUEST,
             JsonRpcErrorResponse::parse_error("Content-Type must be application/json"),
+            None,
         );
     }
 
-    // extract request details for logging
-    let request_id = rpc_utils::extract_request_id(&body);
-    let method = rpc_utils::extract_request_method(&body);
+    // extract request details for logging, the parsed request also keeps the ids of
+    // batched calls so error replies can be matched up by the wallet
+    let rpc_request = rpc_utils::parse_request(&body);
+    let rpc_request = rpc_request.as_ref();
+    let methods = rpc_request.map(|r| r.methods());
+    let request_ids: Option<Vec<_>> =
+        rpc_request.map(|r| r.calls().iter().map(|c| c.id.clone()).collect());
     // let params: Option<String> = rpc_utils::extract_request_params(&body, 100);
 
     info!(
-        "received RPC request: {} bytes, method={:?}, id={:?}",
+        "received RPC request: {} bytes, batch={}, methods={:?}, ids={:?}",
         body.len(),
-        method,
-        request_id
+        rpc_request.is_some_and(|r| r.is_batch()),
+        methods,
+        request_ids
     );
 
     // forward the request to Nimbus
-    let response = match state
+    let response = state
         .local_client
         .forward_request(body.clone(), state.nimbus_rpc_url)
-        .await
-    {
-        Ok(resp) => Ok(resp),
-        Err(e) => Err(e),
-    };
+        .await;
 
     let response = match response {
         Ok(resp) => resp,
@@ -148,54 +233,42 @@ async fn rpc_handler(State(state): State<AppState>, request: Request) -> impl In
                 JsonRpcErrorResponse::connection_error(e.to_string())
             };
 
-            // If we have a request ID, include it in the error
-            let error_response = if let Some(id) = request_id {
-                JsonRpcErrorResponse::with_id(error_response.error, id)
-            } else {
-                error_response
-            };
-
-            return create_error_response(StatusCode::BAD_GATEWAY, error_response);
+            return create_error_response(StatusCode::BAD_GATEWAY, error_response, rpc_request);
         }
     };
 
-    // convert the response to bytes
-    let (response_parts, response_bytes) = match response_to_bytes(response).await {
-        Ok(r) => r,
-        Err(e) => {
-            // let duration_ms = start_time.elapsed().as_millis() as u64;
-            error!("Failed to read response body: {}", e);
-
-            let error_response = JsonRpcErrorResponse::connection_error(e.to_string());
-            let error_response = if let Some(id) = request_id {
-                JsonRpcErrorResponse::with_id(error_response.error, id)
-            } else {
-                error_response
-            };
-            return create_error_response(StatusCode::BAD_GATEWAY, error_response);
+    // stream the response body through, up to the response size cap
+    let (response_parts, response_body) = response.into_parts();
+    let response_body = match state
+        .body_limits
+        .stream_response(&response_parts.headers, response_body)
+    {
+        Ok(body) => body,
+        Err(error_response) => {
+            return create_error_response(StatusCode::BAD_GATEWAY, error_response, rpc_request);
         }
     };
 
     let duration_ms = start_time.elapsed().as_millis() as u64;
-    let status_code = response_parts.status().as_u16();
+    let status_code = response_parts.status.as_u16();
     // let is_success = status_code >= 200 && status_code < 300;
 
     info!(
-        "forwarded response: status={}, {} bytes, duration={}ms",
+        "forwarding response: status={}, content-length={:?}, duration={}ms",
         status_code,
-        response_bytes.len(),
+        response_parts.headers.get(hyper::header::CONTENT_LENGTH),
         duration_ms
     );
 
     // build the response with the upstream status and headers
     // hack: we need to map the status code as axum uses http 1.x and our client uses hyper 0.14
-    let status = StatusCode::from_u16(response_parts.status().as_u16())
+    let status = StatusCode::from_u16(response_parts.status.as_u16())
         .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
 
     let mut response_builder = Response::builder().status(status);
 
     // copy relevant headers from upstream
-    for (key, value) in response_parts.headers() {
+    for (key, value) in &response_parts.headers {
         let key_str = key.as_str();
         // forward most headers but skip hop-by-hop headers
         if !matches!(
@@ -209,37 +282,200 @@ async fn rpc_handler(State(state): State<AppState>, request: Request) -> impl In
         }
     }
 
-    response_builder
-        .body(Body::from(response_bytes))
-        .unwrap_or_else(|e| {
-            error!("Failed to build response: {}", e);
-            create_error_response(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                JsonRpcErrorResponse::new(rpc_utils::JsonRpcError::server_error("Internal error")),
-            )
+    response_builder.body(response_body).unwrap_or_else(|e| {
+        error!("Failed to build response: {}", e);
+        create_error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            JsonRpcErrorResponse::new(rpc_utils::JsonRpcError::server_error("Internal error")),
+            rpc_request,
+        )
+    })
+}
+
+/// WebSocket handler - tunnels JSON-RPC messages and subscription notifications to Nimbus
+async fn ws_handler(State(state): State<AppState>, ws: WebSocketUpgrade) -> impl IntoResponse {
+    ws.max_message_size(state.body_limits.request)
+        .on_upgrade(move |socket| async move {
+            if let Err(e) = ws_session(state, socket).await {
+                warn!("websocket session ended: {}", e);
+            }
         })
 }
 
-/// helper to create a JSON-RPC error response
-fn create_error_response(status: StatusCode, error: JsonRpcErrorResponse) -> Response<Body> {
+/// proxy a WebSocket session to Nimbus
+///
+/// with ticket validation every message must carry a ticket paying for it, and every
+/// subscription notification is billed as debt of the channel that paid last. The session is
+/// closed once a notification finds the channel's debt above the credit limit
+async fn ws_session(state: AppState, socket: WebSocket) -> anyhow::Result<()> {
+    let (upstream, _) = tokio_tungstenite::connect_async(state.nimbus_ws_url.as_str()).await?;
+    info!("websocket session opened to {}", state.nimbus_ws_url);
+    let (mut upstream_tx, mut upstream_rx) = upstream.split();
+    let (mut tx, mut rx) = socket.split();
+
+    let payments = state.validate_tickets.then(|| state.payment_state());
+    // channel notifications are billed to, the one of the last accepted ticket
+    let mut contract_address: Option<String> = None;
+
+    loop {
+        tokio::select! {
+            frame = rx.next() => {
+                let text = match frame {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+                let frame: UserFrame = match serde_json::from_str(&text) {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        warn!("invalid websocket frame: {}", e);
+                        let error = JsonRpcErrorResponse::parse_error("Invalid frame");
+                        send_host_frame(&mut tx, HostFrame {
+                            message: serde_json::from_slice(&error.to_json_bytes_for(None)).ok(),
+                            ..HostFrame::default()
+                        })
+                        .await?;
+                        continue;
+                    }
+                };
+                let body = frame
+                    .message
+                    .as_ref()
+                    .map(|message| message.to_string())
+                    .unwrap_or_default();
+                let rpc_request = rpc_utils::parse_request(body.as_bytes());
+
+                if let Some(payments) = &payments {
+                    let paid = match &frame.ticket {
+                        Some(ticket) => payments
+                            .accept_message_ticket(ticket, rpc_request.as_ref())
+                            .await
+                            .map(|payment| (ticket, payment)),
+                        None => Err(MessageRejection {
+                            message: "Payment required. Please provide a valid payment ticket."
+                                .to_string(),
+                            requirements: None,
+                        }),
+                    };
+                    match paid {
+                        Ok((ticket, payment)) => {
+                            debug!(
+                                "websocket ticket with nonce {} accepted, {} wei owed",
+                                ticket.nonce, payment.debt
+                            );
+                            contract_address =
+                                Some(ticket.hidden_payment_channels_contract_address.clone());
+                            send_host_frame(&mut tx, HostFrame {
+                                debt: Some(payment.debt.to_string()),
+                                receipt: payment.receipt,
+                                ..HostFrame::default()
+                            })
+                            .await?;
+                        }
+                        Err(rejection) => {
+                            warn!("websocket message refused: {}", rejection.message);
+                            let error = JsonRpcErrorResponse::new(rpc_utils::JsonRpcError {
+                                code: -32000,
+                                message: rejection.message,
+                                data: rejection
+                                    .requirements
+                                    .as_ref()
+                                    .and_then(|r| serde_json::to_value(r).ok()),
+                            });
+                            send_host_frame(&mut tx, HostFrame {
+                                message: serde_json::from_slice(
+                                    &error.to_json_bytes_for(rpc_request.as_ref()),
+                                )
+                                .ok(),
+                                requirements: rejection.requirements,
+                                ..HostFrame::default()
+                            })
+                            .await?;
+                            continue;
+                        }
+                    }
+                }
+
+                if frame.message.is_some() {
+                    debug!(
+                        "forwarding websocket message methods={:?}",
+                        rpc_request.as_ref().map(|r| r.methods())
+                    );
+                    upstream_tx.send(UpstreamMessage::text(body)).await?;
+                }
+            }
+            message = upstream_rx.next() => {
+                let text = match message {
+                    Some(Ok(UpstreamMessage::Text(text))) => text,
+                    Some(Ok(UpstreamMessage::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+                let message: serde_json::Value = match serde_json::from_str(&text) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        warn!("invalid message from Nimbus: {}", e);
+                        continue;
+                    }
+                };
+
+                let mut debt = None;
+                if let (Some(payments), Some(contract_address)) = (&payments, &contract_address)
+                    && websocket::is_notification(&message)
+                {
+                    match payments.bill_notification(contract_address) {
+                        Ok(Some(owed)) => debt = Some(owed.to_string()),
+                        Ok(None) => {
+                            warn!(
+                                "closing websocket session, {} owes more than its credit limit",
+                                contract_address
+                            );
+                            tx.send(Message::Close(Some(CloseFrame {
+                                code: close_code::POLICY,
+                                reason: PAYMENT_REQUIRED_CLOSE_REASON.into(),
+                            })))
+                            .await?;
+                            break;
+                        }
+                        Err(e) => error!(
+                            "failed to bill notification to {}: {}",
+                            contract_address, e
+                        ),
+                    }
+                }
+                send_host_frame(&mut tx, HostFrame {
+                    message: Some(message),
+                    debt,
+                    ..HostFrame::default()
+                })
+                .await?;
+            }
+        }
+    }
+
+    info!("websocket session closed");
+    let _ = upstream_tx.close().await;
+    Ok(())
+}
+
+/// send a frame to the user proxy
+async fn send_host_frame(
+    tx: &mut SplitSink<WebSocket, Message>,
+    frame: HostFrame,
+) -> anyhow::Result<()> {
+    tx.send(Message::text(serde_json::to_string(&frame)?))
+        .await?;
+    Ok(())
+}
+
+/// helper to create a JSON-RPC error response, batches get one error per call
+fn create_error_response(
+    status: StatusCode,
+    error: JsonRpcErrorResponse,
+    rpc_request: Option<&RpcRequest>,
+) -> Response<Body> {
     Response::builder()
         .status(status)
         .header("content-type", "application/json")
-        .body(Body::from(error.to_json_bytes()))
+        .body(Body::from(error.to_json_bytes_for(rpc_request)))
         .unwrap()
 }
-
-/// convert a hyper response body to bytes
-async fn response_to_bytes(
-    response: hyper::Response<hyper::Body>,
-) -> anyhow::Result<(hyper::Response<()>, Bytes)> {
-    let (parts, body) = response.into_parts();
-
-    let bytes = hyper::body::to_bytes(body).await.map_err(|e| {
-        error!("Failed to read response body: {}", e);
-        anyhow::anyhow!("Failed to read response body: {}", e)
-    })?;
-
-    let response_without_body = hyper::Response::from_parts(parts, ());
-    Ok((response_without_body, bytes))
-}
@@ -1,22 +1,46 @@
 use crate::{
-    hpc_service::HpcClient,
+    body_limit::{self, BodyLimits},
+    config::PaymentFlow,
+    funds_monitor::InsufficientFunds,
+    hpc_service::{AnyTicket, PaymentTicket},
+    payment_receipt::{
+        PAYMENT_RECEIPT_HEADER, ReceiptBook, ReceiptKey, onion_receipt_key, receipt_key_url,
+        serves_trusted_receipt_key, verify_receipt,
+    },
+    payment_requirements::{PAYMENT_REQUIRED_HEADER, PaymentRequirements, RequirementsCache},
+    postpaid::{DebtBook, PAYMENT_DEBT_HEADER},
+    provider_registry::{ProviderRegistry, TicketTurn, UserChannel},
     proxy_tor_client::ProxyTorClient,
-    rpc_utils::{self, JsonRpcErrorResponse},
+    rpc_utils::{self, JsonRpcErrorResponse, RpcRequest},
+    spend_budget::{BudgetExceeded, SpendBudget},
+    websocket::{self, HostFrame, UserFrame},
+    x402::{
+        PAYMENT_RESPONSE_HEADER, PaymentPayload, PaymentRequiredDocument, SettlementResponse,
+        X402Requirements,
+    },
 };
 use axum::{
     Router,
     body::Body,
-    extract::{Request, State},
-    http::{Response, StatusCode},
+    extract::{
+        ConnectInfo, Request, State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    http::{Extensions, HeaderMap, Response, StatusCode, Uri, header, request::Parts},
     response::IntoResponse,
     routing::post,
 };
 use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt, stream::SplitSink};
 use percent_encoding::percent_decode_str;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hash, Hasher, RandomState};
+use std::net::SocketAddr;
 use tokio::sync::watch;
+use tokio_tungstenite::tungstenite::Message as UpstreamMessage;
 use tower::ServiceBuilder;
 use tower_http::trace::{DefaultOnResponse, TraceLayer};
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
 
 /// types of errors that can occur
 #[derive(Debug, Clone, Copy)]
@@ -32,15 +56,34 @@ pub struct AppState {
     pub client: ProxyTorClient,
     pub issue_payment_tickets: bool,
     pub ready_rx: watch::Receiver<bool>,
-    pub hpc_client: HpcClient,
+    // configured providers and the channel each one is paid through
+    pub providers: ProviderRegistry,
+    // last payment requirements each provider sent with a 402
+    pub payment_requirements: RequirementsCache,
+    // when tickets are attached, see `PaymentFlow`
+    pub payment_flow: PaymentFlow,
+    // EVM network x402 payments are made on
+    pub network: String,
+    // spending limits, every ticket handed out is recorded here
+    pub spend_budget: SpendBudget,
+    // how often a rejected ticket is replaced before the 402 is passed on
+    pub max_payment_retries: u32,
+    // receipt keys and last receipts of the providers
+    pub receipts: ReceiptBook,
+    // caps on request and response bodies
+    pub body_limits: BodyLimits,
+    // postpaid debt each provider reported, paid with the next ticket
+    pub debts: DebtBook,
+    // keys local clients are hashed with, random per process, see `local_client`
+    pub local_client_keys: RandomState,
 }
 
 /// create the axum router with all routes and middleware
 pub fn create_router(state: AppState) -> Router {
     let mut router = Router::new();
 
-    // main RPC endpoint - with payment middleware
-    router = router.route("/", post(rpc_handler));
+    // main RPC endpoint - with payment middleware, WebSocket sessions pay per message
+    router = router.route("/", post(rpc_handler).get(ws_handler));
 
     // request tracing
     router
@@ -50,97 +93,53 @@ pub fn create_router(state: AppState) -> Router {
                 .layer(
                     TraceLayer::new_for_http()
                         .on_response(DefaultOnResponse::new().level(tracing::Level::INFO)),
-                ),
+                )
+                // request size cap, answered with a JSON-RPC error
+                .layer(axum::middleware::map_response_with_state(
+                    state.body_limits.request,
+                    body_limit::json_rpc_too_large,
+                ))
+                .layer(state.body_limits.request_layer()),
         )
         .with_state(state)
 }
 
 /// main RPC handler - forwards JSON-RPC requests to TOR, attaches payment ticket if necessary
-async fn rpc_handler(State(state): State<AppState>, request: Request) -> impl IntoResponse {
+async fn rpc_handler(State(mut state): State<AppState>, request: Request) -> impl IntoResponse {
     let start_time = std::time::Instant::now();
     // let timestamp = chrono::Utc::now();
 
-    // extract provider URL from query parameter
-    let uri = request.uri();
-    let provider_url_override: Option<String> = uri.query().and_then(|q| {
-        // parse query string for 'p' parameter
-        // simple manual parsing: split by '&' and look for 'p='
-        q.split('&').find_map(|param| {
-            if let Some(stripped) = param.strip_prefix("p=") {
-                // URL decode the value
-                percent_decode_str(stripped)
-                    .decode_utf8()
-                    .ok()
-                    .map(|s| s.to_string())
-            } else {
-                None
-            }
-        })
-    });
-
-    // provider URL is required in proxy mode
-    let provider_url = match provider_url_override {
-        Some(url) => {
-            info!("using provider URL: {}", url);
-
-            // validate the URL scheme
-            if !url.starts_with("http://") && !url.starts_with("https://") {
-                warn!("invalid provider URL scheme: {}", url);
-                return create_error_response(
-                    StatusCode::BAD_REQUEST,
-                    JsonRpcErrorResponse::parse_error(
-                        "Provider URL must start with http:// or https://",
-                    ),
-                );
-            }
-            url
-        }
-        None => {
-            warn!("missing required provider URL parameter");
-            return create_error_response(
-                StatusCode::BAD_REQUEST,
-                JsonRpcErrorResponse::parse_error(
-                    "Provider URL is required. Use ?p=<provider_url> query parameter",
-                ),
-            );
-        }
-    };
-
-    // generate payment ticket for .onion providers in proxy mode
-    let payment_ticket = if state.issue_payment_tickets {
-        info!("generating payment ticket...");
-
-        let hpc_client = state.hpc_client;
-
-        match hpc_client.generate_ticket().await {
-            Ok(ticket) => {
-                info!("Payment ticket generated with nonce: {}", ticket.nonce);
-                Some(ticket)
-            }
-            Err(e) => {
-                error!("Failed to generate payment ticket: {}", e);
-                return create_error_response(
-                    StatusCode::PAYMENT_REQUIRED,
-                    JsonRpcErrorResponse::parse_error(format!("Failed to generate payment: {}", e)),
-                );
-            }
-        }
-    } else {
-        None
+    // provider URL (or the name of a configured provider) is required in proxy mode
+    let (provider_url, channel) = match resolve_provider(&state, request.uri()) {
+        Ok(provider) => provider,
+        Err(error) => return create_error_response(StatusCode::BAD_REQUEST, error, None),
     };
 
     // extract headers and body from the request
     let (parts, body) = request.into_parts();
+    state.client = state.client.for_local_client(local_client(
+        &state.local_client_keys,
+        &parts.headers,
+        &parts.extensions,
+    ));
     let headers = parts.headers;
 
     // read the body
-    let body = match axum::body::to_bytes(body, usize::MAX).await {
+    let body = match axum::body::to_bytes(body, state.body_limits.request).await {
         Ok(b) => b,
+        Err(e) if body_limit::is_too_large(&e) => {
+            warn!(
+                "request body is over the {} byte limit",
+                state.body_limits.request
+            );
+            return body_limit::body_too_large_response(state.body_limits.request);
+        }
         Err(e) => {
             error!("failed to read request body: {}", e);
             return create_error_response(
                 StatusCode::BAD_REQUEST,
                 JsonRpcErrorResponse::parse_error(format!("Failed to read request body: {}", e)),
+                None,
             );
         }
     };
@@ -156,64 +155,180 @@ async fn rpc_handler(State(state): State<AppState>, request: Request) -> impl In
         return create_error_response(
             StatusCode::BAD_REQUEST,
             JsonRpcErrorResponse::parse_error("Content-Type must be application/json"),
+            None,
         );
     }
 
-    // extract request details for logging
-    let request_id = rpc_utils::extract_request_id(&body);
-    let method = rpc_utils::extract_request_method(&body);
+    // extract request details for logging, the parsed request also keeps the ids of
+    // batched calls so error replies can be matched up by the wallet
+    let rpc_request = rpc_utils::parse_request(&body);
+    let rpc_request = rpc_request.as_ref();
+    let methods = rpc_request.map(|r| r.methods());
+    let request_ids: Option<Vec<_>> =
+        rpc_request.map(|r| r.calls().iter().map(|c| c.id.clone()).collect());
     // let params = rpc_utils::extract_request_params(&body, 100);
 
     info!(
-        "Received RPC request: {} bytes, method={:?}, id={:?}",
+        "Received RPC request: {} bytes, batch={}, methods={:?}, ids={:?}",
         body.len(),
-        method,
-        request_id
+        rpc_request.is_some_and(|r| r.is_batch()),
+        methods,
+        request_ids
     );
 
-    // forward the request to upstream
-    let response = state
-        .client
-        .forward_request_with_payment(body.clone(), provider_url, payment_ticket.as_ref())
-        .await;
+    // the channel's ticket turn, held until the provider answered the paid request
+    let mut turn = None;
 
-    let response = match response {
-        Ok(resp) => resp,
-        Err(e) => {
-            // let duration_ms = start_time.elapsed().as_millis() as u64;
-            error!("failed to forward request: {}", e);
+    // pay upfront: always in prepay mode, with x402 once the provider's requirements are known
+    let mut payment = if state.issue_payment_tickets
+        && (state.payment_flow == PaymentFlow::Prepay
+            || state.payment_requirements.get(&provider_url).is_some())
+    {
+        turn = Some(channel.ticket_turn().await);
+        match issue_payment(&state, &channel, &provider_url, None, None).await {
+            Ok(payment) => Some(payment),
+            Err(error) => {
+                return create_error_response(StatusCode::PAYMENT_REQUIRED, error, rpc_request);
+            }
+        }
+    } else {
+        None
+    };
 
-            let error_response = if e.to_string().contains("timeout") {
-                JsonRpcErrorResponse::timeout_error()
-            } else {
-                JsonRpcErrorResponse::connection_error(e.to_string())
-            };
+    // forward the request to upstream, the next ticket on the channel may be signed once the
+    // provider answered this one
+    let (mut response_parts, mut response_body) = match forward(
+        &state,
+        &channel,
+        &body,
+        &provider_url,
+        payment.as_ref(),
+        turn,
+    )
+    .await
+    {
+        Ok(r) => r,
+        Err(error) => {
+            return create_error_response(StatusCode::BAD_GATEWAY, error, rpc_request);
+        }
+    };
 
-            // if we have a request ID, include it in the error
-            let error_response = if let Some(id) = request_id {
-                JsonRpcErrorResponse::with_id(error_response.error, id)
-            } else {
-                error_response
-            };
+    // answer a 402 with a new ticket: an unpaid request is paid once (the x402 challenge), a
+    // rejected ticket is replaced up to `max_payment_retries` times
+    let mut retries = 0;
+    while response_parts.status() == hyper::StatusCode::PAYMENT_REQUIRED {
+        // read the challenge, it is passed on as is if it is not answered
+        let response_bytes = match state.body_limits.read_response(response_body).await {
+            Ok(bytes) => bytes,
+            Err(error) => {
+                return create_error_response(StatusCode::BAD_GATEWAY, error, rpc_request);
+            }
+        };
+        response_body = hyper::Body::from(response_bytes.clone());
 
-            return create_error_response(StatusCode::BAD_GATEWAY, error_response);
+        // remember what the provider wants to be paid
+        let requirements =
+            update_payment_requirements(&state, &provider_url, &response_parts, &response_bytes);
+        if !state.issue_payment_tickets {
+            break;
         }
-    };
 
-    // convert the response to bytes
-    let (response_parts, response_bytes) = match response_to_bytes(response).await {
-        Ok(r) => r,
-        Err(e) => {
-            // let duration_ms = start_time.elapsed().as_millis() as u64;
-            error!("failed to read response body: {}", e);
+        let x402_requirements = PaymentRequiredDocument::from_body(&response_bytes)
+            .and_then(|document| document.ticket_requirements(&state.network).cloned());
+        // the next ticket must carry the nonce and amount the provider asked for
+        let requirements = x402_requirements
+            .as_ref()
+            .and_then(|r| r.extra.clone())
+            .or(requirements);
+        if payment.is_some() {
+            if retries >= state.max_payment_retries {
+                warn!(
+                    "{} still requires payment after {} retries, giving up",
+                    provider_url, retries
+                );
+                break;
+            }
+            retries += 1;
+            info!(
+                "{} rejected the payment ticket, retrying with a new ticket ({}/{})",
+                provider_url, retries, state.max_payment_retries
+            );
+        } else {
+            info!(
+                "{} requires payment ({} wei), paying and retrying",
+                provider_url,
+                x402_requirements
+                    .as_ref()
+                    .map_or("unknown", |r| r.max_amount_required.as_str())
+            );
+        }
 
-            let error_response = JsonRpcErrorResponse::connection_error(e.to_string());
-            let error_response = if let Some(id) = request_id {
-                JsonRpcErrorResponse::with_id(error_response.error, id)
-            } else {
-                error_response
-            };
-            return create_error_response(StatusCode::BAD_GATEWAY, error_response);
+        let turn = channel.ticket_turn().await;
+        payment = match issue_payment(
+            &state,
+            &channel,
+            &provider_url,
+            x402_requirements.as_ref(),
+            requirements.as_ref(),
+        )
+        .await
+        {
+            Ok(payment) => Some(payment),
+            Err(error) => {
+                return create_error_response(StatusCode::PAYMENT_REQUIRED, error, rpc_request);
+            }
+        };
+
+        (response_parts, response_body) = match forward(
+            &state,
+            &channel,
+            &body,
+            &provider_url,
+            payment.as_ref(),
+            Some(turn),
+        )
+        .await
+        {
+            Ok(r) => r,
+            Err(error) => {
+                return create_error_response(StatusCode::BAD_GATEWAY, error, rpc_request);
+            }
+        };
+    }
+
+    if let Some(settlement) = response_parts
+        .headers()
+        .get(PAYMENT_RESPONSE_HEADER)
+        .and_then(|v| v.to_str().ok())
+    {
+        match SettlementResponse::from_header_value(settlement) {
+            Ok(settlement) => info!(
+                "{} settled x402 payment: success={}",
+                provider_url, settlement.success
+            ),
+            Err(e) => warn!("{}", e),
+        }
+    }
+
+    // check what the provider says it counted for the ticket
+    if let Some(payment) = &payment
+        && response_parts.status() != hyper::StatusCode::PAYMENT_REQUIRED
+    {
+        let receipt = response_parts
+            .headers()
+            .get(PAYMENT_RECEIPT_HEADER)
+            .and_then(|v| v.to_str().ok());
+        check_receipt(&state, &provider_url, receipt, payment.ticket()).await;
+    }
+
+    // stream the response body through, up to the response size cap
+    let response_body = match state
+        .body_limits
+        .stream_response(response_parts.headers(), response_body)
+    {
+        Ok(body) => body,
+        Err(error) => {
+            return create_error_response(StatusCode::BAD_GATEWAY, error, rpc_request);
         }
     };
 
@@ -222,9 +337,9 @@ async fn rpc_handler(State(state): State<AppState>, request: Request) -> impl In
     // let is_success = status_code >= 200 && status_code < 300;
 
     info!(
-        "forwarded response: status={}, {} bytes, duration={}ms",
+        "forwarding response: status={}, content-length={:?}, duration={}ms",
         status_code,
-        response_bytes.len(),
+        response_parts.headers().get(hyper::header::CONTENT_LENGTH),
         duration_ms
     );
 
@@ -250,37 +365,750 @@ async fn rpc_handler(State(state): State<AppState>, request: Request) -> impl In
         }
     }
 
-    response_builder
-        .body(Body::from(response_bytes))
-        .unwrap_or_else(|e| {
-            error!("Failed to build response: {}", e);
-            create_error_response(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                JsonRpcErrorResponse::new(rpc_utils::JsonRpcError::server_error("Internal error")),
-            )
+    response_builder.body(response_body).unwrap_or_else(|e| {
+        error!("Failed to build response: {}", e);
+        create_error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            JsonRpcErrorResponse::new(rpc_utils::JsonRpcError::server_error("Internal error")),
+            rpc_request,
+        )
+    })
+}
+
+/// resolve the `?p=` query parameter to the provider URL and the channel paying it
+fn resolve_provider(
+    state: &AppState,
+    uri: &Uri,
+) -> Result<(String, UserChannel), JsonRpcErrorResponse> {
+    // extract provider URL from query parameter
+    let provider_url_override: Option<String> = uri.query().and_then(|q| {
+        // parse query string for 'p' parameter
+        // simple manual parsing: split by '&' and look for 'p='
+        q.split('&').find_map(|param| {
+            if let Some(stripped) = param.strip_prefix("p=") {
+                // URL decode the value
+                percent_decode_str(stripped)
+                    .decode_utf8()
+                    .ok()
+                    .map(|s| s.to_string())
+            } else {
+                None
+            }
+        })
+    });
+
+    match provider_url_override {
+        Some(provider) => {
+            let (url, channel) = state.providers.resolve(&provider);
+            info!("using provider URL: {}", url);
+
+            // validate the URL scheme
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                warn!("invalid provider URL scheme: {}", url);
+                return Err(JsonRpcErrorResponse::parse_error(
+                    "Provider URL must start with http:// or https://",
+                ));
+            }
+            Ok((url, channel.clone()))
+        }
+        None => {
+            warn!("missing required provider URL parameter");
+            Err(JsonRpcErrorResponse::parse_error(
+                "Provider URL is required. Use ?p=<provider_url or name> query parameter",
+            ))
+        }
+    }
+}
+
+/// the local client a request comes from, told apart by the Authorization header it sends
+/// (credentials in its RPC URL) or else by its source port, hashed with the process's random
+/// `keys` so it stays out of logs
+fn local_client(
+    keys: &RandomState,
+    headers: &HeaderMap,
+    extensions: &Extensions,
+) -> Option<String> {
+    let mut hasher = keys.build_hasher();
+    if let Some(authorization) = headers.get(header::AUTHORIZATION) {
+        authorization.as_bytes().hash(&mut hasher);
+    } else {
+        let ConnectInfo(addr) = extensions.get::<ConnectInfo<SocketAddr>>()?;
+        addr.port().hash(&mut hasher);
+    }
+    Some(format!("{:016x}", hasher.finish()))
+}
+
+/// WebSocket handler - tunnels the wallet's JSON-RPC messages and subscriptions to the provider
+async fn ws_handler(
+    State(mut state): State<AppState>,
+    parts: Parts,
+    ws: WebSocketUpgrade,
+) -> Response<Body> {
+    let (provider_url, channel) = match resolve_provider(&state, &parts.uri) {
+        Ok(provider) => provider,
+        Err(error) => return create_error_response(StatusCode::BAD_REQUEST, error, None),
+    };
+    state.client = state.client.for_local_client(local_client(
+        &state.local_client_keys,
+        &parts.headers,
+        &parts.extensions,
+    ));
+
+    ws.max_message_size(state.body_limits.request)
+        .on_upgrade(move |socket| async move {
+            if let Err(e) = ws_session(state, provider_url, channel, socket).await {
+                warn!("websocket session ended: {}", e);
+            }
         })
 }
 
-/// helper to create a JSON-RPC error response
-fn create_error_response(status: StatusCode, error: JsonRpcErrorResponse) -> Response<Body> {
+/// proxy a WebSocket session to a provider over Tor
+///
+/// every message from the wallet is sent with a ticket paying for it, and the debt the
+/// provider reports for subscription notifications is settled with a ticket right away
+async fn ws_session(
+    state: AppState,
+    provider_url: String,
+    channel: UserChannel,
+    socket: WebSocket,
+) -> anyhow::Result<()> {
+    let upstream = state.client.connect_websocket(&provider_url).await?;
+    info!("websocket session opened to {}", provider_url);
+    let (mut upstream_tx, mut upstream_rx) = upstream.split();
+    let (mut tx, mut rx) = socket.split();
+
+    // tickets sent and not yet accepted or refused by the provider, in order
+    let mut pending: VecDeque<PaymentTicket> = VecDeque::new();
+
+    loop {
+        tokio::select! {
+            frame = rx.next() => {
+                let text = match frame {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+                let message: serde_json::Value = match serde_json::from_str(&text) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        warn!("invalid websocket message: {}", e);
+                        let error = JsonRpcErrorResponse::parse_error(format!("Parse error: {}", e));
+                        send_error(&mut tx, error, None).await?;
+                        continue;
+                    }
+                };
+                let rpc_request = rpc_utils::parse_request(text.as_bytes());
+                debug!(
+                    "forwarding websocket message methods={:?}",
+                    rpc_request.as_ref().map(|r| r.methods())
+                );
+
+                // notifications are billed as debt, which only postpaid channels pay
+                if state.issue_payment_tickets
+                    && !channel.postpaid
+                    && rpc_request.as_ref().is_some_and(websocket::is_subscribe)
+                {
+                    warn!("refusing a subscription to {}, its channel is not postpaid", provider_url);
+                    let error = JsonRpcErrorResponse::new(rpc_utils::JsonRpcError::server_error(
+                        "Subscriptions are paid as postpaid debt, start the proxy with --postpaid (native or memory backend)",
+                    ));
+                    send_error(&mut tx, error, rpc_request.as_ref()).await?;
+                    continue;
+                }
+
+                // hold the channel's turn until the ticket is on its way
+                let mut frame = UserFrame {
+                    ticket: None,
+                    message: Some(message),
+                };
+                let _turn = if state.issue_payment_tickets {
+                    let turn = channel.ticket_turn().await;
+                    match issue_payment(&state, &channel, &provider_url, None, None).await {
+                        Ok(payment) => frame.ticket = Some(payment.ticket().clone()),
+                        Err(error) => {
+                            send_error(&mut tx, error, rpc_request.as_ref()).await?;
+                            continue;
+                        }
+                    }
+                    Some(turn)
+                } else {
+                    None
+                };
+                upstream_tx
+                    .send(UpstreamMessage::text(serde_json::to_string(&frame)?))
+                    .await?;
+                if let Some(ticket) = frame.ticket {
+                    // the ticket paid what was owed
+                    state.debts.set(&provider_url, 0);
+                    pending.push_back(ticket);
+                }
+            }
+            message = upstream_rx.next() => {
+                let text = match message {
+                    Some(Ok(UpstreamMessage::Text(text))) => text,
+                    Some(Ok(UpstreamMessage::Close(frame))) => {
+                        if let Some(frame) = frame {
+                            info!("{} closed the websocket: {}", provider_url, frame.reason);
+                        }
+                        break;
+                    }
+                    Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+                let frame: HostFrame = match serde_json::from_str(&text) {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        warn!("invalid websocket frame from {}: {}", provider_url, e);
+                        continue;
+                    }
+                };
+
+                // a frame without message accepts the oldest pending ticket, one with
+                // requirements refuses it
+                if frame.message.is_none() || frame.requirements.is_some() {
+                    let ticket = pending.pop_front();
+                    if let (Some(ticket), None) = (&ticket, &frame.requirements) {
+                        check_receipt(&state, &provider_url, frame.receipt.as_deref(), ticket)
+                            .await;
+                    }
+                }
+                let debt = frame.debt();
+                if let Some(requirements) = frame.requirements {
+                    info!(
+                        "{} refused the ticket: price={} expectedAmount={}",
+                        provider_url, requirements.price, requirements.expected_amount
+                    );
+                    if let Some(debt) = requirements.debt.as_ref().and_then(|d| d.parse().ok()) {
+                        state.debts.set(&provider_url, debt);
+                    }
+                    state
+                        .payment_requirements
+                        .update(&provider_url, requirements);
+                }
+                if let Some(debt) = debt {
+                    debug!("{} reports a debt of {} wei", provider_url, debt);
+                    state.debts.set(&provider_url, debt);
+                }
+
+                let Some(message) = frame.message else {
+                    continue;
+                };
+                let notification = websocket::is_notification(&message);
+                tx.send(Message::text(message.to_string())).await?;
+
+                // pay for the notification before the debt reaches the credit limit
+                if let (true, true, Some(debt)) = (notification, state.issue_payment_tickets, debt)
+                    && debt > 0
+                    && channel.postpaid
+                {
+                    let _turn = channel.ticket_turn().await;
+                    match issue_settlement(&state, &channel, &provider_url, debt).await {
+                        Ok(ticket) => {
+                            let frame = UserFrame {
+                                ticket: Some(ticket.clone()),
+                                message: None,
+                            };
+                            upstream_tx
+                                .send(UpstreamMessage::text(serde_json::to_string(&frame)?))
+                                .await?;
+                            state.debts.set(&provider_url, 0);
+                            pending.push_back(ticket);
+                        }
+                        Err(error) => warn!(
+                            "not settling {} wei of notifications with {}: {}",
+                            debt, provider_url, error.error.message
+                        ),
+                    }
+                }
+            }
+        }
+    }
+
+    info!("websocket session to {} closed", provider_url);
+    let _ = upstream_tx.close().await;
+    Ok(())
+}
+
+/// send a JSON-RPC error to the wallet over its WebSocket
+async fn send_error(
+    tx: &mut SplitSink<WebSocket, Message>,
+    error: JsonRpcErrorResponse,
+    rpc_request: Option<&RpcRequest>,
+) -> anyhow::Result<()> {
+    let body = String::from_utf8(error.to_json_bytes_for(rpc_request))?;
+    tx.send(Message::text(body)).await?;
+    Ok(())
+}
+
+/// helper to create a JSON-RPC error response, batches get one error per call
+fn create_error_response(
+    status: StatusCode,
+    error: JsonRpcErrorResponse,
+    rpc_request: Option<&RpcRequest>,
+) -> Response<Body> {
     Response::builder()
         .status(status)
         .header("content-type", "application/json")
-        .body(Body::from(error.to_json_bytes()))
+        .body(Body::from(error.to_json_bytes_for(rpc_request)))
         .unwrap()
 }
 
-/// convert a hyper response body to bytes
-async fn response_to_bytes(
-    response: hyper::Response<hyper::Body>,
-) -> anyhow::Result<(hyper::Response<()>, Bytes)> {
-    let (parts, body) = response.into_parts();
+/// how a payment ticket is attached to a request
+enum Payment {
+    // our own X-Payment-Ticket header
+    Ticket(PaymentTicket),
+    // x402 X-PAYMENT header
+    X402(PaymentPayload),
+}
+
+impl Payment {
+    /// the ticket being paid with
+    fn ticket(&self) -> &PaymentTicket {
+        match self {
+            Self::Ticket(ticket) => ticket,
+            Self::X402(payment) => payment.payload.payment_ticket(),
+        }
+    }
+}
 
-    let bytes = hyper::body::to_bytes(body).await.map_err(|e| {
-        error!("Failed to read response body: {}", e);
-        anyhow::anyhow!("Failed to read response body: {}", e)
+/// generate a payment ticket on the provider's channel and check it against what the provider
+/// asked for, a ticket for another recipient is never sent
+///
+/// after a 402 the ticket is issued to meet the nonce and amount of the provider's
+/// `rejection`, a backend that cannot do so fails instead of signing a ticket bound to be refused
+async fn issue_payment(
+    state: &AppState,
+    channel: &UserChannel,
+    provider_url: &str,
+    x402_requirements: Option<&X402Requirements>,
+    rejection: Option<&PaymentRequirements>,
+) -> Result<Payment, JsonRpcErrorResponse> {
+    // refuse before signing when the budget is already used up
+    let requirements = state.payment_requirements.get(provider_url);
+    let debt = match state.debts.get(provider_url) {
+        debt if channel.postpaid => debt,
+        0 => 0,
+        debt => {
+            warn!(
+                "not paying the {} wei of postpaid debt {} reports, see --postpaid",
+                debt, provider_url
+            );
+            0
+        }
+    };
+    // what a provider that refused a ticket asked the next one to carry
+    let meeting = rejection.map(|rejection| {
+        let min_nonce = rejection
+            .min_nonce
+            .as_deref()
+            .and_then(|nonce| nonce.parse().ok())
+            .unwrap_or_default();
+        // the amount asked for includes the debt, which is only paid on postpaid channels
+        let min_amount = match rejection.debt.as_deref().and_then(|d| d.parse().ok()) {
+            Some(debt) if !channel.postpaid => rejection.expected_amount.saturating_sub(debt),
+            _ => rejection.expected_amount,
+        };
+        (min_nonce, min_amount)
+    });
+    let price = x402_requirements
+        .and_then(|r| r.extra.as_ref())
+        .or(requirements.as_ref())
+        .map_or(channel.ticket_price, |r| r.price);
+    let expected_spend = match meeting {
+        // the ticket is topped up to the amount asked for when that adds more than the price
+        Some((_, min_amount)) => {
+            let last_amount = match state
+                .spend_budget
+                .latest_ticket(channel.contract_address.as_deref())
+            {
+                Ok(latest) => latest.map_or(0, |(_, amount)| amount),
+                Err(e) => return Err(spend_budget_error(e)),
+            };
+            min_amount
+                .saturating_sub(last_amount)
+                .max(price.saturating_add(debt))
+        }
+        None => price.saturating_add(debt),
+    };
+    match state.spend_budget.check(provider_url, expected_spend) {
+        Ok(Ok(())) => {}
+        Ok(Err(exceeded)) => return Err(budget_exceeded(provider_url, &exceeded)),
+        Err(e) => return Err(spend_budget_error(e)),
+    }
+    if let Err(e) = channel.funds_monitor.check_spend(expected_spend) {
+        return Err(insufficient_funds(provider_url, &e));
+    }
+
+    if debt > 0 {
+        info!("generating payment ticket paying {} wei of debt...", debt);
+    } else {
+        info!("generating payment ticket...");
+    }
+
+    let generated = match meeting {
+        Some((min_nonce, min_amount)) => {
+            channel
+                .payment_backend
+                .generate_ticket_meeting(debt, min_nonce, min_amount)
+                .await
+        }
+        None => {
+            channel
+                .payment_backend
+                .generate_ticket_with_debt(debt)
+                .await
+        }
+    };
+    let ticket = match generated {
+        Ok(ticket) => {
+            info!("Payment ticket generated with nonce: {}", ticket.nonce);
+            ticket
+        }
+        Err(e) => {
+            error!("Failed to generate payment ticket: {}", e);
+            return Err(JsonRpcErrorResponse::parse_error(format!(
+                "Failed to generate payment: {}",
+                e
+            )));
+        }
+    };
+
+    let recipient_check =
+        channel
+            .check_ticket(&ticket)
+            .and_then(|()| match (x402_requirements, &requirements) {
+                (Some(x402_requirements), _) => x402_requirements.check_recipient(&ticket),
+                (None, Some(requirements)) => requirements.check_recipient(&ticket),
+                (None, None) => Ok(()),
+            });
+    if let Err(e) = recipient_check {
+        warn!("not sending payment ticket to {}: {}", provider_url, e);
+        channel.payment_backend.release_ticket(&ticket);
+        return Err(JsonRpcErrorResponse::new(
+            rpc_utils::JsonRpcError::server_error(format!(
+                "Payment ticket does not match the provider's requirements: {}",
+                e
+            )),
+        ));
+    }
+
+    authorize_ticket(state, channel, provider_url, &ticket)?;
+
+    Ok(match state.payment_flow {
+        PaymentFlow::Prepay if x402_requirements.is_none() => Payment::Ticket(ticket),
+        _ => Payment::X402(PaymentPayload::new(
+            &state.network,
+            AnyTicket::Cumulative(ticket),
+        )),
+    })
+}
+
+/// generate a ticket settling the debt subscription notifications ran up with a provider
+async fn issue_settlement(
+    state: &AppState,
+    channel: &UserChannel,
+    provider_url: &str,
+    debt: u128,
+) -> Result<PaymentTicket, JsonRpcErrorResponse> {
+    match state.spend_budget.check(provider_url, debt) {
+        Ok(Ok(())) => {}
+        Ok(Err(exceeded)) => return Err(budget_exceeded(provider_url, &exceeded)),
+        Err(e) => return Err(spend_budget_error(e)),
+    }
+    if let Err(e) = channel.funds_monitor.check_spend(debt) {
+        return Err(insufficient_funds(provider_url, &e));
+    }
+
+    info!(
+        "generating settlement ticket paying {} wei of debt...",
+        debt
+    );
+    let ticket = channel
+        .payment_backend
+        .generate_settlement_ticket(debt)
+        .await
+        .map_err(|e| {
+            error!("Failed to generate settlement ticket: {}", e);
+            JsonRpcErrorResponse::parse_error(format!("Failed to generate payment: {}", e))
+        })?;
+
+    if let Err(e) = channel.check_ticket(&ticket) {
+        warn!("not sending settlement ticket to {}: {}", provider_url, e);
+        channel.payment_backend.release_ticket(&ticket);
+        return Err(JsonRpcErrorResponse::new(
+            rpc_utils::JsonRpcError::server_error(format!(
+                "Payment ticket does not match the provider's requirements: {}",
+                e
+            )),
+        ));
+    }
+
+    authorize_ticket(state, channel, provider_url, &ticket)?;
+    Ok(ticket)
+}
+
+/// check a generated ticket against the channel's funds and the spending limits, and record it
+///
+/// a refused ticket is never sent and is given back to the payment backend, so the next ticket
+/// does not build on it
+fn authorize_ticket(
+    state: &AppState,
+    channel: &UserChannel,
+    provider_url: &str,
+    ticket: &PaymentTicket,
+) -> Result<(), JsonRpcErrorResponse> {
+    let authorized = check_ticket_spend(state, channel, provider_url, ticket);
+    if authorized.is_err() {
+        channel.payment_backend.release_ticket(ticket);
+    }
+    authorized
+}
+
+fn check_ticket_spend(
+    state: &AppState,
+    channel: &UserChannel,
+    provider_url: &str,
+    ticket: &PaymentTicket,
+) -> Result<(), JsonRpcErrorResponse> {
+    // the contract must cover the whole cumulative amount, or the ticket is worthless
+    let amount = ticket.amount_value().map_err(|e| {
+        error!("generated ticket has an invalid amount: {}", e);
+        JsonRpcErrorResponse::parse_error(format!("Failed to generate payment: {}", e))
+    })?;
+    if let Err(e) = channel.funds_monitor.check_amount(amount) {
+        return Err(insufficient_funds(provider_url, &e));
+    }
+
+    match state.spend_budget.authorize(provider_url, ticket) {
+        Ok(Ok(spent)) => debug!("ticket for {} spends {} wei", provider_url, spent),
+        Ok(Err(exceeded)) => return Err(budget_exceeded(provider_url, &exceeded)),
+        Err(e) => return Err(spend_budget_error(e)),
+    }
+    channel.funds_monitor.record_ticket_amount(amount);
+    Ok(())
+}
+
+/// JSON-RPC error for a request refused by the spending limits
+fn budget_exceeded(provider_url: &str, exceeded: &BudgetExceeded) -> JsonRpcErrorResponse {
+    warn!("not paying {}: {}", provider_url, exceeded);
+    JsonRpcErrorResponse::new(rpc_utils::JsonRpcError::server_error_with_data(
+        "Spending budget exceeded",
+        serde_json::json!({ "details": exceeded.to_string() }),
+    ))
+}
+
+/// JSON-RPC error for a ticket the channel cannot cover
+fn insufficient_funds(provider_url: &str, e: &InsufficientFunds) -> JsonRpcErrorResponse {
+    warn!("not paying {}: {}", provider_url, e);
+    JsonRpcErrorResponse::new(rpc_utils::JsonRpcError::server_error_with_data(
+        "Insufficient channel funds",
+        serde_json::json!({ "details": e.to_string(), "available": e.available.to_string() }),
+    ))
+}
+
+fn spend_budget_error(e: anyhow::Error) -> JsonRpcErrorResponse {
+    error!("failed to check spending budget: {}", e);
+    JsonRpcErrorResponse::new(rpc_utils::JsonRpcError::server_error(format!(
+        "Failed to check spending budget: {}",
+        e
+    )))
+}
+
+/// forward a request over Tor, the response body is left to be streamed, the ticket `turn`
+/// is given back once the response headers arrived
+async fn forward(
+    state: &AppState,
+    channel: &UserChannel,
+    body: &Bytes,
+    provider_url: &str,
+    payment: Option<&Payment>,
+    turn: Option<TicketTurn>,
+) -> Result<(hyper::Response<()>, hyper::Body), JsonRpcErrorResponse> {
+    let response = match payment {
+        Some(Payment::X402(payment)) => {
+            state
+                .client
+                .forward_request_with_x402_payment(
+                    body.clone(),
+                    provider_url.to_string(),
+                    payment,
+                    turn,
+                )
+                .await
+        }
+        Some(Payment::Ticket(ticket)) => {
+            state
+                .client
+                .forward_request_with_payment(
+                    body.clone(),
+                    provider_url.to_string(),
+                    Some(ticket),
+                    turn,
+                )
+                .await
+        }
+        None => {
+            state
+                .client
+                .forward_request(body.clone(), provider_url.to_string())
+                .await
+        }
+    };
+
+    let response = response.map_err(|e| {
+        error!("failed to forward request: {}", e);
+
+        if e.to_string().contains("timeout") {
+            JsonRpcErrorResponse::timeout_error()
+        } else {
+            JsonRpcErrorResponse::connection_error(e.to_string())
+        }
     })?;
 
-    let response_without_body = hyper::Response::from_parts(parts, ());
-    Ok((response_without_body, bytes))
+    let (parts, response_body) = response.into_parts();
+    let parts = hyper::Response::from_parts(parts, ());
+
+    // remember what the provider bills for postpaid responses, at most what it advertised for
+    // the request plus the metering allowance
+    if let Some(debt) = parts
+        .headers()
+        .get(PAYMENT_DEBT_HEADER)
+        .and_then(|v| v.to_str().ok())
+    {
+        match debt.parse() {
+            Ok(debt) => {
+                debug!("{} reports a debt of {} wei", provider_url, debt);
+                let price = state
+                    .payment_requirements
+                    .get(provider_url)
+                    .map_or(channel.ticket_price, |r| r.price);
+                state.debts.report(provider_url, debt, price);
+            }
+            Err(e) => warn!(
+                "invalid {} from {}: {}",
+                PAYMENT_DEBT_HEADER, provider_url, e
+            ),
+        }
+    }
+    Ok((parts, response_body))
+}
+
+/// verify the provider's signed receipt for `ticket` and flag accounting that does not match
+/// what was paid, problems are logged and the response is passed on regardless
+async fn check_receipt(
+    state: &AppState,
+    provider_url: &str,
+    receipt: Option<&str>,
+    ticket: &PaymentTicket,
+) {
+    let Some(value) = receipt else {
+        warn!(
+            "{} sent no payment receipt for ticket with nonce {}",
+            provider_url, ticket.nonce
+        );
+        return;
+    };
+
+    let key = match state
+        .receipts
+        .key(provider_url)
+        .or_else(|| onion_receipt_key(provider_url))
+    {
+        Some(key) => key,
+        None => match fetch_receipt_key(state, provider_url).await {
+            Ok(key) => {
+                state.receipts.set_key(provider_url, key);
+                key
+            }
+            Err(e) => {
+                warn!("failed to fetch the receipt key of {}: {}", provider_url, e);
+                return;
+            }
+        },
+    };
+
+    let receipt = match verify_receipt(value, &key) {
+        Ok(receipt) => receipt,
+        Err(e) => {
+            warn!("invalid payment receipt from {}: {}", provider_url, e);
+            return;
+        }
+    };
+
+    debug!(
+        "{} credited {} wei for nonce {} at a price of {} wei",
+        provider_url, receipt.amount, receipt.nonce, receipt.price
+    );
+    if let Err(e) = state.receipts.record(provider_url, receipt, ticket) {
+        warn!("inconsistent accounting by {}: {}", provider_url, e);
+    }
+}
+
+/// fetch the receipt key of a provider not reached through an onion address, over TLS
+async fn fetch_receipt_key(
+    state: &AppState,
+    provider_url: &str,
+) -> anyhow::Result<ed25519_dalek::VerifyingKey> {
+    if !serves_trusted_receipt_key(provider_url) {
+        anyhow::bail!("a receipt key served over plain http cannot be trusted, use https");
+    }
+    let response = state.client.get(receipt_key_url(provider_url)).await?;
+    let (parts, bytes) = ProxyTorClient::response_to_bytes(response).await?;
+    if !parts.status().is_success() {
+        anyhow::bail!("receipt key request failed with status {}", parts.status());
+    }
+    let key: ReceiptKey = serde_json::from_slice(&bytes)?;
+    key.verifying_key()
+}
+
+/// remember the payment requirements a provider sent with a 402
+fn update_payment_requirements(
+    state: &AppState,
+    provider_url: &str,
+    response: &hyper::Response<()>,
+    body: &[u8],
+) -> Option<PaymentRequirements> {
+    match payment_requirements(response, body) {
+        Some(requirements) => {
+            info!(
+                "{} requires payment: price={} expectedAmount={} contract={:?} railgun={:?}",
+                provider_url,
+                requirements.price,
+                requirements.expected_amount,
+                requirements.hidden_payment_channels_contract_address,
+                requirements.to_railgun_address
+            );
+            if let Some(debt) = requirements.debt.as_ref().and_then(|d| d.parse().ok()) {
+                state.debts.set(provider_url, debt);
+            }
+            state
+                .payment_requirements
+                .update(provider_url, requirements.clone());
+            Some(requirements)
+        }
+        None => {
+            warn!("{} requires payment but sent no requirements", provider_url);
+            None
+        }
+    }
+}
+
+/// payment requirements of a 402 response, from the header or else the JSON-RPC error data
+fn payment_requirements(
+    response: &hyper::Response<()>,
+    body: &[u8],
+) -> Option<PaymentRequirements> {
+    let header = response
+        .headers()
+        .get(PAYMENT_REQUIRED_HEADER)
+        .and_then(|v| v.to_str().ok());
+
+    match header.map(PaymentRequirements::from_header_value) {
+        Some(Ok(requirements)) => Some(requirements),
+        Some(Err(e)) => {
+            warn!("{}", e);
+            PaymentRequirements::from_error_body(body)
+        }
+        None => PaymentRequirements::from_error_body(body),
+    }
 }
@@ -0,0 +1,498 @@
+use crate::hpc_service::PaymentTicket;
+use crate::ticket_ledger::channel_key;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use parking_lot::Mutex;
+use rusqlite::{Connection, OptionalExtension, params};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{debug, info};
+
+/// spending limits over the budget periods (wei), `None` means unlimited
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SpendLimits {
+    pub hourly: Option<u128>,
+    pub daily: Option<u128>,
+    pub lifetime: Option<u128>,
+}
+
+impl SpendLimits {
+    fn get(&self, period: BudgetPeriod) -> Option<u128> {
+        match period {
+            BudgetPeriod::Hour => self.hourly,
+            BudgetPeriod::Day => self.daily,
+            BudgetPeriod::Lifetime => self.lifetime,
+        }
+    }
+}
+
+/// all limits the user proxy enforces before handing out a ticket
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BudgetLimits {
+    // largest amount a single request may spend (wei)
+    pub per_request: Option<u128>,
+    // limits of every provider on its own
+    pub provider: SpendLimits,
+    // limits over all providers together
+    pub total: SpendLimits,
+}
+
+/// period a budget is measured over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetPeriod {
+    Hour,
+    Day,
+    Lifetime,
+}
+
+impl BudgetPeriod {
+    const ALL: [Self; 3] = [Self::Hour, Self::Day, Self::Lifetime];
+
+    /// start of the current window, `None` for the lifetime budget
+    fn since(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Self::Hour => Some(now - Duration::hours(1)),
+            Self::Day => Some(now - Duration::days(1)),
+            Self::Lifetime => None,
+        }
+    }
+}
+
+impl fmt::Display for BudgetPeriod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Hour => "hourly",
+            Self::Day => "daily",
+            Self::Lifetime => "lifetime",
+        })
+    }
+}
+
+/// whose spend a budget covers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BudgetScope {
+    Provider(String),
+    Total,
+}
+
+impl fmt::Display for BudgetScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Provider(provider) => write!(f, "provider {}", provider),
+            Self::Total => f.write_str("overall"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum BudgetExceeded {
+    #[error("request would spend {amount} wei, above the per-request cap of {limit} wei")]
+    PerRequest { amount: u128, limit: u128 },
+
+    #[error(
+        "{scope} {period} budget of {limit} wei would be exceeded: {spent} wei spent, request needs {amount} wei"
+    )]
+    Budget {
+        scope: BudgetScope,
+        period: BudgetPeriod,
+        limit: u128,
+        spent: u128,
+        amount: u128,
+    },
+}
+
+/// persistent user-side record of what every ticket handed out has spent
+///
+/// tickets carry cumulative amounts, so a ticket spends what it adds over the last ticket sent
+/// for the same contract, or its whole amount once the amounts restart after a claim
+#[derive(Clone)]
+pub struct SpendBudget {
+    conn: Arc<Mutex<Connection>>,
+    limits: BudgetLimits,
+    // budgets of single providers by URL, replacing `limits.provider`
+    provider_limits: Arc<HashMap<String, SpendLimits>>,
+}
+
+impl SpendBudget {
+    /// open (or create) a spend database at the given path
+    pub fn open(path: &Path, limits: BudgetLimits) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create spend directory {:?}", parent))?;
+        }
+
+        info!("opening spend database at {:?}", path);
+        let conn = Connection::open(path)
+            .with_context(|| format!("failed to open spend database {:?}", path))?;
+        Self::with_connection(conn, limits)
+    }
+
+    /// open an in-memory spend database, nothing is persisted
+    pub fn open_in_memory(limits: BudgetLimits) -> Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?, limits)
+    }
+
+    fn with_connection(conn: Connection, limits: BudgetLimits) -> Result<Self> {
+        conn.execute_batch(
+            "
+            PRAGMA journal_mode = WAL;
+
+            CREATE TABLE IF NOT EXISTS spends (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                provider TEXT NOT NULL,
+                channel TEXT NOT NULL,
+                nonce TEXT NOT NULL,
+                amount TEXT NOT NULL,
+                signature TEXT NOT NULL,
+                spent TEXT NOT NULL,
+                spent_at TEXT NOT NULL
+            );
+
+            CREATE INDEX IF NOT EXISTS spends_provider_idx
+                ON spends (provider, spent_at);
+
+            CREATE INDEX IF NOT EXISTS spends_spent_at_idx
+                ON spends (spent_at);
+
+            CREATE INDEX IF NOT EXISTS spends_channel_idx
+                ON spends (channel, id);
+            ",
+        )
+        .context("failed to initialize spend database schema")?;
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            limits,
+            provider_limits: Arc::new(HashMap::new()),
+        })
+    }
+
+    /// give some providers their own budget instead of the common per-provider limits
+    pub fn with_provider_limits(mut self, provider_limits: HashMap<String, SpendLimits>) -> Self {
+        self.provider_limits = Arc::new(provider_limits);
+        self
+    }
+
+    /// the limits being enforced
+    pub fn limits(&self) -> &BudgetLimits {
+        &self.limits
+    }
+
+    /// check that a request to `provider` expected to spend `amount` fits every budget, called
+    /// before a ticket is signed
+    pub fn check(&self, provider: &str, amount: u128) -> Result<Result<(), BudgetExceeded>> {
+        let conn = self.conn.lock();
+        self.check_limits(&conn, provider, amount)
+    }
+
+    /// record a ticket about to be sent to `provider` if what it spends fits every budget,
+    /// returns the amount spent
+    ///
+    /// the check and the insert happen under the same lock so concurrent requests cannot both
+    /// spend the last of a budget
+    pub fn authorize(
+        &self,
+        provider: &str,
+        ticket: &PaymentTicket,
+    ) -> Result<Result<u128, BudgetExceeded>> {
+        let conn = self.conn.lock();
+        let channel = channel_key(&ticket.hidden_payment_channels_contract_address);
+        let spent = ticket_spend(&conn, &channel, ticket)?;
+        // spends are summed as 64-bit integers, see `query_spent`
+        if i64::try_from(spent).is_err() {
+            anyhow::bail!("ticket spends {} wei, too much to record", spent);
+        }
+
+        if let Err(exceeded) = self.check_limits(&conn, provider, spent)? {
+            return Ok(Err(exceeded));
+        }
+
+        conn.execute(
+            "INSERT INTO spends
+                (provider, channel, nonce, amount, signature, spent, spent_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                provider,
+                channel,
+                ticket.nonce,
+                ticket.amount,
+                ticket.signature,
+                spent.to_string(),
+                timestamp(Utc::now()),
+            ],
+        )?;
+
+        debug!(
+            "recorded spend of {} wei for {} (ticket nonce {})",
+            spent, provider, ticket.nonce
+        );
+        Ok(Ok(spent))
+    }
+
+    /// nonce and cumulative amount of the latest ticket handed out for a contract (or any
+    /// contract)
+    pub fn latest_ticket(&self, contract_address: Option<&str>) -> Result<Option<(u128, u128)>> {
+        let conn = self.conn.lock();
+        let latest = conn
+            .query_row(
+                "SELECT nonce, amount FROM spends WHERE (?1 IS NULL OR channel = ?1)
+                 ORDER BY id DESC LIMIT 1",
+                params![contract_address.map(channel_key)],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+            )
+            .optional()?;
+        latest
+            .map(|(nonce, amount)| {
+                let nonce = nonce.parse().with_context(|| {
+                    format!("invalid ticket nonce '{}' in spend database", nonce)
+                })?;
+                let amount = amount.parse().with_context(|| {
+                    format!("invalid ticket amount '{}' in spend database", amount)
+                })?;
+                Ok((nonce, amount))
+            })
+            .transpose()
+    }
+
+    /// what has been spent on `provider` (or on all providers) within `period`
+    pub fn spent(&self, provider: Option<&str>, period: BudgetPeriod) -> Result<u128> {
+        let conn = self.conn.lock();
+        query_spent(&conn, provider, period.since(Utc::now()))
+    }
+
+    fn check_limits(
+        &self,
+        conn: &Connection,
+        provider: &str,
+        amount: u128,
+    ) -> Result<Result<(), BudgetExceeded>> {
+        if let Some(limit) = self.limits.per_request
+            && amount > limit
+        {
+            return Ok(Err(BudgetExceeded::PerRequest { amount, limit }));
+        }
+
+        let now = Utc::now();
+        let scopes = [
+            (
+                BudgetScope::Provider(provider.to_string()),
+                self.provider_limits
+                    .get(provider)
+                    .unwrap_or(&self.limits.provider),
+            ),
+            (BudgetScope::Total, &self.limits.total),
+        ];
+        for (scope, limits) in scopes {
+            for period in BudgetPeriod::ALL {
+                let Some(limit) = limits.get(period) else {
+                    continue;
+                };
+
+                let provider = match &scope {
+                    BudgetScope::Provider(provider) => Some(provider.as_str()),
+                    BudgetScope::Total => None,
+                };
+                let spent = query_spent(conn, provider, period.since(now))?;
+
+                // an exhausted budget refuses even requests that look free
+                if spent >= limit || spent.saturating_add(amount) > limit {
+                    return Ok(Err(BudgetExceeded::Budget {
+                        scope,
+                        period,
+                        limit,
+                        spent,
+                        amount,
+                    }));
+                }
+            }
+        }
+
+        Ok(Ok(()))
+    }
+}
+
+/// what `ticket` adds over the last ticket sent on its channel
+fn ticket_spend(conn: &Connection, channel: &str, ticket: &PaymentTicket) -> Result<u128> {
+    let amount = ticket.amount_value()?;
+    let last = conn
+        .query_row(
+            "SELECT amount, signature FROM spends WHERE channel = ?1 ORDER BY id DESC LIMIT 1",
+            params![channel],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+        )
+        .optional()?;
+
+    let Some((last_amount, last_signature)) = last else {
+        return Ok(amount);
+    };
+    if last_signature == ticket.signature {
+        return Ok(0);
+    }
+
+    let last_amount: u128 = last_amount
+        .parse()
+        .with_context(|| format!("invalid ticket amount '{}' in spend database", last_amount))?;
+    // amounts restart at the price once the host claimed
+    Ok(if amount > last_amount {
+        amount - last_amount
+    } else {
+        amount
+    })
+}
+
+/// sum of the spends of `provider` (or all providers) since `since` (or ever), reading only
+/// the rows of the window through the timestamp indexes
+///
+/// sqlite sums 64-bit integers and fails instead of wrapping, which refuses the ticket
+fn query_spent(
+    conn: &Connection,
+    provider: Option<&str>,
+    since: Option<DateTime<Utc>>,
+) -> Result<u128> {
+    let since = since.map(timestamp).unwrap_or_default();
+    let spent: i64 = match provider {
+        Some(provider) => conn.query_row(
+            "SELECT COALESCE(SUM(CAST(spent AS INTEGER)), 0) FROM spends
+             WHERE provider = ?1 AND spent_at >= ?2",
+            params![provider, since],
+            |row| row.get(0),
+        ),
+        None => conn.query_row(
+            "SELECT COALESCE(SUM(CAST(spent AS INTEGER)), 0) FROM spends WHERE spent_at >= ?1",
+            params![since],
+            |row| row.get(0),
+        ),
+    }
+    .context("failed to sum spends")?;
+    Ok(spent.max(0) as u128)
+}
+
+/// fixed width rfc3339 in UTC, so timestamps compare as strings
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTRACT: &str = "0x00000000000000000000000000000000000000aa";
+
+    fn ticket(nonce: u128, amount: u128) -> PaymentTicket {
+        PaymentTicket {
+            hidden_payment_channels_contract_address: CONTRACT.to_string(),
+            to_railgun_address: "0zk1host".to_string(),
+            nonce: nonce.to_string(),
+            amount: amount.to_string(),
+            signature: format!("0x{:064x}{:064x}", nonce, amount),
+        }
+    }
+
+    #[test]
+    fn tickets_spend_what_they_add() {
+        let budget = SpendBudget::open_in_memory(BudgetLimits::default()).unwrap();
+        assert_eq!(
+            budget.authorize("alice", &ticket(1, 100)).unwrap().unwrap(),
+            100
+        );
+        assert_eq!(
+            budget.authorize("alice", &ticket(2, 250)).unwrap().unwrap(),
+            150
+        );
+        // the same ticket sent again spends nothing
+        assert_eq!(
+            budget.authorize("alice", &ticket(2, 250)).unwrap().unwrap(),
+            0
+        );
+        // amounts restart after a claim
+        assert_eq!(
+            budget.authorize("alice", &ticket(3, 100)).unwrap().unwrap(),
+            100
+        );
+
+        assert_eq!(
+            budget.latest_ticket(Some(CONTRACT)).unwrap(),
+            Some((3, 100))
+        );
+        assert_eq!(
+            budget.spent(Some("alice"), BudgetPeriod::Hour).unwrap(),
+            350
+        );
+        assert_eq!(
+            budget.spent(Some("bob"), BudgetPeriod::Lifetime).unwrap(),
+            0
+        );
+        assert_eq!(budget.spent(None, BudgetPeriod::Day).unwrap(), 350);
+    }
+
+    #[test]
+    fn budgets_refuse_spends_over_the_limit() {
+        let limits = BudgetLimits {
+            per_request: Some(200),
+            provider: SpendLimits {
+                hourly: Some(300),
+                ..Default::default()
+            },
+            total: SpendLimits {
+                lifetime: Some(500),
+                ..Default::default()
+            },
+        };
+        let budget = SpendBudget::open_in_memory(limits).unwrap();
+
+        assert!(matches!(
+            budget.check("alice", 201).unwrap(),
+            Err(BudgetExceeded::PerRequest {
+                amount: 201,
+                limit: 200
+            })
+        ));
+        budget.authorize("alice", &ticket(1, 200)).unwrap().unwrap();
+        assert!(matches!(
+            budget.check("alice", 101).unwrap(),
+            Err(BudgetExceeded::Budget {
+                period: BudgetPeriod::Hour,
+                spent: 200,
+                ..
+            })
+        ));
+        budget.check("alice", 100).unwrap().unwrap();
+        budget.check("bob", 200).unwrap().unwrap();
+
+        // the overall budget covers every provider
+        let mut bob = ticket(1, 200);
+        bob.hidden_payment_channels_contract_address =
+            "0x00000000000000000000000000000000000000bb".to_string();
+        budget.authorize("bob", &bob).unwrap().unwrap();
+        assert!(matches!(
+            budget.check("carol", 101).unwrap(),
+            Err(BudgetExceeded::Budget {
+                scope: BudgetScope::Total,
+                period: BudgetPeriod::Lifetime,
+                spent: 400,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn old_spends_leave_the_window() {
+        let budget = SpendBudget::open_in_memory(BudgetLimits::default()).unwrap();
+        budget.authorize("alice", &ticket(1, 100)).unwrap().unwrap();
+        budget
+            .conn
+            .lock()
+            .execute(
+                "UPDATE spends SET spent_at = ?1",
+                params![timestamp(Utc::now() - Duration::hours(2))],
+            )
+            .unwrap();
+        budget.authorize("alice", &ticket(2, 150)).unwrap().unwrap();
+
+        assert_eq!(budget.spent(Some("alice"), BudgetPeriod::Hour).unwrap(), 50);
+        assert_eq!(budget.spent(Some("alice"), BudgetPeriod::Day).unwrap(), 150);
+        assert_eq!(budget.spent(None, BudgetPeriod::Lifetime).unwrap(), 150);
+    }
+}
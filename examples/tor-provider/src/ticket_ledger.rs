@@ -0,0 +1,1141 @@
+use crate::hpc_service::{LotteryTicket, PaymentTicket};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use rusqlite::{Connection, OptionalExtension, Row, params};
+use serde::Serialize;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{debug, info};
+
+/// a ticket accepted by the host, as stored in the ledger
+#[derive(Debug, Clone)]
+pub struct LedgerTicket {
+    pub id: i64,
+    pub ticket: PaymentTicket,
+    pub received_at: DateTime<Utc>,
+    // requests made with this ticket, the refused replays included
+    pub request_count: u64,
+    pub claimed_at: Option<DateTime<Utc>>,
+    // set for lottery tickets, only winners can be claimed
+    pub lottery: Option<LotteryOutcome>,
+}
+
+impl LedgerTicket {
+    /// whether this ticket has been claimed onchain
+    pub fn is_claimed(&self) -> bool {
+        self.claimed_at.is_some()
+    }
+
+    /// whether this ticket is a lottery ticket
+    pub fn is_lottery(&self) -> bool {
+        self.lottery.is_some()
+    }
+}
+
+/// win probability and draw result of a recorded lottery ticket
+#[derive(Debug, Clone, Copy)]
+pub struct LotteryOutcome {
+    pub win_probability_ppm: u32,
+    pub winner: bool,
+}
+
+/// expected-value accounting of the lottery tickets received on a channel
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LotterySummary {
+    pub tickets: u64,
+    pub winners: u64,
+    // sum of face value * win probability (wei)
+    #[serde(with = "crate::pricing::wei")]
+    pub expected_value: u128,
+    // sum of the face values of winning tickets (wei)
+    #[serde(with = "crate::pricing::wei")]
+    pub won_value: u128,
+}
+
+/// persistent host-side record of accepted payment tickets, per HiddenPaymentChannels contract
+///
+/// tickets carry cumulative amounts, so the latest unclaimed ticket of a contract is always the
+/// most valuable one the host holds. Lottery tickets are recorded in the same table but each one
+/// stands on its own, only winners are ever handed to the claim path. Both kinds share the
+/// channel's nonces, since the contract accepts a claim of either kind only above the last
+/// claimed nonce
+#[derive(Clone)]
+pub struct TicketLedger {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl TicketLedger {
+    /// open (or create) a ledger database at the given path
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create ledger directory {:?}", parent))?;
+        }
+
+        info!("opening ticket ledger at {:?}", path);
+        let conn = Connection::open(path)
+            .with_context(|| format!("failed to open ticket ledger {:?}", path))?;
+        Self::with_connection(conn)
+    }
+
+    /// open an in-memory ledger, nothing is persisted
+    pub fn open_in_memory() -> Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(conn: Connection) -> Result<Self> {
+        conn.execute_batch(
+            "
+            PRAGMA journal_mode = WAL;
+
+            CREATE TABLE IF NOT EXISTS tickets (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                channel TEXT NOT NULL,
+                contract_address TEXT NOT NULL,
+                to_railgun_address TEXT NOT NULL,
+                nonce TEXT NOT NULL,
+                amount TEXT NOT NULL,
+                signature TEXT NOT NULL,
+                received_at TEXT NOT NULL,
+                request_count INTEGER NOT NULL DEFAULT 1,
+                claimed_at TEXT,
+                win_probability_ppm INTEGER,
+                winner INTEGER,
+                UNIQUE (channel, signature)
+            );
+
+            CREATE INDEX IF NOT EXISTS tickets_contract_idx
+                ON tickets (channel, id);
+
+            CREATE TABLE IF NOT EXISTS claims (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                channel TEXT NOT NULL,
+                nonce TEXT NOT NULL,
+                amount TEXT NOT NULL,
+                attempted_at TEXT NOT NULL,
+                success INTEGER NOT NULL,
+                error TEXT
+            );
+
+            CREATE TABLE IF NOT EXISTS debts (
+                channel TEXT PRIMARY KEY,
+                debt TEXT NOT NULL,
+                updated_at TEXT NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS carried (
+                channel TEXT PRIMARY KEY,
+                amount TEXT NOT NULL,
+                updated_at TEXT NOT NULL
+            );
+            ",
+        )
+        .context("failed to initialize ticket ledger schema")?;
+
+        // ledgers created before lottery tickets were supported
+        add_column_if_missing(&conn, "tickets", "win_probability_ppm", "INTEGER")?;
+        add_column_if_missing(&conn, "tickets", "winner", "INTEGER")?;
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// accept a ticket only if it moves the channel forward: its nonce must not go backwards and
+    /// its cumulative amount must grow by at least `price` over the latest unclaimed ticket
+    ///
+    /// the check and the insert happen under the same lock so concurrent requests cannot both
+    /// spend the same increment
+    pub fn accept_ticket(
+        &self,
+        ticket: &PaymentTicket,
+        price: u128,
+    ) -> Result<Result<LedgerTicket, TicketRejection>> {
+        let conn = self.conn.lock();
+        let channel = channel_key(&ticket.hidden_payment_channels_contract_address);
+        let latest = query_latest(&conn, &channel)?;
+        let lottery_nonce = query_latest_lottery_nonce(&conn, &channel)?;
+        let carried = query_carried(&conn, &channel)?;
+
+        if let Err(rejection) =
+            check_progression(latest.as_ref(), lottery_nonce, carried, ticket, price)?
+        {
+            if let TicketRejection::Replayed { .. } = rejection {
+                count_replay(&conn, &channel, ticket)?;
+            }
+            return Ok(Err(rejection));
+        }
+
+        insert_ticket(&conn, ticket, None).map(Ok)
+    }
+
+    /// accept a ticket on a channel that may owe postpaid debt, returns the ticket and the debt
+    /// left
+    ///
+    /// the ticket must add `price` plus the part of the debt above `credit_limit`, whatever it
+    /// adds beyond the price pays the debt down
+    pub fn accept_postpaid_ticket(
+        &self,
+        ticket: &PaymentTicket,
+        price: u128,
+        credit_limit: u128,
+    ) -> Result<Result<(LedgerTicket, u128), TicketRejection>> {
+        let conn = self.conn.lock();
+        let channel = channel_key(&ticket.hidden_payment_channels_contract_address);
+        let latest = query_latest(&conn, &channel)?;
+        let lottery_nonce = query_latest_lottery_nonce(&conn, &channel)?;
+        let carried = query_carried(&conn, &channel)?;
+        let debt = query_debt(&conn, &channel)?;
+
+        let charge = price.saturating_add(debt.saturating_sub(credit_limit));
+        if let Err(rejection) =
+            check_progression(latest.as_ref(), lottery_nonce, carried, ticket, charge)?
+        {
+            if let TicketRejection::Replayed { .. } = rejection {
+                count_replay(&conn, &channel, ticket)?;
+            }
+            return Ok(Err(rejection));
+        }
+
+        // amounts restart after a claim, apart from what the tickets in flight carried over
+        let amount = ticket.amount_value()?;
+        let added = match latest {
+            Some(latest) if !latest.is_claimed() => {
+                amount.saturating_sub(latest.ticket.amount_value()?)
+            }
+            Some(_) => amount.saturating_sub(carried),
+            None => amount,
+        };
+        let debt = debt.saturating_sub(added.saturating_sub(price));
+
+        let recorded = insert_ticket(&conn, ticket, None)?;
+        update_debt(&conn, &channel, debt)?;
+        Ok(Ok((recorded, debt)))
+    }
+
+    /// accept a lottery ticket if it has not been seen before, its nonce is above every ticket
+    /// of the channel and its face value is at least `min_face_value`
+    ///
+    /// lottery tickets are not cumulative, each one pays for a single request and gets a fresh
+    /// nonce so winners can be claimed one after another in nonce order
+    pub fn accept_lottery_ticket(
+        &self,
+        lottery: &LotteryTicket,
+        min_face_value: u128,
+        winner: bool,
+    ) -> Result<Result<LedgerTicket, TicketRejection>> {
+        let conn = self.conn.lock();
+        let ticket = &lottery.ticket;
+        let channel = channel_key(&ticket.hidden_payment_channels_contract_address);
+        let nonce = ticket.nonce_value()?;
+
+        let seen: bool = conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM tickets WHERE channel = ?1 AND signature = ?2)",
+            params![channel, ticket.signature],
+            |row| row.get(0),
+        )?;
+        if seen {
+            count_replay(&conn, &channel, ticket)?;
+            return Ok(Err(TicketRejection::Replayed {
+                nonce,
+                expected_amount: min_face_value,
+            }));
+        }
+
+        let cumulative_nonce = query_latest(&conn, &channel)?
+            .map(|latest| latest.ticket.nonce_value())
+            .transpose()?;
+        let latest_nonce = query_latest_lottery_nonce(&conn, &channel)?.max(cumulative_nonce);
+        if let Some(latest_nonce) = latest_nonce
+            && nonce <= latest_nonce
+        {
+            return Ok(Err(TicketRejection::StaleNonce {
+                nonce,
+                latest_nonce: latest_nonce + 1,
+                expected_amount: min_face_value,
+            }));
+        }
+
+        let amount = ticket.amount_value()?;
+        if amount < min_face_value {
+            return Ok(Err(TicketRejection::Underpaid {
+                amount,
+                expected_amount: min_face_value,
+            }));
+        }
+
+        let outcome = LotteryOutcome {
+            win_probability_ppm: lottery.win_probability_ppm,
+            winner,
+        };
+        insert_ticket(&conn, ticket, Some(outcome)).map(Ok)
+    }
+
+    /// what the next cumulative ticket for a contract must carry to pay `price`
+    pub fn next_ticket(&self, contract_address: &str, price: u128) -> Result<NextTicket> {
+        let conn = self.conn.lock();
+        let channel = channel_key(contract_address);
+        let latest = query_latest(&conn, &channel)?;
+        let lottery_nonce = query_latest_lottery_nonce(&conn, &channel)?;
+        let carried = query_carried(&conn, &channel)?;
+        next_ticket(latest.as_ref(), lottery_nonce, carried, price)
+    }
+
+    /// what a contract owes for postpaid responses (wei)
+    pub fn debt(&self, contract_address: &str) -> Result<u128> {
+        let conn = self.conn.lock();
+        query_debt(&conn, &channel_key(contract_address))
+    }
+
+    /// add the metered cost of a postpaid response to a contract's debt, returns the new debt
+    pub fn add_debt(&self, contract_address: &str, amount: u128) -> Result<u128> {
+        let conn = self.conn.lock();
+        let channel = channel_key(contract_address);
+        let debt = query_debt(&conn, &channel)?.saturating_add(amount);
+        update_debt(&conn, &channel, debt)?;
+        debug!(
+            "{} owes {} wei after a {} wei response",
+            channel, debt, amount
+        );
+        Ok(debt)
+    }
+
+    /// the best ticket the host can still claim for a contract
+    ///
+    /// for lottery channels this is the oldest unclaimed winner newer than the last claim, the
+    /// contract only accepts rising nonces so claiming a newer winner first would forfeit it.
+    /// The same goes for the latest cumulative ticket: when its nonce is below the winner's and
+    /// it is worth more than the winner's face value it is claimed first, the winner stays
+    /// claimable after it
+    pub fn best_unclaimed_ticket(&self, contract_address: &str) -> Result<Option<LedgerTicket>> {
+        let conn = self.conn.lock();
+        let channel = channel_key(contract_address);
+
+        let winner = conn
+            .query_row(
+                &format!(
+                    "SELECT {} FROM tickets
+                     WHERE channel = ?1 AND winner = 1 AND claimed_at IS NULL AND id > (
+                        SELECT COALESCE(MAX(id), 0) FROM tickets
+                        WHERE channel = ?1 AND claimed_at IS NOT NULL
+                     )
+                     ORDER BY id LIMIT 1",
+                    TICKET_COLUMNS
+                ),
+                params![channel],
+                ledger_ticket_from_row,
+            )
+            .optional()?;
+        let cumulative = query_latest(&conn, &channel)?.filter(|ticket| !ticket.is_claimed());
+
+        let (Some(winner), Some(cumulative)) = (&winner, &cumulative) else {
+            return Ok(winner.or(cumulative));
+        };
+        let cumulative_first = cumulative.ticket.nonce_value()? < winner.ticket.nonce_value()?
+            && cumulative.ticket.amount_value()? > winner.ticket.amount_value()?;
+        Ok(Some(if cumulative_first {
+            cumulative.clone()
+        } else {
+            winner.clone()
+        }))
+    }
+
+    /// expected-value accounting of the lottery tickets received for a contract
+    pub fn lottery_summary(&self, contract_address: &str) -> Result<LotterySummary> {
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare(
+            "SELECT amount, win_probability_ppm, winner FROM tickets
+             WHERE channel = ?1 AND win_probability_ppm IS NOT NULL",
+        )?;
+        let rows = stmt
+            .query_map(params![channel_key(contract_address)], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, u32>(1)?,
+                    row.get::<_, bool>(2)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut summary = LotterySummary::default();
+        for (amount, win_probability_ppm, winner) in rows {
+            let face_value: u128 = amount
+                .parse()
+                .with_context(|| format!("invalid ticket amount '{}' in ledger", amount))?;
+            summary.tickets += 1;
+            summary.expected_value = summary.expected_value.saturating_add(
+                face_value.saturating_mul(win_probability_ppm as u128) / crate::lottery::PPM,
+            );
+            if winner {
+                summary.winners += 1;
+                summary.won_value = summary.won_value.saturating_add(face_value);
+            }
+        }
+        Ok(summary)
+    }
+
+    /// contracts the host has accepted tickets for
+    pub fn contract_addresses(&self) -> Result<Vec<String>> {
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare(
+            "SELECT contract_address FROM tickets
+             WHERE id IN (SELECT MAX(id) FROM tickets GROUP BY channel)",
+        )?;
+        let addresses = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(addresses)
+    }
+
+    /// mark a ticket as claimed onchain, together with every ticket the claim settles
+    ///
+    /// the contract only accepts nonces above the claimed one afterwards and amounts restart, so
+    /// tickets with a lower nonce can no longer be claimed. A cumulative claim also settles the
+    /// cumulative tickets accepted while it was in flight: they still carry the claimed amount,
+    /// and the issuer counts the requests they paid for into the first amount after the claim,
+    /// so what they added on top of the claimed amount is carried into the next ticket
+    pub fn mark_claimed(&self, ticket: &PaymentTicket) -> Result<()> {
+        let conn = self.conn.lock();
+        let channel = channel_key(&ticket.hidden_payment_channels_contract_address);
+        let claimed_nonce = ticket.nonce_value()?;
+
+        let claimed = conn
+            .query_row(
+                &format!(
+                    "SELECT {} FROM tickets WHERE channel = ?1 AND signature = ?2",
+                    TICKET_COLUMNS
+                ),
+                params![channel, ticket.signature],
+                ledger_ticket_from_row,
+            )
+            .optional()?
+            .ok_or_else(|| anyhow::anyhow!("ticket to mark as claimed is not in the ledger"))?;
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM tickets WHERE channel = ?1 AND claimed_at IS NULL",
+            TICKET_COLUMNS
+        ))?;
+        let unclaimed = stmt
+            .query_map(params![channel], ledger_ticket_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let claimed_at = Utc::now().to_rfc3339();
+        let mut settled = 0;
+        let mut in_flight_amount = 0;
+        for pending in unclaimed {
+            let nonce = pending.ticket.nonce_value()?;
+            let superseded =
+                nonce <= claimed_nonce || (!claimed.is_lottery() && !pending.is_lottery());
+            if superseded {
+                conn.execute(
+                    "UPDATE tickets SET claimed_at = ?1 WHERE id = ?2",
+                    params![claimed_at, pending.id],
+                )?;
+                settled += 1;
+                if nonce > claimed_nonce {
+                    in_flight_amount = in_flight_amount.max(pending.ticket.amount_value()?);
+                }
+            }
+        }
+        let carried = in_flight_amount.saturating_sub(claimed.ticket.amount_value()?);
+        update_carried(&conn, &channel, carried)?;
+        debug!(
+            "claim of nonce {} settled {} tickets for {}, {} wei carried over",
+            claimed_nonce, settled, channel, carried
+        );
+        Ok(())
+    }
+
+    /// when the oldest claimable ticket received since the last claim arrived, losing lottery
+    /// tickets are ignored
+    pub fn unclaimed_since(&self, contract_address: &str) -> Result<Option<DateTime<Utc>>> {
+        let conn = self.conn.lock();
+        let received_at: Option<String> = conn.query_row(
+            "SELECT MIN(received_at) FROM tickets
+             WHERE channel = ?1 AND winner IS NOT 0 AND id > (
+                SELECT COALESCE(MAX(id), 0) FROM tickets
+                WHERE channel = ?1 AND claimed_at IS NOT NULL
+             )",
+            params![channel_key(contract_address)],
+            |row| row.get(0),
+        )?;
+        Ok(received_at.map(parse_timestamp))
+    }
+
+    /// record the outcome of a claim attempt
+    pub fn record_claim_attempt(
+        &self,
+        ticket: &PaymentTicket,
+        outcome: std::result::Result<(), &str>,
+    ) -> Result<()> {
+        let conn = self.conn.lock();
+        conn.execute(
+            "INSERT INTO claims (channel, nonce, amount, attempted_at, success, error)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                channel_key(&ticket.hidden_payment_channels_contract_address),
+                ticket.nonce,
+                ticket.amount,
+                Utc::now().to_rfc3339(),
+                outcome.is_ok(),
+                outcome.err(),
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+/// minimum amount and nonce of the next cumulative ticket on a channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NextTicket {
+    pub expected_amount: u128,
+    pub min_nonce: u128,
+}
+
+/// why a ticket was not accepted, every variant carries the cumulative amount the host expected
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum TicketRejection {
+    #[error("ticket with nonce {nonce} was already used, expected amount {expected_amount}")]
+    Replayed { nonce: u128, expected_amount: u128 },
+
+    #[error("ticket nonce {nonce} is older than {latest_nonce}, expected amount {expected_amount}")]
+    StaleNonce {
+        nonce: u128,
+        latest_nonce: u128,
+        expected_amount: u128,
+    },
+
+    #[error("ticket amount {amount} is too low, expected amount {expected_amount}")]
+    Underpaid { amount: u128, expected_amount: u128 },
+}
+
+impl TicketRejection {
+    /// the minimum cumulative amount the next ticket must carry
+    pub fn expected_amount(&self) -> u128 {
+        match self {
+            Self::Replayed {
+                expected_amount, ..
+            }
+            | Self::StaleNonce {
+                expected_amount, ..
+            }
+            | Self::Underpaid {
+                expected_amount, ..
+            } => *expected_amount,
+        }
+    }
+}
+
+/// the ticket that would follow `latest` for a request costing `price`, its nonce must also be
+/// above the latest lottery ticket's
+///
+/// `carried` is what the tickets in flight during the last claim added on top of it, the first
+/// ticket after the claim must still pay for those requests
+fn next_ticket(
+    latest: Option<&LedgerTicket>,
+    lottery_nonce: Option<u128>,
+    carried: u128,
+    price: u128,
+) -> Result<NextTicket> {
+    let (expected_amount, min_nonce) = match latest {
+        // first ticket on this channel
+        None => (price, 0),
+        // the contract only accepts nonces above the last claimed one, and amounts restart
+        Some(latest) if latest.is_claimed() => (
+            carried.saturating_add(price),
+            latest.ticket.nonce_value()? + 1,
+        ),
+        Some(latest) => (
+            latest.ticket.amount_value()?.saturating_add(price),
+            latest.ticket.nonce_value()?,
+        ),
+    };
+    Ok(NextTicket {
+        expected_amount,
+        min_nonce: min_nonce.max(lottery_nonce.map_or(0, |nonce| nonce.saturating_add(1))),
+    })
+}
+
+/// check that `ticket` is a valid successor of `latest` and of the latest lottery ticket for a
+/// request costing `price`
+fn check_progression(
+    latest: Option<&LedgerTicket>,
+    lottery_nonce: Option<u128>,
+    carried: u128,
+    ticket: &PaymentTicket,
+    price: u128,
+) -> Result<Result<(), TicketRejection>> {
+    let nonce = ticket.nonce_value()?;
+    let amount = ticket.amount_value()?;
+    let NextTicket {
+        expected_amount,
+        min_nonce,
+    } = next_ticket(latest, lottery_nonce, carried, price)?;
+
+    if latest
+        .is_some_and(|latest| !latest.is_claimed() && latest.ticket.signature == ticket.signature)
+    {
+        return Ok(Err(TicketRejection::Replayed {
+            nonce,
+            expected_amount,
+        }));
+    }
+
+    if nonce < min_nonce {
+        return Ok(Err(TicketRejection::StaleNonce {
+            nonce,
+            latest_nonce: min_nonce,
+            expected_amount,
+        }));
+    }
+
+    if amount < expected_amount {
+        return Ok(Err(TicketRejection::Underpaid {
+            amount,
+            expected_amount,
+        }));
+    }
+
+    Ok(Ok(()))
+}
+
+fn insert_ticket(
+    conn: &Connection,
+    ticket: &PaymentTicket,
+    lottery: Option<LotteryOutcome>,
+) -> Result<LedgerTicket> {
+    let channel = channel_key(&ticket.hidden_payment_channels_contract_address);
+
+    conn.execute(
+        "INSERT INTO tickets
+            (channel, contract_address, to_railgun_address, nonce, amount, signature, received_at,
+             win_probability_ppm, winner)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            channel,
+            ticket.hidden_payment_channels_contract_address,
+            ticket.to_railgun_address,
+            ticket.nonce,
+            ticket.amount,
+            ticket.signature,
+            Utc::now().to_rfc3339(),
+            lottery.map(|l| l.win_probability_ppm),
+            lottery.map(|l| l.winner),
+        ],
+    )?;
+
+    let recorded = conn
+        .query_row(
+            &format!(
+                "SELECT {} FROM tickets WHERE channel = ?1 AND signature = ?2",
+                TICKET_COLUMNS
+            ),
+            params![channel, ticket.signature],
+            ledger_ticket_from_row,
+        )
+        .context("failed to read back recorded ticket")?;
+
+    debug!(
+        "recorded ticket with nonce {} for {}",
+        recorded.ticket.nonce, channel
+    );
+    Ok(recorded)
+}
+
+/// count another request made with an already recorded ticket, the request itself is refused
+fn count_replay(conn: &Connection, channel: &str, ticket: &PaymentTicket) -> Result<()> {
+    let request_count: u64 = conn.query_row(
+        "UPDATE tickets SET request_count = request_count + 1
+         WHERE channel = ?1 AND signature = ?2
+         RETURNING request_count",
+        params![channel, ticket.signature],
+        |row| row.get(0),
+    )?;
+    debug!(
+        "ticket with nonce {} for {} was presented {} times",
+        ticket.nonce, channel, request_count
+    );
+    Ok(())
+}
+
+/// the latest cumulative ticket of a channel, lottery tickets are not part of the progression
+fn query_latest(conn: &Connection, channel: &str) -> Result<Option<LedgerTicket>> {
+    conn.query_row(
+        &format!(
+            "SELECT {} FROM tickets
+             WHERE channel = ?1 AND win_probability_ppm IS NULL
+             ORDER BY id DESC LIMIT 1",
+            TICKET_COLUMNS
+        ),
+        params![channel],
+        ledger_ticket_from_row,
+    )
+    .optional()
+    .map_err(Into::into)
+}
+
+fn query_debt(conn: &Connection, channel: &str) -> Result<u128> {
+    let debt: Option<String> = conn
+        .query_row(
+            "SELECT debt FROM debts WHERE channel = ?1",
+            params![channel],
+            |row| row.get(0),
+        )
+        .optional()?;
+    debt.map(|debt| {
+        debt.parse()
+            .with_context(|| format!("invalid debt '{}' in ledger", debt))
+    })
+    .transpose()
+    .map(Option::unwrap_or_default)
+}
+
+fn update_debt(conn: &Connection, channel: &str, debt: u128) -> Result<()> {
+    conn.execute(
+        "INSERT INTO debts (channel, debt, updated_at) VALUES (?1, ?2, ?3)
+         ON CONFLICT (channel) DO UPDATE SET debt = ?2, updated_at = ?3",
+        params![channel, debt.to_string(), Utc::now().to_rfc3339()],
+    )?;
+    Ok(())
+}
+
+/// what the tickets in flight during the last claim of a channel carried over
+fn query_carried(conn: &Connection, channel: &str) -> Result<u128> {
+    let carried: Option<String> = conn
+        .query_row(
+            "SELECT amount FROM carried WHERE channel = ?1",
+            params![channel],
+            |row| row.get(0),
+        )
+        .optional()?;
+    carried
+        .map(|carried| {
+            carried
+                .parse()
+                .with_context(|| format!("invalid carried amount '{}' in ledger", carried))
+        })
+        .transpose()
+        .map(Option::unwrap_or_default)
+}
+
+fn update_carried(conn: &Connection, channel: &str, carried: u128) -> Result<()> {
+    conn.execute(
+        "INSERT INTO carried (channel, amount, updated_at) VALUES (?1, ?2, ?3)
+         ON CONFLICT (channel) DO UPDATE SET amount = ?2, updated_at = ?3",
+        params![channel, carried.to_string(), Utc::now().to_rfc3339()],
+    )?;
+    Ok(())
+}
+
+/// the highest lottery ticket nonce of a channel, nonces are stored as decimal strings so they
+/// are compared as numbers
+fn query_latest_lottery_nonce(conn: &Connection, channel: &str) -> Result<Option<u128>> {
+    Ok(query_lottery_nonces(conn, channel)?.into_iter().max())
+}
+
+fn query_lottery_nonces(conn: &Connection, channel: &str) -> Result<Vec<u128>> {
+    let mut stmt = conn.prepare(
+        "SELECT nonce FROM tickets WHERE channel = ?1 AND win_probability_ppm IS NOT NULL",
+    )?;
+    let nonces = stmt
+        .query_map(params![channel], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    nonces
+        .iter()
+        .map(|nonce| {
+            nonce
+                .parse()
+                .with_context(|| format!("invalid ticket nonce '{}' in ledger", nonce))
+        })
+        .collect()
+}
+
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<rusqlite::Result<Vec<_>>>()?
+        .iter()
+        .any(|name| name == column);
+
+    if !exists {
+        info!("adding column {} to ledger table {}", column, table);
+        conn.execute_batch(&format!(
+            "ALTER TABLE {} ADD COLUMN {} {}",
+            table, column, definition
+        ))?;
+    }
+    Ok(())
+}
+
+const TICKET_COLUMNS: &str = "id, contract_address, to_railgun_address, nonce, amount, signature, \
+     received_at, request_count, claimed_at, win_probability_ppm, winner";
+
+fn ledger_ticket_from_row(row: &Row<'_>) -> rusqlite::Result<LedgerTicket> {
+    Ok(LedgerTicket {
+        id: row.get(0)?,
+        ticket: PaymentTicket {
+            hidden_payment_channels_contract_address: row.get(1)?,
+            to_railgun_address: row.get(2)?,
+            nonce: row.get(3)?,
+            amount: row.get(4)?,
+            signature: row.get(5)?,
+        },
+        received_at: parse_timestamp(row.get(6)?),
+        request_count: row.get(7)?,
+        claimed_at: row.get::<_, Option<String>>(8)?.map(parse_timestamp),
+        lottery: match row.get::<_, Option<u32>>(9)? {
+            Some(win_probability_ppm) => Some(LotteryOutcome {
+                win_probability_ppm,
+                winner: row.get::<_, Option<bool>>(10)?.unwrap_or(false),
+            }),
+            None => None,
+        },
+    })
+}
+
+fn parse_timestamp(value: String) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(&value)
+        .map(|t| t.with_timezone(&Utc))
+        .unwrap_or_default()
+}
+
+/// channels are keyed by lowercase contract address so checksummed and plain hex match, the
+/// original address is kept as issued since the HiddenPaymentChannels service compares it verbatim
+pub fn channel_key(address: &str) -> String {
+    address.to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payment_backend::TicketIssuer;
+    use k256::ecdsa::SigningKey;
+
+    const PRICE: u128 = 100;
+    const CONTRACT: &str = "0x00000000000000000000000000000000000000aa";
+
+    fn issuer() -> TicketIssuer {
+        TicketIssuer::new(
+            SigningKey::from_slice(&[7u8; 32]).unwrap(),
+            "0zk1host",
+            CONTRACT,
+            PRICE,
+        )
+    }
+
+    fn ticket(nonce: u128, amount: u128) -> PaymentTicket {
+        PaymentTicket {
+            hidden_payment_channels_contract_address: CONTRACT.to_string(),
+            to_railgun_address: "0zk1host".to_string(),
+            nonce: nonce.to_string(),
+            amount: amount.to_string(),
+            signature: format!("0x{:064x}{:064x}", nonce, amount),
+        }
+    }
+
+    #[test]
+    fn lottery_and_cumulative_tickets_share_nonces() {
+        let ledger = TicketLedger::open_in_memory().unwrap();
+        ledger
+            .accept_ticket(&ticket(1, PRICE), PRICE)
+            .unwrap()
+            .unwrap();
+
+        let lottery = |nonce| LotteryTicket {
+            ticket: ticket(nonce, 10 * PRICE),
+            win_probability_ppm: 100_000,
+        };
+        assert!(matches!(
+            ledger
+                .accept_lottery_ticket(&lottery(1), PRICE, false)
+                .unwrap(),
+            Err(TicketRejection::StaleNonce {
+                latest_nonce: 2,
+                ..
+            })
+        ));
+        ledger
+            .accept_lottery_ticket(&lottery(5), PRICE, false)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            ledger.next_ticket(CONTRACT, PRICE).unwrap(),
+            NextTicket {
+                expected_amount: 2 * PRICE,
+                min_nonce: 6,
+            }
+        );
+        assert!(matches!(
+            ledger.accept_ticket(&ticket(2, 2 * PRICE), PRICE).unwrap(),
+            Err(TicketRejection::StaleNonce {
+                latest_nonce: 6,
+                ..
+            })
+        ));
+        ledger
+            .accept_ticket(&ticket(6, 2 * PRICE), PRICE)
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn claims_the_ticket_worth_more_first() {
+        let ledger = TicketLedger::open_in_memory().unwrap();
+        ledger
+            .accept_ticket(&ticket(1, 5 * PRICE), PRICE)
+            .unwrap()
+            .unwrap();
+        let lottery = |nonce, face_value| LotteryTicket {
+            ticket: ticket(nonce, face_value),
+            win_probability_ppm: 100_000,
+        };
+        ledger
+            .accept_lottery_ticket(&lottery(2, 2 * PRICE), PRICE, true)
+            .unwrap()
+            .unwrap();
+
+        // claiming the winner would forfeit the cumulative ticket below it
+        let best = ledger.best_unclaimed_ticket(CONTRACT).unwrap().unwrap();
+        assert_eq!(best.ticket.nonce, "1");
+        ledger.mark_claimed(&best.ticket).unwrap();
+        let best = ledger.best_unclaimed_ticket(CONTRACT).unwrap().unwrap();
+        assert_eq!(best.ticket.nonce, "2");
+        ledger.mark_claimed(&best.ticket).unwrap();
+        assert!(ledger.best_unclaimed_ticket(CONTRACT).unwrap().is_none());
+
+        // a winner worth more is claimed first
+        ledger
+            .accept_ticket(&ticket(3, PRICE), PRICE)
+            .unwrap()
+            .unwrap();
+        ledger
+            .accept_lottery_ticket(&lottery(4, 10 * PRICE), PRICE, true)
+            .unwrap()
+            .unwrap();
+        let best = ledger.best_unclaimed_ticket(CONTRACT).unwrap().unwrap();
+        assert_eq!(best.ticket.nonce, "4");
+    }
+
+    #[test]
+    fn accepts_tickets_after_a_claim_with_tickets_in_flight() {
+        let ledger = TicketLedger::open_in_memory().unwrap();
+        let issuer = issuer();
+
+        let first = issuer.issue().unwrap();
+        ledger.accept_ticket(&first, PRICE).unwrap().unwrap();
+        let best = ledger.best_unclaimed_ticket(CONTRACT).unwrap().unwrap();
+        assert_eq!(best.ticket.nonce, "1");
+
+        // accepted while the claim of the first ticket is in flight
+        let in_flight = issuer.issue().unwrap();
+        assert_eq!(in_flight.amount_value().unwrap(), 2 * PRICE);
+        ledger.accept_ticket(&in_flight, PRICE).unwrap().unwrap();
+
+        ledger.mark_claimed(&best.ticket).unwrap();
+        issuer.observe_claimed_nonce(1);
+        assert!(ledger.best_unclaimed_ticket(CONTRACT).unwrap().is_none());
+        // the request paid in flight is carried into the next ticket
+        assert_eq!(
+            ledger.next_ticket(CONTRACT, PRICE).unwrap(),
+            NextTicket {
+                expected_amount: 2 * PRICE,
+                min_nonce: 3,
+            }
+        );
+        assert!(matches!(
+            ledger.accept_ticket(&ticket(3, PRICE), PRICE).unwrap(),
+            Err(TicketRejection::Underpaid {
+                expected_amount: 200,
+                ..
+            })
+        ));
+
+        // the issuer restarts at the claim and counts the request paid in flight
+        let next = issuer.issue().unwrap();
+        assert_eq!(next.amount_value().unwrap(), 2 * PRICE);
+        ledger.accept_ticket(&next, PRICE).unwrap().unwrap();
+        let best = ledger.best_unclaimed_ticket(CONTRACT).unwrap().unwrap();
+        assert_eq!(best.ticket.signature, next.signature);
+
+        // a ticket from before the claim can no longer be used
+        assert!(matches!(
+            ledger.accept_ticket(&in_flight, PRICE).unwrap(),
+            Err(TicketRejection::StaleNonce { .. })
+        ));
+    }
+
+    #[test]
+    fn keeps_tickets_across_restarts() {
+        let path = std::env::temp_dir().join(format!("ledger-{}.db", std::process::id()));
+        let issuer = issuer();
+        let first = issuer.issue().unwrap();
+        let second = issuer.issue().unwrap();
+        {
+            let ledger = TicketLedger::open(&path).unwrap();
+            ledger.accept_ticket(&first, PRICE).unwrap().unwrap();
+            ledger.accept_ticket(&second, PRICE).unwrap().unwrap();
+        }
+
+        let ledger = TicketLedger::open(&path).unwrap();
+        // checksummed and lowercase addresses name the same channel
+        let best = ledger
+            .best_unclaimed_ticket(&CONTRACT.to_uppercase().replace("0X", "0x"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(best.ticket.signature, second.signature);
+        assert_eq!(
+            ledger.next_ticket(CONTRACT, PRICE).unwrap().expected_amount,
+            3 * PRICE
+        );
+        assert_eq!(
+            ledger.contract_addresses().unwrap(),
+            vec![CONTRACT.to_string()]
+        );
+        drop(ledger);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    fn recorded(ticket: PaymentTicket, claimed: bool) -> LedgerTicket {
+        LedgerTicket {
+            id: 1,
+            ticket,
+            received_at: Utc::now(),
+            request_count: 1,
+            claimed_at: claimed.then(Utc::now),
+            lottery: None,
+        }
+    }
+
+    #[test]
+    fn tickets_must_add_the_price_on_a_newer_nonce() {
+        // the first ticket on a channel pays for one request
+        assert!(
+            check_progression(None, None, 0, &ticket(1, PRICE), PRICE)
+                .unwrap()
+                .is_ok()
+        );
+        assert!(matches!(
+            check_progression(None, None, 0, &ticket(1, PRICE - 1), PRICE).unwrap(),
+            Err(TicketRejection::Underpaid {
+                expected_amount: PRICE,
+                ..
+            })
+        ));
+
+        let latest = recorded(ticket(3, 2 * PRICE), false);
+        // the same nonce with a higher amount is the next cumulative ticket
+        assert!(
+            check_progression(Some(&latest), None, 0, &ticket(3, 3 * PRICE), PRICE)
+                .unwrap()
+                .is_ok()
+        );
+        assert!(
+            check_progression(Some(&latest), None, 0, &ticket(4, 3 * PRICE), PRICE)
+                .unwrap()
+                .is_ok()
+        );
+        assert!(matches!(
+            check_progression(Some(&latest), None, 0, &ticket(4, 2 * PRICE), PRICE).unwrap(),
+            Err(TicketRejection::Underpaid {
+                expected_amount: 300,
+                ..
+            })
+        ));
+        assert!(matches!(
+            check_progression(Some(&latest), None, 0, &ticket(2, 3 * PRICE), PRICE).unwrap(),
+            Err(TicketRejection::StaleNonce {
+                latest_nonce: 3,
+                expected_amount: 300,
+                ..
+            })
+        ));
+        // a higher price needs a larger step
+        assert!(matches!(
+            check_progression(Some(&latest), None, 0, &ticket(3, 3 * PRICE), 2 * PRICE).unwrap(),
+            Err(TicketRejection::Underpaid {
+                expected_amount: 400,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn replayed_tickets_are_refused() {
+        let latest = recorded(ticket(3, 2 * PRICE), false);
+        assert!(matches!(
+            check_progression(Some(&latest), None, 0, &latest.ticket, PRICE).unwrap(),
+            Err(TicketRejection::Replayed {
+                nonce: 3,
+                expected_amount: 300,
+            })
+        ));
+
+        // through the ledger as well, every request made with the ticket is counted
+        let ledger = TicketLedger::open_in_memory().unwrap();
+        let first = issuer().issue().unwrap();
+        ledger.accept_ticket(&first, PRICE).unwrap().unwrap();
+        for _ in 0..2 {
+            assert!(matches!(
+                ledger.accept_ticket(&first, PRICE).unwrap(),
+                Err(TicketRejection::Replayed { .. })
+            ));
+        }
+        let best = ledger.best_unclaimed_ticket(CONTRACT).unwrap().unwrap();
+        assert_eq!(best.request_count, 3);
+    }
+
+    #[test]
+    fn amounts_restart_after_a_claim() {
+        let claimed = recorded(ticket(3, 5 * PRICE), true);
+        assert!(
+            check_progression(Some(&claimed), None, 0, &ticket(4, PRICE), PRICE)
+                .unwrap()
+                .is_ok()
+        );
+        // requests paid by tickets in flight during the claim are carried over
+        assert!(matches!(
+            check_progression(Some(&claimed), None, PRICE, &ticket(4, PRICE), PRICE).unwrap(),
+            Err(TicketRejection::Underpaid {
+                expected_amount: 200,
+                ..
+            })
+        ));
+        // the claimed nonce cannot be used again, even with the claimed ticket's signature
+        assert!(matches!(
+            check_progression(Some(&claimed), None, 0, &ticket(3, 6 * PRICE), PRICE).unwrap(),
+            Err(TicketRejection::StaleNonce {
+                latest_nonce: 4,
+                ..
+            })
+        ));
+        assert!(matches!(
+            check_progression(Some(&claimed), None, 0, &claimed.ticket, PRICE).unwrap(),
+            Err(TicketRejection::StaleNonce { .. })
+        ));
+
+        // lottery tickets push the minimum nonce further
+        assert!(matches!(
+            check_progression(Some(&claimed), Some(7), 0, &ticket(4, PRICE), PRICE).unwrap(),
+            Err(TicketRejection::StaleNonce {
+                latest_nonce: 8,
+                ..
+            })
+        ));
+        assert!(
+            check_progression(Some(&claimed), Some(7), 0, &ticket(8, PRICE), PRICE)
+                .unwrap()
+                .is_ok()
+        );
+    }
+}
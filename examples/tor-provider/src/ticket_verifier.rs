@@ -0,0 +1,361 @@
+use crate::hpc_service::PaymentTicket;
+use anyhow::{Result, anyhow};
+use k256::ecdsa::{RecoveryId, Signature, SigningKey, VerifyingKey};
+use sha3::{Digest, Keccak256};
+use tracing::debug;
+
+/// 20 byte EVM address
+pub type Address = [u8; 20];
+
+/// keccak256 hash of arbitrary bytes
+pub fn keccak256(data: &[u8]) -> [u8; 32] {
+    Keccak256::digest(data).into()
+}
+
+/// parse a 0x-prefixed hex address (case-insensitive, checksum is not enforced)
+pub fn parse_address(address: &str) -> Result<Address> {
+    let bytes = decode_hex(address)?;
+    bytes
+        .try_into()
+        .map_err(|b: Vec<u8>| anyhow!("address must be 20 bytes, got {}", b.len()))
+}
+
+/// format an address as 0x-prefixed lowercase hex
+pub fn format_address(address: &Address) -> String {
+    format!("0x{}", hex::encode(address))
+}
+
+/// derive the EVM address of a secp256k1 public key
+pub fn address_from_verifying_key(key: &VerifyingKey) -> Address {
+    let point = key.to_encoded_point(false);
+    let hash = keccak256(&point.as_bytes()[1..]);
+
+    let mut address = [0u8; 20];
+    address.copy_from_slice(&hash[12..]);
+    address
+}
+
+/// compute the message hash signed by the ticket signer, this must match `verify_signature` in
+/// HiddenPaymentChannels.sol:
+/// keccak256(abi.encodePacked(keccak256(toRailgunAddress), amount, nonce, address(this)))
+pub fn ticket_message_hash(ticket: &PaymentTicket) -> Result<[u8; 32]> {
+    let contract = parse_address(&ticket.hidden_payment_channels_contract_address)?;
+
+    let mut packed = Vec::with_capacity(32 + 32 + 32 + 20);
+    packed.extend_from_slice(&keccak256(ticket.to_railgun_address.as_bytes()));
+    packed.extend_from_slice(&encode_uint256(ticket.amount_value()?));
+    packed.extend_from_slice(&encode_uint256(ticket.nonce_value()?));
+    packed.extend_from_slice(&contract);
+
+    Ok(keccak256(&packed))
+}
+
+/// recover the signer address from a 65 byte (r || s || v) signature over a prehashed message,
+/// equivalent to OpenZeppelin's `ECDSA.recover`, which refuses signatures with a high `s` or a
+/// `v` other than 27/28
+pub fn recover_signer(message_hash: &[u8; 32], signature: &str) -> Result<Address> {
+    let bytes = decode_hex(signature)?;
+    if bytes.len() != 65 {
+        return Err(anyhow!("signature must be 65 bytes, got {}", bytes.len()));
+    }
+
+    let signature =
+        Signature::from_slice(&bytes[..64]).map_err(|e| anyhow!("invalid signature: {}", e))?;
+    // the contract could never claim a ticket signed with the high `s` twin
+    if signature.normalize_s().is_some() {
+        return Err(anyhow!("signature has a high s value"));
+    }
+
+    // `ECDSA.recover` only takes the legacy 27/28 recovery ids, a ticket with a raw 0/1 id
+    // could not be claimed
+    let v = match bytes[64] {
+        v @ (27 | 28) => v - 27,
+        v => return Err(anyhow!("invalid signature recovery id: {}", v)),
+    };
+    let recovery_id =
+        RecoveryId::from_byte(v).ok_or_else(|| anyhow!("invalid signature recovery id"))?;
+
+    let key = VerifyingKey::recover_from_prehash(message_hash, &signature, recovery_id)
+        .map_err(|e| anyhow!("failed to recover signer: {}", e))?;
+
+    Ok(address_from_verifying_key(&key))
+}
+
+/// the canonical 65 byte (r || s || v) form of a hex signature, with a low `s` and `v` in 27/28,
+/// what the lottery draws over
+///
+/// every way of writing a signature that recovers the same signer (with or without `0x`, upper
+/// or lower case hex, a raw 0/1 recovery id, the high `s` twin) gives the same bytes, whether or
+/// not `recover_signer` accepts it
+pub fn normalize_signature(signature: &str) -> Result<[u8; 65]> {
+    let bytes = decode_hex(signature)?;
+    let mut bytes: [u8; 65] = bytes
+        .try_into()
+        .map_err(|b: Vec<u8>| anyhow!("signature must be 65 bytes, got {}", b.len()))?;
+
+    let parsed =
+        Signature::from_slice(&bytes[..64]).map_err(|e| anyhow!("invalid signature: {}", e))?;
+
+    // accept both the legacy (27/28) and raw (0/1) recovery ids
+    let mut v = match bytes[64] {
+        v @ (27 | 28) => v - 27,
+        v @ (0 | 1) => v,
+        v => return Err(anyhow!("invalid signature recovery id: {}", v)),
+    };
+    // (r, -s) is the same signature for the negated nonce point, whose y parity is flipped
+    if let Some(low) = parsed.normalize_s() {
+        bytes[..64].copy_from_slice(&low.to_bytes());
+        v ^= 1;
+    }
+    bytes[64] = v + 27;
+    Ok(bytes)
+}
+
+/// sign a prehashed message, returns a 65 byte (r || s || v) hex signature with v in 27/28,
+/// the same format the HiddenPaymentChannels service produces
+pub fn sign_message_hash(key: &SigningKey, message_hash: &[u8; 32]) -> Result<String> {
+    let (signature, recovery_id) = key
+        .sign_prehash_recoverable(message_hash)
+        .map_err(|e| anyhow!("failed to sign message: {}", e))?;
+
+    let mut bytes = signature.to_bytes().to_vec();
+    bytes.push(recovery_id.to_byte() + 27);
+    Ok(format!("0x{}", hex::encode(bytes)))
+}
+
+/// verifies payment tickets natively, performs the same checks as the HiddenPaymentChannels
+/// service without the HTTP round trip
+#[derive(Clone, Debug)]
+pub struct TicketVerifier {
+    signer_address: Address,
+    expected_to_railgun_address: Option<String>,
+    expected_contract_address: Option<Address>,
+}
+
+impl TicketVerifier {
+    /// create a verifier that accepts tickets signed by `signer_address`
+    pub fn new(signer_address: &str) -> Result<Self> {
+        Ok(Self {
+            signer_address: parse_address(signer_address)?,
+            expected_to_railgun_address: None,
+            expected_contract_address: None,
+        })
+    }
+
+    /// only accept tickets paying to this railgun address
+    pub fn with_expected_to_railgun_address(mut self, address: impl Into<String>) -> Self {
+        self.expected_to_railgun_address = Some(address.into());
+        self
+    }
+
+    /// only accept tickets issued for this HiddenPaymentChannels contract
+    pub fn with_expected_contract_address(mut self, address: &str) -> Result<Self> {
+        self.expected_contract_address = Some(parse_address(address)?);
+        Ok(self)
+    }
+
+    /// the address tickets must be signed by
+    pub fn signer_address(&self) -> &Address {
+        &self.signer_address
+    }
+
+    /// verify a payment ticket, returns Ok(false) if the ticket is well-formed but not valid
+    pub fn verify(&self, ticket: &PaymentTicket) -> Result<bool> {
+        if let Some(expected) = &self.expected_to_railgun_address
+            && &ticket.to_railgun_address != expected
+        {
+            debug!("ticket with nonce {} is not for this host", ticket.nonce);
+            return Ok(false);
+        }
+
+        if let Some(expected) = &self.expected_contract_address
+            && &parse_address(&ticket.hidden_payment_channels_contract_address)? != expected
+        {
+            debug!(
+                "ticket with nonce {} is for another contract: {}",
+                ticket.nonce, ticket.hidden_payment_channels_contract_address
+            );
+            return Ok(false);
+        }
+
+        let message_hash = ticket_message_hash(ticket)?;
+        let signer = match recover_signer(&message_hash, &ticket.signature) {
+            Ok(signer) => signer,
+            Err(e) => {
+                debug!(
+                    "ticket with nonce {} has a bad signature: {}",
+                    ticket.nonce, e
+                );
+                return Ok(false);
+            }
+        };
+
+        if signer != self.signer_address {
+            debug!(
+                "ticket with nonce {} is signed by {} instead of {}",
+                ticket.nonce,
+                format_address(&signer),
+                format_address(&self.signer_address)
+            );
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+}
+
+/// left-pad an unsigned integer to a 32 byte big-endian uint256
+fn encode_uint256(value: u128) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[16..].copy_from_slice(&value.to_be_bytes());
+    word
+}
+
+/// decode 0x-prefixed (or bare) hex
+fn decode_hex(value: &str) -> Result<Vec<u8>> {
+    let value = value.strip_prefix("0x").unwrap_or(value);
+    hex::decode(value).map_err(|e| anyhow!("invalid hex '{}': {}", value, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payment_backend::TicketIssuer;
+
+    const CONTRACT: &str = "0x00000000000000000000000000000000000000aa";
+
+    fn issuer() -> TicketIssuer {
+        TicketIssuer::new(
+            SigningKey::from_slice(&[7u8; 32]).unwrap(),
+            "0zk1host",
+            CONTRACT,
+            100,
+        )
+    }
+
+    fn verifier(issuer: &TicketIssuer) -> TicketVerifier {
+        TicketVerifier::new(&format_address(&issuer.signer_address()))
+            .unwrap()
+            .with_expected_to_railgun_address("0zk1host")
+            .with_expected_contract_address(CONTRACT)
+            .unwrap()
+    }
+
+    #[test]
+    fn derives_evm_addresses() {
+        // the well known address of private key 1
+        let mut secret = [0u8; 32];
+        secret[31] = 1;
+        let key = SigningKey::from_slice(&secret).unwrap();
+        assert_eq!(
+            format_address(&address_from_verifying_key(key.verifying_key())),
+            "0x7e5f4552091a69125d5dfcb7b8c2659029395bdf"
+        );
+        assert!(parse_address("0x7E5F4552091A69125d5DfCb7b8C2659029395Bdf").is_ok());
+        assert!(parse_address("0x7e5f").is_err());
+    }
+
+    #[test]
+    fn recovers_the_signer_of_claimable_signatures_only() {
+        let key = SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let address = address_from_verifying_key(key.verifying_key());
+        let hash = keccak256(b"ticket");
+
+        let signature = sign_message_hash(&key, &hash).unwrap();
+        assert_eq!(recover_signer(&hash, &signature).unwrap(), address);
+
+        // raw 0/1 recovery ids are refused like the contract refuses them
+        let mut bytes = decode_hex(&signature).unwrap();
+        bytes[64] -= 27;
+        assert!(recover_signer(&hash, &hex::encode(&bytes)).is_err());
+
+        // the high s twin of the signature is refused like the contract refuses it
+        assert!(recover_signer(&hash, &high_s_twin(&signature)).is_err());
+
+        bytes[64] = 5;
+        assert!(recover_signer(&hash, &hex::encode(&bytes)).is_err());
+        assert!(recover_signer(&hash, &hex::encode(&bytes[..64])).is_err());
+        // another message recovers another signer
+        assert_ne!(
+            recover_signer(&keccak256(b"other"), &signature).unwrap(),
+            address
+        );
+    }
+
+    /// the same signature with `n - s` and the other recovery id
+    fn high_s_twin(signature: &str) -> String {
+        let bytes = decode_hex(signature).unwrap();
+        let low = Signature::from_slice(&bytes[..64]).unwrap();
+        let (r, s) = low.split_scalars();
+        let high = Signature::from_scalars(r, -*s).unwrap();
+        let mut twin = high.to_bytes().to_vec();
+        twin.push(27 + 28 - bytes[64]);
+        format!("0x{}", hex::encode(twin))
+    }
+
+    #[test]
+    fn normalizes_signature_encodings() {
+        let key = SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let signature = sign_message_hash(&key, &keccak256(b"ticket")).unwrap();
+        let normalized = normalize_signature(&signature).unwrap();
+
+        let mut raw_v = decode_hex(&signature).unwrap();
+        raw_v[64] -= 27;
+        for variant in [
+            signature.trim_start_matches("0x").to_string(),
+            signature.to_uppercase().replace("0X", "0x"),
+            hex::encode(&raw_v),
+            high_s_twin(&signature),
+        ] {
+            assert_eq!(normalize_signature(&variant).unwrap(), normalized);
+        }
+    }
+
+    #[test]
+    fn verifies_tickets() {
+        let issuer = issuer();
+        let verifier = verifier(&issuer);
+        let ticket = issuer.issue().unwrap();
+        assert!(verifier.verify(&ticket).unwrap());
+
+        // any signed field that changes breaks the signature
+        let mut tampered = ticket.clone();
+        tampered.amount = "1000".to_string();
+        assert!(!verifier.verify(&tampered).unwrap());
+
+        let mut tampered = ticket.clone();
+        tampered.signature = "0x1234".to_string();
+        assert!(!verifier.verify(&tampered).unwrap());
+
+        // tickets for another host or contract are refused before the signature is checked
+        let mut other = ticket.clone();
+        other.to_railgun_address = "0zk1other".to_string();
+        assert!(!verifier.verify(&other).unwrap());
+
+        let mut other = ticket.clone();
+        other.hidden_payment_channels_contract_address =
+            "0x00000000000000000000000000000000000000bb".to_string();
+        assert!(!verifier.verify(&other).unwrap());
+
+        // nor could a valid signature with a raw 0/1 recovery id
+        let mut raw_v = ticket.clone();
+        let mut bytes = decode_hex(&ticket.signature).unwrap();
+        bytes[64] -= 27;
+        raw_v.signature = format!("0x{}", hex::encode(&bytes));
+        assert!(!verifier.verify(&raw_v).unwrap());
+
+        // the high s twin of a valid signature could not be claimed
+        let mut high_s = ticket.clone();
+        high_s.signature = high_s_twin(&ticket.signature);
+        assert!(!verifier.verify(&high_s).unwrap());
+
+        // signed by someone else
+        let stranger = TicketIssuer::new(
+            SigningKey::from_slice(&[8u8; 32]).unwrap(),
+            "0zk1host",
+            CONTRACT,
+            100,
+        );
+        assert!(!verifier.verify(&stranger.issue().unwrap()).unwrap());
+    }
+}
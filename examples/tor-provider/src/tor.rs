@@ -1,7 +1,11 @@
+use crate::config::{IsolationPolicy, UserConfig};
 use anyhow::Result;
-use arti_client::{TorClient, TorClientConfig};
-use std::path::PathBuf;
+use arti_client::{IsolationToken, TorClient, TorClientConfig};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::sync::watch;
 use tor_config::CfgPath;
 use tracing::{info, warn};
@@ -10,6 +14,8 @@ use tracing::{info, warn};
 #[derive(Clone)]
 pub struct TorClientManager {
     client: TorClient<tor_rtcompat::PreferredRuntime>,
+    config: TorClientConfig,
+    state_dir: PathBuf,
     ready_rx: watch::Receiver<bool>,
 }
 
@@ -20,7 +26,7 @@ impl TorClientManager {
         info!("initializing TOR client...");
 
         // configure Tor client with custom or default data directory
-        let config = if let Some(dir) = data_dir {
+        let (config, state_dir) = if let Some(dir) = data_dir {
             info!("using custom TOR data directory: {:?}", dir);
             let mut builder = TorClientConfig::builder();
 
@@ -32,7 +38,7 @@ impl TorClientManager {
             // enable .onion address connections
             builder.address_filter().allow_onion_addrs(true);
 
-            builder.build()?
+            (builder.build()?, dir)
         } else {
             info!("using default TOR data directory (~/.local/share/arti)");
             let mut builder = TorClientConfig::builder();
@@ -40,7 +46,8 @@ impl TorClientManager {
             // enable .onion address connections
             builder.address_filter().allow_onion_addrs(true);
 
-            builder.build()?
+            let state_dir = CfgPath::new("${ARTI_LOCAL_DATA}".to_owned()).path()?;
+            (builder.build()?, state_dir)
         };
 
         // create a channel to signal when bootstrap is complete
@@ -48,7 +55,7 @@ impl TorClientManager {
 
         info!("starting TOR client bootstrap...");
 
-        let client = TorClient::create_bootstrapped(config).await?;
+        let client = TorClient::create_bootstrapped(config.clone()).await?;
 
         info!("TOR client bootstrapped successfully!");
 
@@ -57,7 +64,34 @@ impl TorClientManager {
             warn!("failed to send ready signal (receiver dropped)");
         }
 
-        Ok(Self { client, ready_rx })
+        Ok(Self {
+            client,
+            config,
+            state_dir,
+            ready_rx,
+        })
+    }
+
+    /// a client that is never bootstrapped, for tests that do not reach the Tor network
+    #[cfg(test)]
+    pub(crate) fn unbootstrapped(state_dir: &Path) -> Result<Self> {
+        let mut builder = TorClientConfig::builder();
+        let cfg_path = CfgPath::new(state_dir.to_string_lossy().into_owned());
+        builder.storage().state_dir(cfg_path.clone());
+        builder.storage().cache_dir(cfg_path);
+        builder.storage().permissions().dangerously_trust_everyone();
+        let config = builder.build()?;
+
+        let client = TorClient::builder()
+            .config(config.clone())
+            .create_unbootstrapped()?;
+        let (_, ready_rx) = watch::channel(false);
+        Ok(Self {
+            client,
+            config,
+            state_dir: state_dir.to_path_buf(),
+            ready_rx,
+        })
     }
 
     /// get the underlying TorClient
@@ -65,6 +99,16 @@ impl TorClientManager {
         &self.client
     }
 
+    /// the configuration the client was created with
+    pub fn config(&self) -> &TorClientConfig {
+        &self.config
+    }
+
+    /// directory holding the client's state, Arti's keystore lives in its `keystore` subdirectory
+    pub fn state_dir(&self) -> &Path {
+        &self.state_dir
+    }
+
     /// get a receiver to watch for ready status
     pub fn ready_receiver(&self) -> watch::Receiver<bool> {
         self.ready_rx.clone()
@@ -83,3 +127,88 @@ pub async fn bootstrap_tor_client(data_dir: Option<PathBuf>) -> Result<Arc<TorCl
     let manager = TorClientManager::new(data_dir).await?;
     Ok(Arc::new(manager))
 }
+
+/// how long the token of an isolation group is kept unused, a group seen again later gets new
+/// circuits
+const TOKEN_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// hands out the arti isolation tokens that keep requests of different groups, as set by an
+/// `IsolationPolicy`, on different circuits
+#[derive(Clone)]
+pub struct CircuitIsolation {
+    policy: IsolationPolicy,
+    window: Duration,
+    tokens: Arc<Mutex<HashMap<String, (IsolationToken, Instant)>>>,
+}
+
+/// isolation of a single request
+pub struct Isolation {
+    // arti token of the request's group
+    pub token: IsolationToken,
+    // group of requests that may share circuits and connections, `None` when the request shares
+    // them with no other
+    pub group: Option<String>,
+}
+
+impl Isolation {
+    /// key the connections to a provider (`scheme://host:port`) are pooled under, `None` when
+    /// the request's connection may not be reused
+    pub fn pool_key(&self, origin: &str) -> Option<String> {
+        match self.group.as_deref() {
+            Some(group) if group == origin => Some(origin.to_string()),
+            Some(group) => Some(format!("{} [{}]", origin, group)),
+            None => None,
+        }
+    }
+}
+
+impl CircuitIsolation {
+    /// isolate requests by `policy`, `window` is the length of a time window for
+    /// `IsolationPolicy::PerTimeWindow`
+    pub fn new(policy: IsolationPolicy, window: Duration) -> Self {
+        Self {
+            policy,
+            window,
+            tokens: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// isolation from the command line configuration
+    pub fn from_config(config: &UserConfig) -> Self {
+        Self::new(config.tor_isolation, config.isolation_window())
+    }
+
+    /// isolation of a request to a provider (`scheme://host:port`) from a local client
+    pub fn isolate(&self, origin: &str, local_client: Option<&str>) -> Isolation {
+        let group = match self.policy {
+            IsolationPolicy::PerProvider => origin.to_string(),
+            IsolationPolicy::PerLocalClient => {
+                format!("client {}", local_client.unwrap_or("unknown"))
+            }
+            IsolationPolicy::PerRequest => {
+                return Isolation {
+                    token: IsolationToken::new(),
+                    group: None,
+                };
+            }
+            IsolationPolicy::PerTimeWindow => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                format!("window {}", now / self.window.as_secs().max(1))
+            }
+        };
+
+        let mut tokens = self.tokens.lock();
+        tokens.retain(|_, (_, used)| used.elapsed() < TOKEN_IDLE_TIMEOUT);
+        let (token, used) = tokens
+            .entry(group.clone())
+            .or_insert_with(|| (IsolationToken::new(), Instant::now()));
+        *used = Instant::now();
+        Isolation {
+            token: *token,
+            group: Some(group),
+        }
+    }
+}
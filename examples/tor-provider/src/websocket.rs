@@ -0,0 +1,71 @@
+use crate::hpc_service::PaymentTicket;
+use crate::payment_requirements::PaymentRequirements;
+use crate::rpc_utils::RpcRequest;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// JSON-RPC method of subscription notifications pushed by the node
+pub const NOTIFICATION_METHOD: &str = "eth_subscription";
+
+/// JSON-RPC method opening a subscription, its notifications are billed as postpaid debt
+pub const SUBSCRIBE_METHOD: &str = "eth_subscribe";
+
+/// close reason sent when the host stops serving a session with unpaid debt
+pub const PAYMENT_REQUIRED_CLOSE_REASON: &str = "payment required";
+
+/// frame the user proxy sends to the host over a WebSocket tunnel
+///
+/// every JSON-RPC message from the wallet travels with the ticket paying for it, a frame with a
+/// ticket and no message only settles the debt notifications ran up
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UserFrame {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ticket: Option<PaymentTicket>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<Value>,
+}
+
+/// frame the host sends to the user proxy over a WebSocket tunnel
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HostFrame {
+    // JSON-RPC response or notification from the node, or the error refusing a message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<Value>,
+    // what the channel owes after this frame (wei)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub debt: Option<String>,
+    // signed receipt for the last accepted ticket
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receipt: Option<String>,
+    // what the next ticket must look like, set when a ticket was refused
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requirements: Option<PaymentRequirements>,
+}
+
+impl HostFrame {
+    /// the debt the frame reports, `None` if it reports none or it is not a number
+    pub fn debt(&self) -> Option<u128> {
+        self.debt.as_deref().and_then(|debt| debt.parse().ok())
+    }
+}
+
+/// whether a JSON-RPC message is a subscription notification
+pub fn is_notification(message: &Value) -> bool {
+    message.get("method").and_then(Value::as_str) == Some(NOTIFICATION_METHOD)
+}
+
+/// whether a JSON-RPC request opens a subscription
+pub fn is_subscribe(rpc_request: &RpcRequest) -> bool {
+    rpc_request.methods().contains(&SUBSCRIBE_METHOD)
+}
+
+/// the WebSocket URL of an HTTP(S) URL (`http://` becomes `ws://`, `https://` becomes `wss://`)
+pub fn websocket_url(url: &str) -> String {
+    if let Some(rest) = url.strip_prefix("https://") {
+        format!("wss://{}", rest)
+    } else if let Some(rest) = url.strip_prefix("http://") {
+        format!("ws://{}", rest)
+    } else {
+        url.to_string()
+    }
+}
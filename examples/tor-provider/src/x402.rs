@@ -0,0 +1,297 @@
+use crate::hpc_service::{AnyTicket, PaymentTicket};
+use crate::payment_requirements::PaymentRequirements;
+use anyhow::{Context, Result, anyhow};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+/// x402 protocol version spoken by both proxies
+pub const X402_VERSION: u32 = 1;
+
+/// request header carrying the base64 encoded payment payload
+pub const PAYMENT_HEADER: &str = "X-PAYMENT";
+
+/// response header carrying the base64 encoded settlement response
+pub const PAYMENT_RESPONSE_HEADER: &str = "X-PAYMENT-RESPONSE";
+
+/// payment scheme of HiddenPaymentChannels tickets
+pub const SCHEME: &str = "hidden-payment-channels";
+
+/// how long a payment requirements document stays valid
+const MAX_TIMEOUT_SECONDS: u64 = 60;
+
+/// body of an x402 `402 Payment Required` response
+///
+/// a batch gets the document as its body. The host answers single JSON-RPC requests with a
+/// response object, so there `x402Version` and `accepts` are added next to its JSON-RPC `error`,
+/// whose `message` stands for x402's `error` string
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PaymentRequiredDocument {
+    pub x402_version: u32,
+    // why payment is required
+    #[serde(
+        default,
+        deserialize_with = "error_message",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub error: Option<String>,
+    pub accepts: Vec<X402Requirements>,
+}
+
+/// one accepted way of paying for a resource
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct X402Requirements {
+    pub scheme: String,
+    pub network: String,
+    // amount (wei) the ticket must carry
+    pub max_amount_required: String,
+    pub resource: String,
+    pub description: String,
+    pub mime_type: String,
+    // railgun address the ticket pays to
+    pub pay_to: String,
+    pub max_timeout_seconds: u64,
+    // HiddenPaymentChannels contract the ticket must be issued for
+    pub asset: String,
+    // the full HiddenPaymentChannels payment requirements
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extra: Option<PaymentRequirements>,
+}
+
+/// payload of the `X-PAYMENT` header
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PaymentPayload {
+    pub x402_version: u32,
+    pub scheme: String,
+    pub network: String,
+    // the payment ticket (cumulative or lottery)
+    pub payload: AnyTicket,
+}
+
+/// payload of the `X-PAYMENT-RESPONSE` header
+///
+/// tickets are settled later by a claim, so there is no transaction to report and the payer
+/// is never revealed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SettlementResponse {
+    pub success: bool,
+    pub transaction: String,
+    pub network: String,
+    pub payer: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_reason: Option<String>,
+}
+
+impl PaymentRequiredDocument {
+    /// a document accepting HiddenPaymentChannels tickets for `resource`
+    pub fn new(
+        requirements: &PaymentRequirements,
+        network: &str,
+        resource: &str,
+        description: &str,
+        error: &str,
+    ) -> Self {
+        Self {
+            x402_version: X402_VERSION,
+            error: Some(error.to_string()),
+            accepts: vec![X402Requirements {
+                scheme: SCHEME.to_string(),
+                network: network.to_string(),
+                max_amount_required: requirements.expected_amount.to_string(),
+                resource: resource.to_string(),
+                description: description.to_string(),
+                mime_type: "application/json".to_string(),
+                pay_to: requirements.to_railgun_address.clone().unwrap_or_default(),
+                max_timeout_seconds: MAX_TIMEOUT_SECONDS,
+                asset: requirements
+                    .hidden_payment_channels_contract_address
+                    .clone()
+                    .unwrap_or_default(),
+                extra: Some(requirements.clone()),
+            }],
+        }
+    }
+
+    /// the body of a 402 answering with `response`: the document added to a JSON-RPC error
+    /// response object, or the document itself for anything else (a batch)
+    pub fn into_body(self, response: &[u8]) -> Vec<u8> {
+        let Ok(serde_json::Value::Object(mut document)) = serde_json::to_value(&self) else {
+            return response.to_vec();
+        };
+        let Ok(serde_json::Value::Object(mut object)) = serde_json::from_slice(response) else {
+            return serde_json::to_vec(&document).unwrap_or_else(|_| response.to_vec());
+        };
+
+        // the JSON-RPC error carries the message
+        document.remove("error");
+        object.extend(document);
+        serde_json::to_vec(&object).unwrap_or_else(|_| response.to_vec())
+    }
+
+    /// extract a document from a 402 response body
+    pub fn from_body(body: &[u8]) -> Option<Self> {
+        serde_json::from_slice(body).ok()
+    }
+
+    /// the requirements for paying with HiddenPaymentChannels tickets on `network`
+    pub fn ticket_requirements(&self, network: &str) -> Option<&X402Requirements> {
+        self.accepts
+            .iter()
+            .find(|accept| accept.scheme == SCHEME && accept.network == network)
+    }
+}
+
+impl X402Requirements {
+    /// check that a ticket pays the recipient these requirements ask for
+    pub fn check_recipient(&self, ticket: &PaymentTicket) -> Result<(), String> {
+        if let Some(requirements) = &self.extra {
+            return requirements.check_recipient(ticket);
+        }
+
+        PaymentRequirements::new(0, 0)
+            .with_recipient(non_empty(&self.asset), non_empty(&self.pay_to))
+            .check_recipient(ticket)
+    }
+}
+
+impl PaymentPayload {
+    /// wrap a ticket for the `X-PAYMENT` header
+    pub fn new(network: &str, ticket: AnyTicket) -> Self {
+        Self {
+            x402_version: X402_VERSION,
+            scheme: SCHEME.to_string(),
+            network: network.to_string(),
+            payload: ticket,
+        }
+    }
+
+    /// decode an `X-PAYMENT` header and check it is a HiddenPaymentChannels payment on `network`
+    pub fn from_header_value(value: &str, network: &str) -> Result<Self> {
+        let payload: Self = decode_header(value).context("invalid X-PAYMENT header")?;
+        if payload.x402_version != X402_VERSION {
+            return Err(anyhow!("unsupported x402 version {}", payload.x402_version));
+        }
+        if payload.scheme != SCHEME || payload.network != network {
+            return Err(anyhow!(
+                "unsupported payment scheme {} on {}",
+                payload.scheme,
+                payload.network
+            ));
+        }
+        Ok(payload)
+    }
+
+    /// encode for the `X-PAYMENT` header
+    pub fn to_header_value(&self) -> String {
+        encode_header(self)
+    }
+}
+
+impl SettlementResponse {
+    /// a ticket was accepted on `network`
+    pub fn accepted(network: &str) -> Self {
+        Self {
+            success: true,
+            transaction: String::new(),
+            network: network.to_string(),
+            payer: String::new(),
+            error_reason: None,
+        }
+    }
+
+    /// encode for the `X-PAYMENT-RESPONSE` header
+    pub fn to_header_value(&self) -> String {
+        encode_header(self)
+    }
+
+    /// decode an `X-PAYMENT-RESPONSE` header
+    pub fn from_header_value(value: &str) -> Result<Self> {
+        decode_header(value).context("invalid X-PAYMENT-RESPONSE header")
+    }
+}
+
+/// x402's `error` string, or the message of the JSON-RPC error standing for it
+fn error_message<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    Ok(match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(error) => Some(error),
+        error => error
+            .get("message")
+            .and_then(|message| message.as_str())
+            .map(str::to_string),
+    })
+}
+
+fn non_empty(value: &str) -> Option<&str> {
+    Some(value).filter(|v| !v.is_empty())
+}
+
+/// base64 encoded JSON, as used by the x402 headers
+fn encode_header<T: Serialize>(value: &T) -> String {
+    BASE64.encode(serde_json::to_vec(value).expect("x402 header serializes"))
+}
+
+fn decode_header<T: DeserializeOwned>(value: &str) -> Result<T> {
+    let bytes = BASE64.decode(value.trim())?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document() -> PaymentRequiredDocument {
+        let requirements = PaymentRequirements::new(100, 300).with_recipient(
+            Some("0x00000000000000000000000000000000000000aa"),
+            Some("0zk1host"),
+        );
+        PaymentRequiredDocument::new(
+            &requirements,
+            "sepolia",
+            "http://host.onion/",
+            "JSON-RPC eth_call",
+            "Payment required",
+        )
+    }
+
+    #[test]
+    fn single_responses_carry_the_document_next_to_the_json_rpc_error() {
+        let response =
+            br#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"Payment required"}}"#;
+        let body = document().into_body(response);
+
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value["id"], 1);
+        assert_eq!(value["error"]["code"], -32000);
+        assert_eq!(value["x402Version"], X402_VERSION);
+
+        let parsed = PaymentRequiredDocument::from_body(&body).unwrap();
+        assert_eq!(parsed.error.as_deref(), Some("Payment required"));
+        let accept = parsed.ticket_requirements("sepolia").unwrap();
+        assert_eq!(accept.max_amount_required, "300");
+        assert_eq!(accept.pay_to, "0zk1host");
+    }
+
+    #[test]
+    fn batches_get_the_document_itself() {
+        let response =
+            br#"[{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"Payment required"}}]"#;
+        let body = document().into_body(response);
+
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value["x402Version"], X402_VERSION);
+        assert_eq!(value["error"], "Payment required");
+        assert_eq!(value["accepts"][0]["scheme"], SCHEME);
+        assert!(
+            PaymentRequiredDocument::from_body(&body)
+                .unwrap()
+                .ticket_requirements("sepolia")
+                .is_some()
+        );
+    }
+}
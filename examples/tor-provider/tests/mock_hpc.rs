@@ -0,0 +1,171 @@
+use axum::{Json, Router, routing::post};
+use serde_json::{Value, json};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tor_provider::body_limit::BodyLimits;
+use tor_provider::channel_registry::{ChannelRegistry, HostChannel};
+use tor_provider::config::{BodyLimitConfig, HpcConfig};
+use tor_provider::funds_monitor::FundsMonitor;
+use tor_provider::mock_hpc;
+use tor_provider::payment_backend::{
+    MEMORY_RAILGUN_ADDRESS, MemoryBackend, PaymentBackendKind, create_payment_backend,
+    memory_contract_address,
+};
+use tor_provider::payment_receipt::{PAYMENT_RECEIPT_HEADER, ReceiptSigner, verify_receipt};
+use tor_provider::payment_requirements::{PAYMENT_REQUIRED_HEADER, PaymentRequirements};
+use tor_provider::pricing::PricingTable;
+use tor_provider::provider_registry::UserChannel;
+use tor_provider::proxy_local_client::ProxyLocalClient;
+use tor_provider::server_host::{AppState, create_router};
+use tor_provider::ticket_ledger::TicketLedger;
+
+const PRICE: u128 = 300_000_000;
+
+/// serve `router` on a free local port
+async fn serve(router: Router) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    addr
+}
+
+/// a Nimbus stand-in answering every call with the chain id
+fn nimbus() -> Router {
+    Router::new().route(
+        "/",
+        post(|Json(call): Json<Value>| async move {
+            Json(json!({ "jsonrpc": "2.0", "id": call["id"], "result": "0xaa36a7" }))
+        }),
+    )
+}
+
+/// both sides talk to the mock service through the HTTP backend
+fn hpc_config(mock: SocketAddr) -> HpcConfig {
+    HpcConfig {
+        hpc_service_url: format!("http://{}", mock),
+        payment_backend: Some(PaymentBackendKind::Http),
+        hpc_contract_address: Some(memory_contract_address()),
+        railgun_address: Some(MEMORY_RAILGUN_ADDRESS.to_string()),
+        ticket_price_wei: PRICE,
+        ..Default::default()
+    }
+}
+
+fn host_state(config: &HpcConfig, nimbus: SocketAddr, receipt_signer: ReceiptSigner) -> AppState {
+    let payment_backend = create_payment_backend(config).unwrap();
+    let channel = HostChannel {
+        contract_address: config.contract_address(),
+        railgun_address: config.railgun_address(),
+        funds_monitor: FundsMonitor::new(payment_backend.clone(), Vec::new()),
+        payment_backend,
+        pricing: Arc::new(PricingTable::flat(PRICE)),
+    };
+    AppState {
+        local_client: ProxyLocalClient::new(std::time::Duration::from_secs(10)).unwrap(),
+        validate_tickets: true,
+        nimbus_rpc_url: format!("http://{}/", nimbus),
+        nimbus_ws_url: format!("ws://{}/", nimbus),
+        ready_rx: watch::channel(true).1,
+        channels: ChannelRegistry::single(channel),
+        ledger: TicketLedger::open_in_memory().unwrap(),
+        lottery: None,
+        free_tier: None,
+        postpaid: None,
+        network: config.network.clone(),
+        receipt_signer,
+        body_limits: BodyLimits::from_config(&BodyLimitConfig::default()),
+    }
+}
+
+#[tokio::test]
+async fn user_pays_host_through_the_mock_service() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mock = listener.local_addr().unwrap();
+    mock_hpc::spawn(listener, Arc::new(MemoryBackend::new(10 * PRICE, PRICE)));
+
+    let config = hpc_config(mock);
+    let receipt_signer = ReceiptSigner::new(ed25519_dalek::SigningKey::from_bytes(&[7u8; 32]));
+    let receipt_key = receipt_signer.receipt_key().verifying_key().unwrap();
+    let nimbus = serve(nimbus()).await;
+    let host = serve(create_router(host_state(&config, nimbus, receipt_signer))).await;
+    let host_url = format!("http://{}/", host);
+
+    let client = reqwest::Client::new();
+    let call = json!({ "jsonrpc": "2.0", "id": 1, "method": "eth_chainId", "params": [] });
+
+    // without a ticket the host asks for payment
+    let response = client.post(&host_url).json(&call).send().await.unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::PAYMENT_REQUIRED);
+
+    // the user's channel gets its tickets from the mock service, the host validates them there
+    let channel = UserChannel::from_config(&config, false, &[]).unwrap();
+    let mut previous = None;
+    for nonce in 1..=2u128 {
+        let ticket = channel.payment_backend.generate_ticket().await.unwrap();
+        assert_eq!(ticket.amount_value().unwrap(), nonce * PRICE);
+
+        let response = client
+            .post(&host_url)
+            .header("X-Payment-Ticket", serde_json::to_string(&ticket).unwrap())
+            .json(&call)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+        let receipt = response.headers()[PAYMENT_RECEIPT_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let receipt = verify_receipt(&receipt, &receipt_key).unwrap();
+        assert_eq!(receipt.nonce, ticket.nonce);
+        assert_eq!(receipt.price, PRICE);
+
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["result"], "0xaa36a7");
+        previous = Some(ticket);
+    }
+
+    // a ticket that does not verify learns nothing about the channel it names
+    let mut forged = previous.clone().unwrap();
+    forged.nonce = "3".to_string();
+    let response = client
+        .post(&host_url)
+        .header("X-Payment-Ticket", serde_json::to_string(&forged).unwrap())
+        .json(&call)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::PAYMENT_REQUIRED);
+    assert_eq!(
+        payment_required(&response),
+        PaymentRequirements::new(PRICE, PRICE).with_recipient(None, Some(MEMORY_RAILGUN_ADDRESS))
+    );
+
+    // a ticket is only good for one request
+    let response = client
+        .post(&host_url)
+        .header(
+            "X-Payment-Ticket",
+            serde_json::to_string(&previous.unwrap()).unwrap(),
+        )
+        .json(&call)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::PAYMENT_REQUIRED);
+    let requirements = payment_required(&response);
+    assert_eq!(requirements.expected_amount, 3 * PRICE);
+    assert_eq!(requirements.min_nonce.as_deref(), Some("2"));
+}
+
+fn payment_required(response: &reqwest::Response) -> PaymentRequirements {
+    PaymentRequirements::from_header_value(
+        response.headers()[PAYMENT_REQUIRED_HEADER]
+            .to_str()
+            .unwrap(),
+    )
+    .unwrap()
+}